@@ -0,0 +1,70 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+#![feature(test)]
+extern crate test;
+
+// Throughput of the zenoh => DDS data path of a route: the payloads received from zenoh
+// go through the decoder of the route and are written by its forwarding DDS writer.
+//
+// Run with: cargo bench --bench zenoh_to_dds
+use cyclors::*;
+use test::Bencher;
+use zplugin_dds::coders::{Coders, DDSWriter};
+use zplugin_dds::qos::{Qos, Reliability};
+use zplugin_dds::{create_forwarding_dds_writer, QosHolder};
+
+const TOPIC_NAME: &str = "bench/zenoh_to_dds";
+const TYPE_NAME: &str = "bench::Payload";
+
+fn bench_route(b: &mut Bencher, size: usize) {
+    unsafe {
+        let dp = dds_create_participant(DDS_DOMAIN_DEFAULT, std::ptr::null(), std::ptr::null());
+        let qos = Qos {
+            reliability: Some(Reliability {
+                kind: dds_reliability_kind_DDS_RELIABILITY_BEST_EFFORT,
+                max_blocking_time: 0,
+            }),
+            ..Default::default()
+        };
+        let wr = create_forwarding_dds_writer(
+            dp,
+            TOPIC_NAME.to_string(),
+            TYPE_NAME.to_string(),
+            true,
+            QosHolder(qos.to_qos_native()),
+        );
+        let writer = DDSWriter::new(dp, wr, TOPIC_NAME, TYPE_NAME, true);
+        let decoder = Coders::new().new_decoder(TOPIC_NAME, TYPE_NAME, Box::new(writer));
+
+        // a CDR little endian encapsulation header followed by the data
+        let mut payload = vec![0x00, 0x01, 0x00, 0x00];
+        payload.resize(size, 0xab);
+        b.bytes = size as u64;
+        // the bridge gets each payload from zenoh as a new Vec
+        b.iter(|| decoder.decode(payload.clone()));
+
+        drop(decoder);
+        dds_delete(dp);
+    }
+}
+
+#[bench]
+fn bench_zenoh_to_dds_1kb(b: &mut Bencher) {
+    bench_route(b, 1024);
+}
+
+#[bench]
+fn bench_zenoh_to_dds_1mb(b: &mut Bencher) {
+    bench_route(b, 1024 * 1024);
+}
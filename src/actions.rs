@@ -0,0 +1,203 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::sim_clock::{elapsed, Clock};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// A ROS 2 action <action> is made of the services "<action>/_action/{send_goal,cancel_goal,get_result}"
+// and of the topics "rt/<action>/_action/{feedback,status}"
+const ACTION_CHUNK: &str = "/_action/";
+const TOPIC_PREFIX: &str = "rt/";
+
+/// The services of a ROS 2 action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionService {
+    SendGoal,
+    CancelGoal,
+    GetResult,
+}
+
+/// The topics of a ROS 2 action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionTopic {
+    Feedback,
+    Status,
+}
+
+fn split_action(name: &str) -> Option<(&str, &str)> {
+    let i = name.rfind(ACTION_CHUNK)?;
+    Some((&name[..i], &name[i + ACTION_CHUNK.len()..]))
+}
+
+/// The name of the ROS 2 action the service `service_name` belongs to, if any,
+/// and which of its services it is.
+pub fn action_service(service_name: &str) -> Option<(&str, ActionService)> {
+    let (action, service) = split_action(service_name)?;
+    match service {
+        "send_goal" => Some((action, ActionService::SendGoal)),
+        "cancel_goal" => Some((action, ActionService::CancelGoal)),
+        "get_result" => Some((action, ActionService::GetResult)),
+        _ => None,
+    }
+}
+
+/// The name of the ROS 2 action the topic `topic_name` belongs to, if any,
+/// and which of its topics it is.
+pub fn action_topic(topic_name: &str) -> Option<(&str, ActionTopic)> {
+    let (action, topic) = split_action(topic_name.strip_prefix(TOPIC_PREFIX)?)?;
+    match topic {
+        "feedback" => Some((action, ActionTopic::Feedback)),
+        "status" => Some((action, ActionTopic::Status)),
+        _ => None,
+    }
+}
+
+const ENCAPSULATION_LEN: usize = 4;
+
+// A goal is forgotten after this time without requests about it, as an action server keeps the
+// result of a goal for 15 minutes by default: e.g. if its client never gets its result, or crashed
+const GOAL_EXPIRY: Duration = Duration::from_secs(15 * 60);
+
+/// The UUID of a goal.
+pub type GoalId = [u8; 16];
+
+/// The goal a request of an action service is about: the requests of send_goal and get_result
+/// start with the goal's UUID, as the request of cancel_goal with its goal info.
+pub fn goal_id(request: &[u8]) -> Option<GoalId> {
+    let mut id = [0u8; 16];
+    id.copy_from_slice(request.get(ENCAPSULATION_LEN..ENCAPSULATION_LEN + 16)?);
+    Some(id)
+}
+
+/// A cancel_goal request with a zero goal UUID cancels all the goals.
+pub fn is_any_goal(id: &GoalId) -> bool {
+    id.iter().all(|b| *b == 0)
+}
+
+/// The reply of send_goal starts with a boolean telling if the goal was accepted.
+pub fn is_goal_accepted(reply: &[u8]) -> bool {
+    reply
+        .get(ENCAPSULATION_LEN)
+        .map(|b| *b != 0)
+        .unwrap_or(false)
+}
+
+/// The server of each goal sent by the local clients of an action, as the bridge serving it, for
+/// its cancel_goal and get_result requests to reach the server that accepted it.
+#[derive(Debug, Default)]
+pub struct ActionGoals {
+    // the server of each goal, with the last time it was accepted or requested
+    servers: Mutex<HashMap<GoalId, (String, Duration)>>,
+    clock: Clock,
+}
+
+impl ActionGoals {
+    pub fn accepted(&self, goal: GoalId, server: String) {
+        let now = self.clock.now();
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, (_, t)| !elapsed(*t, now, GOAL_EXPIRY));
+        servers.insert(goal, (server, now));
+    }
+
+    pub fn server(&self, goal: &GoalId) -> Option<String> {
+        let now = self.clock.now();
+        let mut servers = self.servers.lock().unwrap();
+        match servers.get_mut(goal) {
+            Some((_, t)) if elapsed(*t, now, GOAL_EXPIRY) => {
+                servers.remove(goal);
+                None
+            }
+            Some((server, t)) => {
+                *t = now;
+                Some(server.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Forgets a goal once its result is received.
+    pub fn done(&self, goal: &GoalId) {
+        self.servers.lock().unwrap().remove(goal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_names() {
+        assert_eq!(
+            action_service("fibonacci/_action/send_goal"),
+            Some(("fibonacci", ActionService::SendGoal))
+        );
+        assert_eq!(
+            action_service("robot1/fibonacci/_action/get_result"),
+            Some(("robot1/fibonacci", ActionService::GetResult))
+        );
+        assert_eq!(action_service("add_two_ints"), None);
+        assert_eq!(action_service("fibonacci/_action/status"), None);
+        assert_eq!(
+            action_topic("rt/fibonacci/_action/feedback"),
+            Some(("fibonacci", ActionTopic::Feedback))
+        );
+        assert_eq!(action_topic("rt/fibonacci/_action/get_result"), None);
+        assert_eq!(action_topic("rq/fibonacci/_action/status"), None);
+    }
+
+    #[test]
+    fn test_goals() {
+        let mut request = vec![0x00, 0x01, 0x00, 0x00];
+        request.extend_from_slice(&[7u8; 16]);
+        request.extend_from_slice(&[0x0a, 0x00, 0x00, 0x00]);
+        let goal = goal_id(&request).unwrap();
+        assert_eq!(goal, [7u8; 16]);
+        assert!(!is_any_goal(&goal));
+        assert!(is_any_goal(&[0u8; 16]));
+        assert_eq!(goal_id(&request[..10]), None);
+        assert!(is_goal_accepted(&[0x00, 0x01, 0x00, 0x00, 0x01]));
+        assert!(!is_goal_accepted(&[0x00, 0x01, 0x00, 0x00, 0x00]));
+
+        let goals = ActionGoals::default();
+        goals.accepted(goal, String::from("bridge2"));
+        assert_eq!(goals.server(&goal).as_deref(), Some("bridge2"));
+        goals.done(&goal);
+        assert_eq!(goals.server(&goal), None);
+    }
+
+    #[test]
+    fn test_goals_expiry() {
+        let clock = Clock::sim();
+        let goals = ActionGoals {
+            servers: Mutex::default(),
+            clock: clock.clone(),
+        };
+        let minute = Duration::from_secs(60);
+        // a goal whose client never gets the result, and a goal requested now and then
+        goals.accepted([1u8; 16], String::from("bridge2"));
+        goals.accepted([2u8; 16], String::from("bridge3"));
+        clock.set(10 * minute);
+        assert_eq!(goals.server(&[2u8; 16]).as_deref(), Some("bridge3"));
+        clock.set(20 * minute);
+        assert_eq!(goals.server(&[1u8; 16]), None);
+        assert_eq!(goals.server(&[2u8; 16]).as_deref(), Some("bridge3"));
+
+        // the expired goals are forgotten as new ones are accepted
+        goals.accepted([3u8; 16], String::from("bridge2"));
+        clock.set(40 * minute);
+        goals.accepted([4u8; 16], String::from("bridge2"));
+        assert_eq!(goals.servers.lock().unwrap().len(), 1);
+    }
+}
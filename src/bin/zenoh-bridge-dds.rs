@@ -11,23 +11,38 @@
 // Contributors:
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
-#![feature(vec_into_raw_parts)]
 
 use async_std::task;
 use clap::{App, Arg};
 use cyclors::*;
 use futures::prelude::*;
-use log::{debug, info};
+use log::{debug, info, warn};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use zenoh::net::*;
 use zenoh::Properties;
 use zplugin_dds::*;
+use crate::bridges::*;
 use crate::coders::*;
+use crate::qos::Qos;
+use crate::redundancy::run_leader_election;
+use crate::remote_discovery::*;
+use crate::types::*;
+use crate::zenoh_qos::ZenohQos;
+use crate::config::BridgeConfig;
+use crate::downsampling::*;
+use crate::history::*;
+use crate::policy::{Direction, Endpoint, Policy};
+use crate::services::*;
+use crate::actions::*;
+use crate::ros2_names::demangle_type;
+use crate::sim_clock::*;
+use crate::tf_aggregation::*;
+use crate::protobuf::proto_schema;
 
-fn parse_args() -> (Properties, String, u32, Option<Regex>, Coders) {
+fn parse_args() -> (Properties, String, u32, BridgeConfig, Coders) {
     let args = App::new("zenoh bridge for DDS")
         .arg(Arg::from_usage(
             "-e, --peer=[LOCATOR]...  'Peer locator used to initiate the zenoh session.'\n",
@@ -64,7 +79,13 @@ fn parse_args() -> (Properties, String, u32, Option<Regex>, Coders) {
         )
         .arg(
             Arg::from_usage(
-                "-a, --allow=[String] 'The regular expression describing set of /partition/topic-name that should be bridged, everything is forwarded by default.'\n"
+                "-a, --allow=[String] 'The regular expression describing set of /partition/topic-name that should be bridged, everything is forwarded by default. \
+                Replaces the policy of the bridge configuration.'\n"
+            )
+        )
+        .arg(
+            Arg::from_usage(
+                "--bridge-config=[FILE]   'Bridge configuration (e.g. allow/deny policy)'\n"
             )
         )
         .arg(
@@ -72,6 +93,11 @@ fn parse_args() -> (Properties, String, u32, Option<Regex>, Coders) {
                 "--coders-config=[FILE]   'Coders configuration'\n"
             )
         )
+        .arg(
+            Arg::from_usage(
+                "--proto-schema=[TYPE]   'Prints the Protobuf schema of a type known from the bridge configuration, and exits'\n"
+            )
+        )
         .get_matches();
 
     let scope: String = args
@@ -112,16 +138,35 @@ fn parse_args() -> (Properties, String, u32, Option<Regex>, Coders) {
         config.insert("multicast_scouting".into(), "false".into());
     }
 
-    let allow = if let Some(res) = args.value_of("allow") {
+    let mut bridge_config = match args.value_of("bridge-config") {
+        Some(conf_file) => match BridgeConfig::from_config(conf_file) {
+            Ok(c) => c,
+            Err(e) => panic!("Unable to load the bridge configuration: {}", e),
+        },
+        None => BridgeConfig::default(),
+    };
+
+    if let Some(type_name) = args.value_of("proto-schema") {
+        match proto_schema(&bridge_config.types, type_name) {
+            Ok(schema) => {
+                println!("{}", schema);
+                std::process::exit(0);
+            }
+            Err(e) => panic!(
+                "Unable to generate the Protobuf schema of {}: {}",
+                type_name, e
+            ),
+        }
+    }
+
+    if let Some(res) = args.value_of("allow") {
         match Regex::new(res) {
-            Ok(re) => Some(re),
+            Ok(re) => bridge_config.policy = Policy::from_allow_regex(re),
             Err(e) => {
                 panic!("Unable to compile allow regular expression, please see error details below:\n {:?}\n", e)
             }
         }
-    } else {
-        None
-    };
+    }
 
     let did = if let Some(sdid) = args.value_of("domain") {
         match sdid.parse::<u32>() {
@@ -135,15 +180,222 @@ fn parse_args() -> (Properties, String, u32, Option<Regex>, Coders) {
     let coders = match args.value_of("coders-config") {
         Some(conf_file) => Coders::from_config(conf_file),
         None => Coders::new(),
+    }
+    .with_types(bridge_config.types.clone());
+
+    (config, scope, did, bridge_config, coders)
+}
+
+// A forwarding DDS reader or writer, shared by all the matching endpoints
+struct Route {
+    // the forwarding DDS reader or writer, with the reply writer or reader of a service route
+    entities: Vec<dds_entity_t>,
+    // the task routing the zenoh subscription to the DDS writer, replying with the history
+    // of a transient-local DDS publication, or serving the queries of a service, if any
+    zsub_task: Option<task::JoinHandle<()>>,
+    endpoints: HashSet<String>,
+}
+
+// A zenoh DELETE disposes or unregisters the DDS instance
+fn route_sample_to_dds(
+    topic_name: &str,
+    d: Sample,
+    decoder: &(dyn Coder + Send),
+    source_timestamps: bool,
+) {
+    let is_delete = d
+        .data_info
+        .as_ref()
+        .and_then(|i| i.kind)
+        .map(|k| k == data_kind::DELETE)
+        .unwrap_or(false);
+    let payload = d.payload.to_vec();
+    if is_delete {
+        let (kind, key) = SampleKind::from_delete_payload(&payload);
+        log::trace!("Route {:?} to DDS '{}'", kind, topic_name);
+        decoder.decode_with(
+            key.to_vec(),
+            &SampleMeta {
+                instance: None,
+                kind,
+                timestamp: None,
+                encoding: None,
+            },
+        );
+    } else if source_timestamps {
+        let timestamp = source_timestamp(&d.data_info);
+        log::trace!(
+            "Route data to DDS '{}' with timestamp {:?}",
+            topic_name,
+            timestamp
+        );
+        decoder.decode_with(
+            payload,
+            &SampleMeta {
+                instance: None,
+                kind: SampleKind::Put,
+                timestamp,
+                encoding: None,
+            },
+        );
+    } else {
+        log::trace!("Route data to DDS '{}'", topic_name);
+        decoder.decode(payload);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_zenoh_to_dds_route(
+    z: Arc<Session>,
+    dp: dds_entity_t,
+    key: String,
+    topic_name: String,
+    type_name: String,
+    keyless: bool,
+    qos: QosHolder,
+    coders: &Coders,
+    source_timestamps: bool,
+    reliability: Reliability,
+) -> Route {
+    info!(
+        "New route: zenoh '{}' => DDS '{}' with type '{}'",
+        key, topic_name, type_name
+    );
+    let transient_local = unsafe { is_transient_local(qos.0) };
+    let wr = create_forwarding_dds_writer(dp, topic_name.clone(), type_name.clone(), keyless, qos);
+    let sub_info = SubInfo {
+        reliability,
+        mode: SubMode::Push,
+        period: None,
     };
+    let writer = DDSWriter::new(dp, wr, &topic_name, &type_name, keyless);
+    let decoder = coders.new_decoder(&topic_name, &type_name, Box::new(writer));
+    let zsub_task = task::spawn(async move {
+        let rkey = ResKey::RName(key.clone());
+        let mut sub = z.declare_subscriber(&rkey, &sub_info).await.unwrap();
+        let mut isub = if keyless {
+            None
+        } else {
+            // The samples of a keyed topic may also be published on the keys of their instances
+            let ikey = ResKey::RName(format!("{}/*", key));
+            Some(z.declare_subscriber(&ikey, &sub_info).await.unwrap())
+        };
+        // A late-joining transient-local reader gets the history cached by the remote bridges
+        if transient_local {
+            for d in query_history(&z, &key, keyless).await {
+                route_sample_to_dds(&topic_name, d, decoder.as_ref(), source_timestamps);
+            }
+        }
+        if let Some(isub) = isub.as_mut() {
+            let mut stream = futures::stream::select(sub.stream(), isub.stream());
+            while let Some(d) = stream.next().await {
+                if is_topic_or_instance_key(&key, &d.res_name) {
+                    route_sample_to_dds(&topic_name, d, decoder.as_ref(), source_timestamps);
+                }
+            }
+        } else {
+            let stream = sub.stream();
+            while let Some(d) = stream.next().await {
+                route_sample_to_dds(&topic_name, d, decoder.as_ref(), source_timestamps);
+            }
+        }
+    });
+    Route {
+        entities: vec![wr],
+        zsub_task: Some(zsub_task),
+        endpoints: HashSet::new(),
+    }
+}
+
+// Removes an undiscovered endpoint from its routes, deleting the routes that are no longer used
+async fn remove_endpoint(
+    routes: &mut HashMap<String, Route>,
+    endpoint_routes: &mut HashMap<String, Vec<String>>,
+    endpoint: &str,
+) {
+    for key in endpoint_routes.remove(endpoint).unwrap_or_default() {
+        let unused = match routes.get_mut(&key) {
+            Some(route) => {
+                route.endpoints.remove(endpoint);
+                route.endpoints.is_empty()
+            }
+            None => false,
+        };
+        if unused {
+            delete_route(&key, routes.remove(&key).unwrap()).await;
+        }
+    }
+}
+
+// Deletes the routes that `endpoint` shares with other endpoints, so that they don't keep its
+// former QoS, and returns the entities of those endpoints to re-create the routes from.
+async fn release_shared_routes(
+    routes: &mut HashMap<String, Route>,
+    endpoint_routes: &mut HashMap<String, Vec<String>>,
+    discovered: &HashMap<String, Vec<MatchedEntity>>,
+    endpoint: &str,
+) -> Vec<MatchedEntity> {
+    let mut others = Vec::<String>::new();
+    for key in endpoint_routes.get(endpoint).cloned().unwrap_or_default() {
+        let shared = routes
+            .get(&key)
+            .map_or(false, |route| route.endpoints.iter().any(|e| e != endpoint));
+        if !shared {
+            continue;
+        }
+        let route = routes.remove(&key).unwrap();
+        for other in route.endpoints.iter().filter(|e| *e != endpoint) {
+            if let Some(keys) = endpoint_routes.get_mut(other) {
+                keys.retain(|k| *k != key);
+            }
+            if !others.contains(other) {
+                others.push(other.clone());
+            }
+        }
+        delete_route(&key, route).await;
+    }
+    others
+        .iter()
+        .filter_map(|other| discovered.get(other))
+        .flatten()
+        .cloned()
+        .collect()
+}
 
-    (config, scope, did, allow, coders)
+async fn delete_route(key: &str, route: Route) {
+    info!("Route removed for '{}'", key);
+    if let Some(zsub_task) = route.zsub_task {
+        zsub_task.cancel().await;
+    }
+    for entity in route.entities {
+        unsafe {
+            dds_delete(entity);
+        }
+    }
 }
 
-fn is_allowed(sre: &Option<Regex>, path: &str) -> bool {
-    match sre {
-        Some(re) => re.is_match(path),
-        _ => true,
+async fn remove_all_routes(routes: &mut HashMap<String, Route>) {
+    for (key, route) in routes.drain() {
+        delete_route(&key, route).await;
+    }
+}
+
+// Keeps track of the discovered entities, to create their routes later on
+fn remember(discovered: &mut HashMap<String, Vec<MatchedEntity>>, me: &MatchedEntity) {
+    match me {
+        MatchedEntity::DiscoveredPublication { key, .. }
+        | MatchedEntity::DiscoveredSubscription { key, .. }
+        | MatchedEntity::DiscoveredRemotePublication { key, .. } => {
+            discovered.entry(key.clone()).or_default().push(me.clone());
+        }
+        MatchedEntity::UndiscoveredPublication { key }
+        | MatchedEntity::ChangedPublication { key }
+        | MatchedEntity::UndiscoveredSubscription { key }
+        | MatchedEntity::ChangedSubscription { key }
+        | MatchedEntity::UndiscoveredRemotePublication { key } => {
+            discovered.remove(key);
+        }
+        MatchedEntity::LeadershipChanged { .. } => (),
     }
 }
 
@@ -164,177 +416,642 @@ async fn main() {
 
     const DDS_INFINITE_TIME: i64 = 0x7FFFFFFFFFFFFFFF;
     env_logger::init();
-    let (config, scope, did, allow_re, coders) = parse_args();
-    let dp = unsafe { dds_create_participant(did, std::ptr::null(), std::ptr::null()) };
+    let (config, scope, did, bridge_config, coders) = parse_args();
+    let scope = bridge_config.sim_time.scope(&scope);
+    let clock = bridge_config.sim_time.clock();
+    let dp = create_bridge_participant(did);
+    let pr = create_participants_reader(dp);
+    // In simulation the bridge runs on the time of /clock, read by a best-effort reader to
+    // match all its publishers
+    if clock.is_sim() {
+        create_forwarding_dds_reader(
+            dp,
+            CLOCK_TOPIC.to_string(),
+            CLOCK_TYPE.to_string(),
+            true,
+            QosHolder(Qos::default().to_qos_native()),
+            ResKey::RName(CLOCK_TOPIC.to_string()),
+            Box::new(ClockWriter::new(clock.clone())),
+            &Coders::new(),
+            pr,
+        );
+    }
     let z = Arc::new(open(config.into()).await.unwrap());
     let (tx, rx): (Sender<MatchedEntity>, Receiver<MatchedEntity>) = channel();
-    run_discovery(dp, tx);
+    let bridge_id = participant_id(dp);
+    let adverts = Arc::new(Mutex::new(HashMap::<String, RemotePublication>::new()));
+    run_remote_discovery(
+        z.clone(),
+        scope.clone(),
+        bridge_id.clone(),
+        adverts.clone(),
+        tx.clone(),
+    );
+    let filter_adverts = Arc::new(Mutex::new(HashMap::<String, SubscriptionFilter>::new()));
+    let remote_filters = Arc::new(RemoteFilters::default());
+    run_filters_exchange(
+        z.clone(),
+        scope.clone(),
+        bridge_id.clone(),
+        filter_adverts.clone(),
+        remote_filters.clone(),
+    );
+    let type_cache = Arc::new(TypeCache::default());
+    let type_info = |type_name: &str, keyless: bool| {
+        if bridge_config.ros2_names {
+            TypeInfo::new(&demangle_type(type_name), keyless, &bridge_config.types)
+        } else {
+            TypeInfo::new(type_name, keyless, &bridge_config.types)
+        }
+    };
+    run_types_queryable(z.clone(), scope.clone(), type_cache.clone());
+    if bridge_config.redundancy.enabled {
+        run_leader_election(
+            z.clone(),
+            scope.clone(),
+            bridge_id.clone(),
+            bridge_config.redundancy.lease,
+            tx.clone(),
+        );
+    }
+    // The static routes are created first, as if their endpoints had been discovered
+    for me in bridge_config.routes.matched_entities() {
+        tx.send(me).unwrap();
+    }
+    run_discovery(dp, pr, tx);
     let mut rid_map = HashMap::<String, ResourceId>::new();
-    let mut rd_map = HashMap::<String, dds_entity_t>::new();
-    let mut wr_map = HashMap::<String, dds_entity_t>::new();
-    let _zsub_map = HashMap::<String, CallbackSubscriber>::new();
+    let mut rd_map = HashMap::<String, Route>::new();
+    let mut wr_map = HashMap::<String, Route>::new();
+    // the keys of the routes used by each discovered endpoint
+    let mut endpoint_routes = HashMap::<String, Vec<String>>::new();
+    // In redundancy mode only the leader creates routes. The discovered entities are kept to
+    // create their routes from if this bridge becomes the leader, or when a route is re-created.
+    let mut leader = !bridge_config.redundancy.enabled;
+    let mut discovered = HashMap::<String, Vec<MatchedEntity>>::new();
+    // the goals sent by the local clients of each ROS 2 action, by action name
+    let mut action_goals = HashMap::<String, Arc<ActionGoals>>::new();
     while let Ok(me) = rx.recv() {
-        match me {
-            MatchedEntity::DiscoveredPublication {
-                topic_name,
-                type_name,
-                keyless,
-                partition,
-                qos,
-            } => {
-                debug!(
-                    "DiscoveredPublication({}, {}, {:?}",
-                    topic_name, type_name, partition
+        let entities = match me {
+            MatchedEntity::LeadershipChanged { leader: true } => {
+                info!(
+                    "Leader: creating the routes of {} discovered endpoints",
+                    discovered.len()
                 );
-                let key = match partition {
-                    Some(p) => format!("{}/{}/{}", scope, p, topic_name),
-                    None => format!("{}/{}", scope, topic_name),
-                };
-                if !is_allowed(&allow_re, &key) {
-                    info!(
-                        "Ignoring Publication for key {} as it is not allowed (see --allow option)",
-                        &key
-                    );
-                    break;
+                leader = true;
+                discovered.values().flatten().cloned().collect()
+            }
+            MatchedEntity::LeadershipChanged { leader: false } => {
+                info!("Standby: removing all the routes");
+                leader = false;
+                remove_all_routes(&mut rd_map).await;
+                remove_all_routes(&mut wr_map).await;
+                endpoint_routes.clear();
+                let adv_keys: Vec<String> = adverts.lock().unwrap().keys().cloned().collect();
+                for adv_key in adv_keys {
+                    withdraw(&z, &adverts, adv_key).await;
                 }
-                debug!("Declaring resource {}", key);
-                match rd_map.get(&key) {
-                    None => {
-                        let rkey = ResKey::RName(key.clone());
-                        let nrid = z.declare_resource(&rkey).await.unwrap();
-                        let rid = ResKey::RId(nrid);
-                        let _ = z.declare_publisher(&rid).await;
-                        rid_map.insert(key.clone(), nrid);
-                        info!(
-                            "New route: DDS '{}' => zenoh '{}' (rid={}) with type '{}'",
-                            topic_name, key, rid, type_name
-                        );
-                        let dr: dds_entity_t = create_forwarding_dds_reader(
-                            dp,
-                            topic_name,
-                            type_name,
-                            keyless,
-                            qos,
-                            rid,
-                            z.clone(),
-                            &coders,
-                        );
-                        rd_map.insert(key, dr);
-                    }
-                    _ => {
-                        debug!(
-                            "Already forwarding matching subscription {} -- ignoring",
-                            topic_name
-                        );
-                    }
+                let adv_keys: Vec<String> =
+                    filter_adverts.lock().unwrap().keys().cloned().collect();
+                for adv_key in adv_keys {
+                    withdraw_filter(&z, &filter_adverts, adv_key).await;
                 }
+                vec![]
             }
-            MatchedEntity::UndiscoveredPublication {
-                topic_name,
-                type_name,
-                partition,
-            } => {
-                debug!(
-                    "UndiscoveredPublication({}, {}, {:?}",
-                    topic_name, type_name, partition
-                );
+            MatchedEntity::ChangedPublication { ref key } if leader => {
+                remember(&mut discovered, &me);
+                let mut entities = release_shared_routes(
+                    &mut rd_map,
+                    &mut endpoint_routes,
+                    &discovered,
+                    key,
+                )
+                .await;
+                entities.insert(0, me);
+                entities
             }
-            MatchedEntity::DiscoveredSubscription {
-                topic_name,
-                type_name,
-                keyless,
-                partition,
-                qos,
-            } => {
-                debug!(
-                    "DiscoveredSubscription({}, {}, {:?}",
-                    topic_name, type_name, partition
-                );
-                let key = match &partition {
-                    Some(p) => format!("{}/{}/{}", scope, p, topic_name),
-                    None => format!("{}/{}", scope, topic_name),
-                };
-
-                if !is_allowed(&allow_re, &key) {
-                    info!("Ignoring subscription for key {} as it is not allowed (see --allow option)", &key);
-                    break;
+            MatchedEntity::ChangedSubscription { ref key } if leader => {
+                remember(&mut discovered, &me);
+                let mut entities = release_shared_routes(
+                    &mut wr_map,
+                    &mut endpoint_routes,
+                    &discovered,
+                    key,
+                )
+                .await;
+                entities.insert(0, me);
+                entities
+            }
+            me => {
+                remember(&mut discovered, &me);
+                if leader {
+                    vec![me]
+                } else {
+                    vec![]
                 }
-                if let Some(wr) = match wr_map.get(&key) {
-                    Some(_) => {
-                        debug!(
-                            "The Subscription({}, {}, {:?} is aready handled, IGNORING",
-                            topic_name, type_name, partition
-                        );
-                        None
+            }
+        };
+        for me in entities {
+            match me {
+                MatchedEntity::DiscoveredPublication {
+                    key: endpoint,
+                    participant,
+                    topic_name,
+                    type_name,
+                    keyless,
+                    partition,
+                    qos,
+                } => {
+                    debug!(
+                        "DiscoveredPublication({}, {}, {:?}",
+                        topic_name, type_name, partition
+                    );
+                    // The local topics are routed with their name in the fleet
+                    let fleet_topic = bridge_config.namespace.to_fleet(&topic_name);
+                    if bridge_config.services.enabled {
+                        if let Some((service, topic)) = service_name(&topic_name) {
+                            // The requests of a local client are routed as zenoh queries, its
+                            // replies being written by the same route: its reply reader and the
+                            // reply writer of a local server need no route
+                            if topic == ServiceTopic::Request {
+                                let key = bridge_config.partitions.key(
+                                    &scope,
+                                    partition.as_deref(),
+                                    &service_key_topic(
+                                        service_name(&fleet_topic).map_or(service, |(s, _)| s),
+                                    ),
+                                );
+                                if !bridge_config.policy.is_allowed(&Endpoint {
+                                    direction: Direction::DdsToZenoh,
+                                    topic_name: &topic_name,
+                                    type_name: &type_name,
+                                    partition: partition.as_deref(),
+                                    participant: &participant,
+                                    key: &key,
+                                }) {
+                                    info!(
+                                        "Ignoring service {} for key {} as it is not allowed (see the allow/deny policy)",
+                                        service, &key
+                                    );
+                                    continue;
+                                }
+                                if !rd_map.contains_key(&key) {
+                                    bridge_config
+                                        .partitions
+                                        .adjust_qos(qos.0, partition.as_deref());
+                                    // The client routes of the services of an action share its goals
+                                    let goals = action_service(service).map(|(action, _)| {
+                                        action_goals.entry(action.to_string()).or_default().clone()
+                                    });
+                                    match create_service_client_route(
+                                        z.clone(),
+                                        dp,
+                                        key.clone(),
+                                        service,
+                                        type_name.clone(),
+                                        qos,
+                                        &coders,
+                                        pr,
+                                        bridge_config.services.timeout,
+                                        goals,
+                                    ) {
+                                        Some((dr, wr)) => {
+                                            info!(
+                                                "New route: DDS service '{}' clients => zenoh queries '{}'",
+                                                service, key
+                                            );
+                                            rd_map.insert(
+                                                key.clone(),
+                                                Route {
+                                                    entities: vec![dr, wr],
+                                                    zsub_task: None,
+                                                    endpoints: HashSet::new(),
+                                                },
+                                            );
+                                        }
+                                        None => {
+                                            warn!(
+                                                "Ignoring service {} with unexpected request type {}",
+                                                service, type_name
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                }
+                                if let Some(route) = rd_map.get_mut(&key) {
+                                    route.endpoints.insert(endpoint.clone());
+                                }
+                                let keys = endpoint_routes.entry(endpoint).or_default();
+                                if !keys.contains(&key) {
+                                    keys.push(key);
+                                }
+                            }
+                            continue;
+                        }
                     }
-                    None => {
+                    let key = bridge_config.key(&scope, partition.as_deref(), &fleet_topic);
+                    if !bridge_config.policy.is_allowed(&Endpoint {
+                        direction: Direction::DdsToZenoh,
+                        topic_name: &topic_name,
+                        type_name: &type_name,
+                        partition: partition.as_deref(),
+                        participant: &participant,
+                        key: &key,
+                    }) {
                         info!(
-                            "New route: zenoh '{}' => DDS '{}' with type '{}'",
-                            key, topic_name, type_name
+                            "Ignoring Publication for key {} as it is not allowed (see the allow/deny policy)",
+                            &key
                         );
-                        // Workaround for the Publisher to correctly match with a FastRTPS Subscriber declaring a Reliability max_blocking_time < infinite
-                        let mut kind: dds_reliability_kind_t =
-                            dds_reliability_kind_DDS_RELIABILITY_RELIABLE;
-                        let mut max_blocking_time: dds_duration_t = 0;
-                        unsafe {
-                            if dds_qget_reliability(qos.0, &mut kind, &mut max_blocking_time)
-                                && max_blocking_time < DDS_INFINITE_TIME
-                            {
-                                // Add 1 nanosecond to max_blocking_time for the Publisher
-                                max_blocking_time += 1;
-                                dds_qset_reliability(qos.0, kind, max_blocking_time);
+                        continue;
+                    }
+                    publish_type_info(&z, &scope, &type_cache, type_info(&type_name, keyless))
+                        .await;
+                    // The publication is advertised with the partitions allowed so far
+                    let adv_key = publication_adv_key(&scope, &bridge_id, &endpoint);
+                    let advertised = adverts.lock().unwrap().get(&adv_key).cloned();
+                    match advertised {
+                        None => {
+                            let publication = RemotePublication {
+                                topic_name: fleet_topic.clone(),
+                                type_name: type_name.clone(),
+                                keyless,
+                                participant: participant.clone(),
+                                qos: unsafe { Qos::from_qos_native(qos.0) },
+                            };
+                            advertise(&z, &adverts, adv_key, publication).await;
+                        }
+                        Some(mut publication) => {
+                            if let Some(p) = &partition {
+                                let ps = publication.qos.partitions.get_or_insert_with(Vec::new);
+                                if !ps.contains(p) {
+                                    ps.push(p.clone());
+                                    advertise(&z, &adverts, adv_key, publication).await;
+                                }
                             }
                         }
-
-                        let wr = create_forwarding_dds_writer(
-                            dp,
-                            topic_name.clone(),
-                            type_name.clone(),
-                            keyless,
-                            qos,
-                        );
-                        wr_map.insert(key.clone(), wr);
-                        Some(wr)
                     }
-                } {
+                    bridge_config.partitions.adjust_qos(qos.0, partition.as_deref());
+                    debug!("Declaring resource {}", key);
+                    match rd_map.get_mut(&key) {
+                        None => {
+                            let rkey = ResKey::RName(key.clone());
+                            let nrid = z.declare_resource(&rkey).await.unwrap();
+                            let rid = ResKey::RId(nrid);
+                            let _ = z.declare_publisher(&rid).await;
+                            rid_map.insert(key.clone(), nrid);
+                            info!(
+                                "New route: DDS '{}' => zenoh '{}' (rid={}) with type '{}'",
+                                topic_name, key, rid, type_name
+                            );
+                            let zqos = bridge_config
+                                .zenoh_qos
+                                .apply(&topic_name, unsafe { ZenohQos::from_dds(qos.0) });
+                            let mut writer = ZenohWriter::new(z.clone(), rid.clone())
+                                .with_source_timestamps(bridge_config.source_timestamps)
+                                .with_congestion_control(zqos.congestion_control);
+                            // The history of a transient-local publication is kept for the
+                            // late-joining remote readers
+                            let mut history_task = None;
+                            if unsafe { is_transient_local(qos.0) } {
+                                let mut history =
+                                    HistoryCache::new(key.clone(), unsafe { history_depth(qos.0) });
+                                if let Some(lifespan) = unsafe { lifespan(qos.0) } {
+                                    history = history.with_lifespan(lifespan, clock.clone());
+                                }
+                                let history = Arc::new(history);
+                                history_task =
+                                    Some(run_history_queryable(z.clone(), history.clone()));
+                                writer = writer.with_history(history);
+                            }
+                            // The simulation time is routed as is, without waiting behind the
+                            // congested data. The batches of transforms replace the downsampling:
+                            // each holds transforms that the next ones may not update.
+                            let handling = bridge_config.tf.handling(&topic_name, &type_name);
+                            let writer: Box<dyn Writer + Send> = match handling {
+                                _ if clock.is_sim() && topic_name == CLOCK_TOPIC => Box::new(
+                                    writer.with_congestion_control(CongestionControl::Drop),
+                                ),
+                                Some(TfHandling::Batch(period)) => Box::new(TfBatcher::new(
+                                    Box::new(writer),
+                                    period,
+                                    clock.clone(),
+                                )),
+                                handling => {
+                                    // The remote filters may be advertised after the route is
+                                    // created: the downsampler forwards the samples directly
+                                    // as long as the route has neither limit nor filter
+                                    let writer: Box<dyn Writer + Send> =
+                                        Box::new(Downsampler::new(
+                                            Box::new(writer),
+                                            key.clone(),
+                                            bridge_config.rate_limits.period(&topic_name),
+                                            remote_filters.clone(),
+                                            clock.clone(),
+                                        ));
+                                    if handling == Some(TfHandling::AggregateStatic) {
+                                        Box::new(TfStaticAggregator::new(writer))
+                                    } else {
+                                        writer
+                                    }
+                                }
+                            };
+                            let dr: dds_entity_t = create_forwarding_dds_reader(
+                                dp, topic_name, type_name, keyless, qos, rid, writer, &coders, pr,
+                            );
+                            let mut endpoints = HashSet::new();
+                            endpoints.insert(endpoint.clone());
+                            rd_map.insert(
+                                key.clone(),
+                                Route {
+                                    entities: vec![dr],
+                                    zsub_task: history_task,
+                                    endpoints,
+                                },
+                            );
+                        }
+                        Some(route) => {
+                            debug!(
+                                "Already forwarding matching subscription {} -- ignoring",
+                                topic_name
+                            );
+                            route.endpoints.insert(endpoint.clone());
+                        }
+                    }
+                    let keys = endpoint_routes.entry(endpoint).or_default();
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+                MatchedEntity::UndiscoveredPublication { key }
+                | MatchedEntity::ChangedPublication { key } => {
+                    debug!("UndiscoveredPublication({})", key);
+                    remove_endpoint(&mut rd_map, &mut endpoint_routes, &key).await;
+                    withdraw(&z, &adverts, publication_adv_key(&scope, &bridge_id, &key)).await;
+                }
+                MatchedEntity::DiscoveredSubscription {
+                    key: endpoint,
+                    participant,
+                    topic_name,
+                    type_name,
+                    keyless,
+                    partition,
+                    qos,
+                } => {
                     debug!(
-                        "The Subscription({}, {}, {:?} is new setting up zenoh and DDS endpoings",
+                        "DiscoveredSubscription({}, {}, {:?}",
                         topic_name, type_name, partition
                     );
-                    let sub_info = SubInfo {
-                        reliability: Reliability::Reliable,
-                        mode: SubMode::Push,
-                        period: None,
-                    };
+                    // The local topics are routed with their name in the fleet
+                    let fleet_topic = bridge_config.namespace.to_fleet(&topic_name);
+                    if bridge_config.services.enabled {
+                        if let Some((service, topic)) = service_name(&topic_name) {
+                            // The zenoh queries are routed as requests to a local server, its
+                            // replies being read by the same route
+                            if topic == ServiceTopic::Request {
+                                let key = bridge_config.partitions.key(
+                                    &scope,
+                                    partition.as_deref(),
+                                    &service_key_topic(
+                                        service_name(&fleet_topic).map_or(service, |(s, _)| s),
+                                    ),
+                                );
+                                if !bridge_config.policy.is_allowed(&Endpoint {
+                                    direction: Direction::ZenohToDds,
+                                    topic_name: &topic_name,
+                                    type_name: &type_name,
+                                    partition: partition.as_deref(),
+                                    participant: &participant,
+                                    key: &key,
+                                }) {
+                                    info!(
+                                        "Ignoring service {} for key {} as it is not allowed (see the allow/deny policy)",
+                                        service, &key
+                                    );
+                                    continue;
+                                }
+                                if !wr_map.contains_key(&key) {
+                                    bridge_config
+                                        .partitions
+                                        .adjust_qos(qos.0, partition.as_deref());
+                                    match create_service_server_route(
+                                        z.clone(),
+                                        dp,
+                                        key.clone(),
+                                        &bridge_id,
+                                        service,
+                                        type_name.clone(),
+                                        qos,
+                                        &coders,
+                                        pr,
+                                        bridge_config.services.timeout,
+                                    ) {
+                                        Some((wr, dr, task)) => {
+                                            info!(
+                                                "New route: zenoh queries '{}' => DDS service '{}' server",
+                                                key, service
+                                            );
+                                            wr_map.insert(
+                                                key.clone(),
+                                                Route {
+                                                    entities: vec![wr, dr],
+                                                    zsub_task: Some(task),
+                                                    endpoints: HashSet::new(),
+                                                },
+                                            );
+                                        }
+                                        None => {
+                                            warn!(
+                                                "Ignoring service {} with unexpected request type {}",
+                                                service, type_name
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                }
+                                if let Some(route) = wr_map.get_mut(&key) {
+                                    route.endpoints.insert(endpoint.clone());
+                                }
+                                let keys = endpoint_routes.entry(endpoint).or_default();
+                                if !keys.contains(&key) {
+                                    keys.push(key);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    let key = bridge_config.key(&scope, partition.as_deref(), &fleet_topic);
 
-                    let zn = z.clone();
-                    let writer = DDSWriter{
-                       wr, dp, keyless,
-                       ton: topic_name.clone(),
-                       tyn: topic_name.clone(),
+                    if !bridge_config.policy.is_allowed(&Endpoint {
+                        direction: Direction::ZenohToDds,
+                        topic_name: &topic_name,
+                        type_name: &type_name,
+                        partition: partition.as_deref(),
+                        participant: &participant,
+                        key: &key,
+                    }) {
+                        info!("Ignoring subscription for key {} as it is not allowed (see the allow/deny policy)", &key);
+                        continue;
+                    }
+                    publish_type_info(&z, &scope, &type_cache, type_info(&type_name, keyless))
+                        .await;
+                    // The time_based_filter of the subscription is advertised for the
+                    // remote bridges to downsample the routed samples accordingly
+                    let mut time_based_filter: dds_duration_t = 0;
+                    unsafe {
+                        dds_qget_time_based_filter(qos.0, &mut time_based_filter);
+                    }
+                    let adv_key = filter_adv_key(&scope, &bridge_id, &endpoint);
+                    let advertised = filter_adverts.lock().unwrap().get(&adv_key).cloned();
+                    let filter = match advertised {
+                        None => Some(SubscriptionFilter {
+                            keys: vec![key.clone()],
+                            time_based_filter,
+                        }),
+                        Some(mut filter) if !filter.keys.contains(&key) => {
+                            filter.keys.push(key.clone());
+                            Some(filter)
+                        }
+                        Some(_) => None,
                     };
-                    let decoder = coders.new_decoder(&topic_name, &type_name, Box::new(writer));
-                    task::spawn(async move {
-                        let rkey = ResKey::RName(key);
-                        let mut sub = zn.declare_subscriber(&rkey, &sub_info).await.unwrap();
-                        let stream = sub.stream();
+                    if let Some(filter) = filter {
+                        advertise_filter(&z, &filter_adverts, adv_key, filter).await;
+                    }
+                    match wr_map.get_mut(&key) {
+                        Some(route) => {
+                            debug!(
+                                "The Subscription({}, {}, {:?} is aready handled, IGNORING",
+                                topic_name, type_name, partition
+                            );
+                            route.endpoints.insert(endpoint.clone());
+                        }
+                        None => {
+                            debug!(
+                                "The Subscription({}, {}, {:?} is new setting up zenoh and DDS endpoings",
+                                topic_name, type_name, partition
+                            );
+                            // Workaround for the Publisher to correctly match with a FastRTPS Subscriber declaring a Reliability max_blocking_time < infinite
+                            let mut kind: dds_reliability_kind_t =
+                                dds_reliability_kind_DDS_RELIABILITY_RELIABLE;
+                            let mut max_blocking_time: dds_duration_t = 0;
+                            bridge_config.partitions.adjust_qos(qos.0, partition.as_deref());
+                            unsafe {
+                                dds_qset_history(qos.0, dds_history_kind_DDS_HISTORY_KEEP_ALL, 0);
+                                if dds_qget_reliability(qos.0, &mut kind, &mut max_blocking_time)
+                                    && max_blocking_time < DDS_INFINITE_TIME
+                                {
+                                    // Add 1 nanosecond to max_blocking_time for the Publisher
+                                    max_blocking_time += 1;
+                                    dds_qset_reliability(qos.0, kind, max_blocking_time);
+                                }
+                            }
 
-                        while let Some(d) = stream.next().await {
-                            log::trace!("Route data to DDS '{}'", topic_name);
-                            decoder.decode(d.payload.to_vec());
+                            let zqos = bridge_config
+                                .zenoh_qos
+                                .apply(&topic_name, unsafe { ZenohQos::from_dds(qos.0) });
+                            let mut route = create_zenoh_to_dds_route(
+                                z.clone(),
+                                dp,
+                                key.clone(),
+                                topic_name,
+                                type_name,
+                                keyless,
+                                qos,
+                                &coders,
+                                bridge_config.source_timestamps,
+                                zqos.reliability,
+                            );
+                            route.endpoints.insert(endpoint.clone());
+                            wr_map.insert(key.clone(), route);
+                        }
+                    }
+                    let keys = endpoint_routes.entry(endpoint).or_default();
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+                MatchedEntity::UndiscoveredSubscription { key }
+                | MatchedEntity::ChangedSubscription { key } => {
+                    debug!("UndiscoveredSubscription({})", key);
+                    remove_endpoint(&mut wr_map, &mut endpoint_routes, &key).await;
+                    withdraw_filter(
+                        &z,
+                        &filter_adverts,
+                        filter_adv_key(&scope, &bridge_id, &key),
+                    )
+                    .await;
+                }
+                MatchedEntity::DiscoveredRemotePublication {
+                    key: endpoint,
+                    participant,
+                    topic_name,
+                    type_name,
+                    keyless,
+                    partition,
+                    qos,
+                } => {
+                    debug!(
+                        "DiscoveredRemotePublication({}, {}, {:?}",
+                        topic_name, type_name, partition
+                    );
+                    // The remote publications are advertised with their name in the fleet
+                    let fleet_topic = topic_name;
+                    let topic_name = match bridge_config.namespace.to_local(&fleet_topic) {
+                        Some(topic_name) => topic_name,
+                        None => {
+                            debug!(
+                                "Ignoring remote publication on {}: not in this robot's namespace",
+                                fleet_topic
+                            );
+                            continue;
                         }
-                    });
+                    };
+                    let key = bridge_config.key(&scope, partition.as_deref(), &fleet_topic);
+                    if !bridge_config.policy.is_allowed(&Endpoint {
+                        direction: Direction::ZenohToDds,
+                        topic_name: &topic_name,
+                        type_name: &type_name,
+                        partition: partition.as_deref(),
+                        participant: &participant,
+                        key: &key,
+                    }) {
+                        info!("Ignoring remote publication for key {} as it is not allowed (see the allow/deny policy)", &key);
+                        continue;
+                    }
+                    // Create the DDS writer ahead of any local subscription, mirroring the remote writer's QoS
+                    match wr_map.get_mut(&key) {
+                        Some(route) => {
+                            route.endpoints.insert(endpoint.clone());
+                        }
+                        None => {
+                            bridge_config.partitions.adjust_qos(qos.0, partition.as_deref());
+                            let zqos = bridge_config
+                                .zenoh_qos
+                                .apply(&topic_name, unsafe { ZenohQos::from_dds(qos.0) });
+                            let mut route = create_zenoh_to_dds_route(
+                                z.clone(),
+                                dp,
+                                key.clone(),
+                                topic_name,
+                                type_name,
+                                keyless,
+                                qos,
+                                &coders,
+                                bridge_config.source_timestamps,
+                                zqos.reliability,
+                            );
+                            route.endpoints.insert(endpoint.clone());
+                            wr_map.insert(key.clone(), route);
+                        }
+                    }
+                    let keys = endpoint_routes.entry(endpoint).or_default();
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
                 }
-            }
-            MatchedEntity::UndiscoveredSubscription {
-                topic_name,
-                type_name,
-                partition,
-            } => {
-                debug!(
-                    "UndiscoveredSubscription({}, {}, {:?}",
-                    topic_name, type_name, partition
-                );
+                MatchedEntity::UndiscoveredRemotePublication { key } => {
+                    debug!("UndiscoveredRemotePublication({})", key);
+                    remove_endpoint(&mut wr_map, &mut endpoint_routes, &key).await;
+                }
+                MatchedEntity::LeadershipChanged { .. } => (),
             }
         }
     }
@@ -0,0 +1,120 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use cyclors::*;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::os::raw;
+
+// The user_data of the DDS participants of the bridges, allowing them to recognise each other.
+// The endpoints of another bridge's participant and the data it writes are never routed,
+// since they come from zenoh in the first place.
+pub const BRIDGE_USER_DATA: &[u8] = b"zenoh-bridge-dds";
+
+/// Creates the DDS participant of a bridge, marked as such by its user_data.
+pub fn create_bridge_participant(domain_id: dds_domainid_t) -> dds_entity_t {
+    unsafe {
+        let qos = dds_create_qos();
+        dds_qset_userdata(
+            qos,
+            BRIDGE_USER_DATA.as_ptr() as *const raw::c_void,
+            BRIDGE_USER_DATA.len() as u64,
+        );
+        let dp = dds_create_participant(domain_id, qos, std::ptr::null());
+        dds_delete_qos(qos);
+        dp
+    }
+}
+
+/// Creates a reader of the DCPSParticipant built-in topic, used to look up the discovered participants.
+pub fn create_participants_reader(dp: dds_entity_t) -> dds_entity_t {
+    unsafe {
+        dds_create_reader(
+            dp,
+            DDS_BUILTIN_TOPIC_DCPSPARTICIPANT,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    }
+}
+
+unsafe fn has_bridge_user_data(qos: *const dds_qos_t) -> bool {
+    let mut ud: *mut raw::c_void = std::ptr::null_mut();
+    let mut sz = 0u64;
+    if !dds_qget_userdata(qos, &mut ud, &mut sz) || ud.is_null() {
+        return false;
+    }
+    let result = std::slice::from_raw_parts(ud as *const u8, sz as usize) == BRIDGE_USER_DATA;
+    dds_free(ud);
+    result
+}
+
+/// Returns true if the participant with key `participant_key` is the participant of a bridge.
+///
+/// # Safety
+/// `pr` must be a reader created with `create_participants_reader`.
+pub unsafe fn is_bridge_participant(
+    pr: dds_entity_t,
+    participant_key: &dds_builtintopic_guid_t,
+) -> bool {
+    // DDSI discovers a participant before its endpoints, thus it is already known here
+    let mut key_sample: dds_builtintopic_participant_t = MaybeUninit::zeroed().assume_init();
+    key_sample.key = *participant_key;
+    let ih = dds_lookup_instance(pr, &key_sample as *const _ as *const raw::c_void);
+    if ih == 0 {
+        return false;
+    }
+    #[allow(clippy::uninit_assumed_init)]
+    let mut si: [dds_sample_info_t; 1] = { MaybeUninit::uninit().assume_init() };
+    let mut samples: [*mut raw::c_void; 1] = [std::ptr::null_mut()];
+    let n = dds_read_instance(pr, samples.as_mut_ptr(), si.as_mut_ptr(), 1, 1, ih);
+    if n <= 0 {
+        return false;
+    }
+    let participant = samples[0] as *const dds_builtintopic_participant_t;
+    let result = si[0].valid_data && has_bridge_user_data((*participant).qos);
+    dds_return_loan(pr, samples.as_mut_ptr(), n);
+    result
+}
+
+/// The writers matched by a forwarding reader that belong to other bridges, by instance handle.
+#[derive(Debug, Default)]
+pub struct BridgeWriters {
+    writers: HashMap<dds_instance_handle_t, bool>,
+}
+
+impl BridgeWriters {
+    /// Returns true if the writer `publication_handle` matched by reader `dr` belongs to a bridge.
+    ///
+    /// # Safety
+    /// `pr` must be a reader created with `create_participants_reader`.
+    pub unsafe fn is_bridge_writer(
+        &mut self,
+        pr: dds_entity_t,
+        dr: dds_entity_t,
+        publication_handle: dds_instance_handle_t,
+    ) -> bool {
+        if let Some(result) = self.writers.get(&publication_handle) {
+            return *result;
+        }
+        let publication = dds_get_matched_publication_data(dr, publication_handle);
+        if publication.is_null() {
+            // not (or no longer) matched: don't cache it
+            return false;
+        }
+        let result = is_bridge_participant(pr, &(*publication).participant_key);
+        dds_builtintopic_free_endpoint(publication);
+        self.writers.insert(publication_handle, result);
+        result
+    }
+}
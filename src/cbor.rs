@@ -0,0 +1,434 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::type_registry::{BaseType, Field, MessageType, Multiplicity, TypeRegistry, Value};
+use std::convert::TryFrom;
+
+// The major types of CBOR (RFC 8949)
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+// The max nesting of the CBOR items
+const MAX_DEPTH: usize = 64;
+
+// Writes the head of an item: its major type and its argument, in the fewest bytes
+fn head(buf: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        buf.push(major | n as u8);
+    } else if n <= u8::MAX as u64 {
+        buf.push(major | 24);
+        buf.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+        buf.push(major | 25);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        buf.push(major | 26);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        buf.push(major | 27);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn signed(buf: &mut Vec<u8>, v: i64) {
+    if v >= 0 {
+        head(buf, UNSIGNED, v as u64);
+    } else {
+        // the negative integers are encoded as -1 - v
+        head(buf, NEGATIVE, !(v as u64));
+    }
+}
+
+fn write(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Bool(b) => buf.push(if *b { 0xf5 } else { 0xf4 }),
+        Value::I8(v) => signed(buf, *v as i64),
+        Value::U8(v) => head(buf, UNSIGNED, *v as u64),
+        Value::I16(v) => signed(buf, *v as i64),
+        Value::U16(v) => head(buf, UNSIGNED, *v as u64),
+        Value::I32(v) => signed(buf, *v as i64),
+        Value::U32(v) => head(buf, UNSIGNED, *v as u64),
+        Value::I64(v) => signed(buf, *v),
+        Value::U64(v) => head(buf, UNSIGNED, *v),
+        // the floats keep their precision, and their NaN payloads
+        Value::F32(v) => {
+            buf.push(SIMPLE << 5 | 26);
+            buf.extend_from_slice(&v.to_bits().to_be_bytes());
+        }
+        Value::F64(v) => {
+            buf.push(SIMPLE << 5 | 27);
+            buf.extend_from_slice(&v.to_bits().to_be_bytes());
+        }
+        Value::String(s) => {
+            head(buf, TEXT, s.len() as u64);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        // the arrays of bytes, e.g. the data of an image, are byte strings
+        Value::Array(elements)
+            if !elements.is_empty() && elements.iter().all(|e| matches!(e, Value::U8(_))) =>
+        {
+            head(buf, BYTES, elements.len() as u64);
+            buf.extend(elements.iter().map(|e| match e {
+                Value::U8(b) => *b,
+                _ => 0,
+            }));
+        }
+        Value::Array(elements) => {
+            head(buf, ARRAY, elements.len() as u64);
+            for e in elements {
+                write(buf, e);
+            }
+        }
+        Value::Struct(fields) => {
+            head(buf, MAP, fields.len() as u64);
+            for (name, v) in fields {
+                head(buf, TEXT, name.len() as u64);
+                buf.extend_from_slice(name.as_bytes());
+                write(buf, v);
+            }
+        }
+    }
+}
+
+/// The CBOR form of a value: the structures are maps of their field names, the arrays of bytes
+/// are byte strings and the other arrays and sequences are arrays.
+pub fn to_cbor(value: &Value) -> Vec<u8> {
+    let mut buf = vec![];
+    write(&mut buf, value);
+    buf
+}
+
+// A CBOR data item, as needed to tell the value of a field
+#[derive(Debug)]
+enum Item {
+    Unsigned(u64),
+    // -1 - n
+    Negative(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Item>),
+    Map(Vec<(Item, Item)>),
+    Bool(bool),
+    Null,
+    F32(f32),
+    F64(f64),
+}
+
+// The float of a half-precision float
+fn half_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        0x1f if mantissa == 0.0 => f64::INFINITY,
+        0x1f => f64::NAN,
+        e => (mantissa + 1024.0) * 2f64.powi(e as i32 - 25),
+    };
+    if half & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+struct CborReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CborReader<'a> {
+    fn bytes(&mut self, n: u64) -> Result<&'a [u8], String> {
+        let end = usize::try_from(n)
+            .ok()
+            .and_then(|n| n.checked_add(self.pos))
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| String::from("Truncated CBOR data"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn argument(&mut self, info: u8) -> Result<u64, String> {
+        let size = match info {
+            0..=23 => return Ok(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Err(String::from("Unsupported indefinite length in CBOR data")),
+            _ => return Err(format!("Invalid CBOR additional information {}", info)),
+        };
+        Ok(self.bytes(size)?.iter().fold(0, |n, b| n << 8 | *b as u64))
+    }
+
+    // The count of the elements of an array or map, each of them taking at least a byte
+    fn count(&mut self, info: u8) -> Result<usize, String> {
+        let n = self.argument(info)?;
+        if n > (self.buf.len() - self.pos) as u64 {
+            return Err(String::from("Truncated CBOR data"));
+        }
+        Ok(n as usize)
+    }
+
+    fn item(&mut self, depth: usize) -> Result<Item, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("Too deeply nested CBOR data"));
+        }
+        let initial = self.bytes(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        Ok(match major {
+            UNSIGNED => Item::Unsigned(self.argument(info)?),
+            NEGATIVE => Item::Negative(self.argument(info)?),
+            BYTES => {
+                let n = self.argument(info)?;
+                Item::Bytes(self.bytes(n)?.to_vec())
+            }
+            TEXT => {
+                let n = self.argument(info)?;
+                Item::Text(
+                    String::from_utf8(self.bytes(n)?.to_vec())
+                        .map_err(|_| String::from("Invalid UTF-8 string in CBOR data"))?,
+                )
+            }
+            ARRAY => {
+                let n = self.count(info)?;
+                Item::Array(
+                    (0..n)
+                        .map(|_| self.item(depth + 1))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            MAP => {
+                let n = self.count(info)?;
+                let mut entries = Vec::with_capacity(n);
+                for _ in 0..n {
+                    entries.push((self.item(depth + 1)?, self.item(depth + 1)?));
+                }
+                Item::Map(entries)
+            }
+            // the tags are ignored, their item being kept
+            TAG => {
+                self.argument(info)?;
+                self.item(depth + 1)?
+            }
+            _ => match info {
+                20 => Item::Bool(false),
+                21 => Item::Bool(true),
+                22 | 23 => Item::Null,
+                25 => Item::F64(half_to_f64(self.argument(info)? as u16)),
+                26 => Item::F32(f32::from_bits(self.argument(info)? as u32)),
+                27 => Item::F64(f64::from_bits(self.argument(info)?)),
+                _ => return Err(format!("Unsupported CBOR simple value {}", info)),
+            },
+        })
+    }
+}
+
+/// The value of the type `type_name` given in CBOR. As in JSON, the missing fields take their
+/// default value.
+pub fn from_cbor(types: &TypeRegistry, type_name: &str, data: &[u8]) -> Result<Value, String> {
+    let t = types
+        .get(type_name)
+        .ok_or_else(|| format!("Unknown type {}", type_name))?;
+    let mut reader = CborReader { buf: data, pos: 0 };
+    let item = reader.item(0)?;
+    if reader.pos != data.len() {
+        return Err(String::from("Trailing bytes after the CBOR data"));
+    }
+    struct_from_item(types, t, item)
+}
+
+fn struct_from_item(types: &TypeRegistry, t: &MessageType, item: Item) -> Result<Value, String> {
+    let entries = match item {
+        Item::Map(entries) => entries,
+        i => {
+            return Err(format!(
+                "Invalid {:?} for a {}: a map is expected",
+                i, t.name
+            ))
+        }
+    };
+    let mut values: Vec<Option<Value>> = vec![None; t.fields.len()];
+    for (key, item) in entries {
+        let i = match &key {
+            Item::Text(name) => t.fields.iter().position(|f| &f.name == name),
+            _ => None,
+        }
+        .ok_or_else(|| format!("Unknown field {:?} of {}", key, t.name))?;
+        let f = &t.fields[i];
+        values[i] = Some(
+            field_from_item(types, f, item)
+                .map_err(|e| format!("{} in the field {} of {}", e, f.name, t.name))?,
+        );
+    }
+    let mut fields = Vec::with_capacity(t.fields.len());
+    for (f, value) in t.fields.iter().zip(values) {
+        let value = match value {
+            Some(value) => value,
+            None => types.default_value(&f.base, f.multiplicity)?,
+        };
+        fields.push((f.name.clone(), value));
+    }
+    Ok(Value::Struct(fields))
+}
+
+fn field_from_item(types: &TypeRegistry, f: &Field, item: Item) -> Result<Value, String> {
+    match (f.multiplicity, &f.base, item) {
+        (Multiplicity::Single, base, item) => single_from_item(types, base, item),
+        (_, BaseType::U8, Item::Bytes(bytes)) => {
+            Ok(Value::Array(bytes.into_iter().map(Value::U8).collect()))
+        }
+        (_, base, Item::Array(items)) => Ok(Value::Array(
+            items
+                .into_iter()
+                .map(|i| single_from_item(types, base, i))
+                .collect::<Result<_, _>>()?,
+        )),
+        (_, _, item) => Err(format!("Invalid {:?}: an array is expected", item)),
+    }
+}
+
+fn int<T: TryFrom<i128>>(item: &Item) -> Option<T> {
+    let n = match item {
+        Item::Unsigned(n) => *n as i128,
+        Item::Negative(n) => -1 - *n as i128,
+        _ => return None,
+    };
+    T::try_from(n).ok()
+}
+
+fn single_from_item(types: &TypeRegistry, base: &BaseType, item: Item) -> Result<Value, String> {
+    let invalid = |item: &Item| format!("Invalid {:?} for a {:?}", item, base);
+    Ok(match (base, item) {
+        (BaseType::Bool, Item::Bool(b)) => Value::Bool(b),
+        (BaseType::I8, i) => Value::I8(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::U8, i) => Value::U8(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::I16, i) => Value::I16(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::U16, i) => Value::U16(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::I32, i) => Value::I32(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::U32, i) => Value::U32(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::I64, i) => Value::I64(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::U64, i) => Value::U64(int(&i).ok_or_else(|| invalid(&i))?),
+        (BaseType::F32, Item::F32(v)) => Value::F32(v),
+        (BaseType::F32, Item::F64(v)) => Value::F32(v as f32),
+        (BaseType::F64, Item::F32(v)) => Value::F64(v as f64),
+        (BaseType::F64, Item::F64(v)) => Value::F64(v),
+        (BaseType::F32, i) => Value::F32(int::<i64>(&i).ok_or_else(|| invalid(&i))? as f32),
+        (BaseType::F64, i) => Value::F64(int::<i64>(&i).ok_or_else(|| invalid(&i))? as f64),
+        (BaseType::String, Item::Text(s)) => Value::String(s),
+        (BaseType::Message(name), i) => struct_from_item(
+            types,
+            types
+                .get(name)
+                .ok_or_else(|| format!("Unknown type {}", name))?,
+            i,
+        )?,
+        (_, i) => return Err(invalid(&i)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cbor() {
+        let mut registry = TypeRegistry::default();
+        registry
+            .add_msg(
+                "demo",
+                "Sample",
+                "bool ok\nint8 a\nint64 b\nuint64 c\nfloat32 d\nfloat64 e\nstring f\n\
+                 uint8[] data\nint16[2] g\nSample2[] nested\n",
+            )
+            .unwrap();
+        registry
+            .add_msg("demo", "Sample2", "string name\n")
+            .unwrap();
+        let value = Value::Struct(vec![
+            (String::from("ok"), Value::Bool(true)),
+            (String::from("a"), Value::I8(-128)),
+            (String::from("b"), Value::I64(i64::MIN)),
+            (String::from("c"), Value::U64(u64::MAX)),
+            (String::from("d"), Value::F32(0.1)),
+            (String::from("e"), Value::F64(-0.0)),
+            (String::from("f"), Value::String(String::from("é"))),
+            (
+                String::from("data"),
+                Value::Array(vec![Value::U8(0), Value::U8(255)]),
+            ),
+            (
+                String::from("g"),
+                Value::Array(vec![Value::I16(-300), Value::I16(300)]),
+            ),
+            (
+                String::from("nested"),
+                Value::Array(vec![Value::Struct(vec![(
+                    String::from("name"),
+                    Value::String(String::new()),
+                )])]),
+            ),
+        ]);
+        let cbor = to_cbor(&value);
+        #[rustfmt::skip]
+        assert_eq!(
+            cbor,
+            vec![
+                0xaa,
+                0x62, b'o', b'k', 0xf5,
+                0x61, b'a', 0x38, 0x7f,
+                0x61, b'b', 0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0x61, b'c', 0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0x61, b'd', 0xfa, 0x3d, 0xcc, 0xcc, 0xcd,
+                0x61, b'e', 0xfb, 0x80, 0, 0, 0, 0, 0, 0, 0,
+                0x61, b'f', 0x62, 0xc3, 0xa9,
+                0x64, b'd', b'a', b't', b'a', 0x42, 0x00, 0xff,
+                0x61, b'g', 0x82, 0x39, 0x01, 0x2b, 0x19, 0x01, 0x2c,
+                0x66, b'n', b'e', b's', b't', b'e', b'd', 0x81, 0xa1, 0x64, b'n', b'a', b'm', b'e', 0x60,
+            ]
+        );
+        let decoded = from_cbor(&registry, "demo/msg/Sample", &cbor).unwrap();
+        assert_eq!(decoded, value);
+        let cdr = registry.encode("demo/msg/Sample", &value).unwrap();
+        assert_eq!(registry.encode("demo/msg/Sample", &decoded).unwrap(), cdr);
+
+        // the missing fields take their default value, and the integers fit in their type
+        let partial = from_cbor(
+            &registry,
+            "demo/msg/Sample",
+            &[0xa1, 0x61, b'd', 0xf9, 0x3c, 0x00],
+        )
+        .unwrap();
+        assert_eq!(partial.field("d"), Some(&Value::F32(1.0)));
+        assert_eq!(
+            partial.field("g"),
+            Some(&Value::Array(vec![Value::I16(0); 2]))
+        );
+        assert!(from_cbor(
+            &registry,
+            "demo/msg/Sample",
+            &[0xa1, 0x61, b'a', 0x18, 0x80]
+        )
+        .is_err());
+        assert!(from_cbor(&registry, "demo/msg/Sample", &[0xa1, 0x61, b'x', 0xf5]).is_err());
+        assert!(from_cbor(&registry, "demo/msg/Sample", &cbor[..cbor.len() - 1]).is_err());
+        assert!(from_cbor(&registry, "demo/msg/Sample", &[0x9f]).is_err());
+    }
+}
@@ -1,10 +1,16 @@
 extern crate yaml_rust;
 
-use zenoh::net::{RBuf, ResKey, Session};
+use zenoh::net::{data_kind, encoding, CongestionControl, DataInfo, RBuf, ResKey, Session, ZInt};
+use zenoh::net::protocol::core::{Timestamp, TimestampID, NTP64};
 use std::str;
 use crate::gst_coder::GstCoder;
+use crate::history::HistoryCache;
+use crate::tf_coder::{FramePrefix, TfCoder};
+use crate::transcoding::{TranscodingCoder, WireFormat};
+use crate::type_registry::TypeRegistry;
 use async_std::task;
 use std::sync::Arc;
+use std::time::Duration;
 use std::ffi::CString;
 use cyclors::*;
 use yaml_rust::YamlLoader;
@@ -12,79 +18,314 @@ use std::fs::File;
 use std::io::prelude::*;
 
 
+// The statusinfo flags of a DDS sample
+const NN_STATUSINFO_DISPOSE: u32 = 0x1;
+const NN_STATUSINFO_UNREGISTER: u32 = 0x2;
+
+/// The kind of a routed sample: either data or a change of state of its DDS instance.
+/// The latter are routed over zenoh as DELETE, with the statusinfo as first byte of the payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleKind {
+    Put,
+    Dispose,
+    Unregister,
+}
+
+impl Default for SampleKind {
+    fn default() -> Self {
+        SampleKind::Put
+    }
+}
+
+impl SampleKind {
+    fn statusinfo(self) -> u32 {
+        match self {
+            SampleKind::Put => 0,
+            SampleKind::Dispose => NN_STATUSINFO_DISPOSE,
+            SampleKind::Unregister => NN_STATUSINFO_UNREGISTER,
+        }
+    }
+
+    /// Splits the payload of a zenoh DELETE into the kind and the serialized key of the instance.
+    pub fn from_delete_payload(payload: &[u8]) -> (Self, &[u8]) {
+        match payload.split_first() {
+            Some((s, key)) if *s as u32 & NN_STATUSINFO_DISPOSE == 0 => {
+                (SampleKind::Unregister, key)
+            }
+            Some((_, key)) => (SampleKind::Dispose, key),
+            None => (SampleKind::Dispose, payload),
+        }
+    }
+}
+
+/// Metadata of a sample routed by the bridge, in addition to its payload.
+#[derive(Debug, Clone, Default)]
+pub struct SampleMeta {
+    // the zenoh key chunk of the DDS instance of the sample, for the keyed topics
+    pub instance: Option<String>,
+    pub kind: SampleKind,
+    // the DDS source timestamp of the sample
+    pub timestamp: Option<dds_time_t>,
+    // the zenoh encoding of the payload transcoded by a coder, rather than the CDR of DDS
+    pub encoding: Option<ZInt>,
+}
+
+// The source timestamps are carried over zenoh as the timestamps of the samples, which only
+// identify the bridges as a whole
+const TIMESTAMP_ID: [u8; 16] = *b"zenoh-bridge-dds";
+
+/// The zenoh timestamp of a DDS source timestamp.
+pub fn zenoh_timestamp(timestamp: dds_time_t) -> Timestamp {
+    Timestamp::new(
+        NTP64::from(Duration::from_nanos(timestamp.max(0) as u64)),
+        TimestampID::new(TIMESTAMP_ID.len(), TIMESTAMP_ID),
+    )
+}
+
+/// The DDS source timestamp carried by the info of a zenoh sample, if any.
+pub fn source_timestamp(info: &Option<DataInfo>) -> Option<dds_time_t> {
+    info.as_ref()
+        .and_then(|i| i.timestamp.as_ref())
+        .map(|ts| ts.get_time().to_duration().as_nanos() as dds_time_t)
+}
+
+/// The info of a zenoh sample with a source timestamp and a payload encoding, if any.
+pub fn data_info(
+    timestamp: Option<dds_time_t>,
+    payload_encoding: Option<ZInt>,
+) -> Option<DataInfo> {
+    if timestamp.is_none() && payload_encoding.is_none() {
+        return None;
+    }
+    let mut info = DataInfo::new();
+    info.timestamp = timestamp.map(zenoh_timestamp);
+    info.encoding = payload_encoding;
+    Some(info)
+}
+
 pub trait Writer {
     fn write(&self, buf: &[u8]);
+
+    fn write_with(&self, buf: &[u8], _meta: &SampleMeta) {
+        self.write(buf);
+    }
 }
 
 pub struct ZenohWriter {
     key: ResKey,
     session: Arc<Session>,
+    source_timestamps: bool,
+    congestion_control: CongestionControl,
+    history: Option<Arc<HistoryCache>>,
 }
 
 impl ZenohWriter {
     pub fn new(session: Arc<Session>, key: ResKey) -> Self {
         ZenohWriter {
-            key, session
+            key,
+            session,
+            source_timestamps: false,
+            congestion_control: CongestionControl::default(),
+            history: None,
+        }
+    }
+
+    /// Publishes the samples with their DDS source timestamp.
+    pub fn with_source_timestamps(mut self, source_timestamps: bool) -> Self {
+        self.source_timestamps = source_timestamps;
+        self
+    }
+
+    /// The congestion control of the data writes, the DELETE always blocking.
+    pub fn with_congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+        self.congestion_control = congestion_control;
+        self
+    }
+
+    /// Keeps the last samples written in `history`, for the late-joining remote readers.
+    pub fn with_history(mut self, history: Arc<HistoryCache>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    async fn put(&self, key: &ResKey, payload: RBuf, info: Option<DataInfo>) {
+        match info {
+            Some(info) => self
+                .session
+                .write_info(key, payload, info, self.congestion_control)
+                .await
+                .unwrap(),
+            None => self
+                .session
+                .write_ext(
+                    key,
+                    payload,
+                    encoding::APP_OCTET_STREAM,
+                    data_kind::DEFAULT,
+                    self.congestion_control,
+                )
+                .await
+                .unwrap(),
         }
     }
 }
 
 impl Writer for ZenohWriter {
     fn write(&self, buf: &[u8]) {
+        task::block_on(self.put(&self.key, RBuf::from(buf), None));
+    }
+
+    fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+        let key = match &meta.instance {
+            Some(instance) => instance_key(&self.key, instance),
+            None => self.key.clone(),
+        };
         task::block_on(async {
-            self.session.write(&self.key, RBuf::from(buf)).await.unwrap();
+            if meta.kind == SampleKind::Put {
+                let timestamp = meta.timestamp.filter(|_| self.source_timestamps);
+                if let Some(history) = &self.history {
+                    history.insert(&meta.instance, buf.to_vec(), timestamp, meta.encoding);
+                }
+                self.put(&key, RBuf::from(buf), data_info(timestamp, meta.encoding))
+                    .await;
+            } else {
+                if let (SampleKind::Dispose, Some(history)) = (meta.kind, &self.history) {
+                    history.dispose(&meta.instance);
+                }
+                let mut payload = Vec::with_capacity(buf.len() + 1);
+                payload.push(meta.kind.statusinfo() as u8);
+                payload.extend_from_slice(buf);
+                self.session
+                    .write_ext(
+                        &key,
+                        payload.into(),
+                        encoding::APP_OCTET_STREAM,
+                        data_kind::DELETE,
+                        CongestionControl::Block,
+                    )
+                    .await
+                    .unwrap();
+            }
         });
     }
 }
 
+// The key of a DDS instance is the key of its topic with the instance as last chunk
+fn instance_key(key: &ResKey, instance: &str) -> ResKey {
+    match key {
+        ResKey::RName(name) => ResKey::RName(format!("{}/{}", name, instance)),
+        ResKey::RId(id) => ResKey::RIdWithSuffix(*id, format!("/{}", instance)),
+        ResKey::RIdWithSuffix(id, suffix) => {
+            ResKey::RIdWithSuffix(*id, format!("{}/{}", suffix, instance))
+        }
+    }
+}
+
+/// Returns true if `res_name` is either the key `key` of a topic or the key of one of its instances.
+pub fn is_topic_or_instance_key(key: &str, res_name: &str) -> bool {
+    if res_name == key {
+        return true;
+    }
+    match res_name.strip_prefix(key).and_then(|s| s.strip_prefix('/')) {
+        Some(instance) => instance.len() == 32 && instance.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
 pub struct DDSWriter {
-    pub dp: i32,
-    pub ton: String,
-    pub tyn: String,
-    pub keyless: bool,
-    pub wr: i32,
+    wr: dds_entity_t,
+    // the blob sertopic of the writer's topic, created once and used for all its samples
+    st: *mut ddsi_sertopic,
+}
+
+// The sertopic is reference counted by Cyclone and never modified once created
+unsafe impl Send for DDSWriter {}
+
+impl DDSWriter {
+    pub fn new(
+        dp: dds_entity_t,
+        wr: dds_entity_t,
+        topic_name: &str,
+        type_name: &str,
+        keyless: bool,
+    ) -> Self {
+        let cton = CString::new(topic_name).unwrap();
+        let ctyn = CString::new(type_name).unwrap();
+        let st = unsafe {
+            cdds_create_blob_sertopic(
+                dp,
+                cton.as_ptr() as *mut std::os::raw::c_char,
+                ctyn.as_ptr() as *mut std::os::raw::c_char,
+                keyless,
+            )
+        };
+        DDSWriter { wr, st }
+    }
+
+    fn write_serdata(
+        &self,
+        buf: &[u8],
+        kind: ddsi_serdata_kind,
+        statusinfo: u32,
+        timestamp: Option<dds_time_t>,
+    ) {
+        unsafe {
+            // The payload is only borrowed by the sample: Cyclone serializes it during the write,
+            // after which the buffer is released by its owner as before.
+            let fwdp =
+                cdds_ddsi_payload_create(self.st, kind, buf.as_ptr() as *mut u8, buf.len() as u64);
+            if statusinfo == 0 && timestamp.is_none() {
+                dds_writecdr(self.wr, fwdp as *mut ddsi_serdata);
+            } else {
+                // dds_writecdr() would reset the statusinfo and timestamp, hence the sample is forwarded
+                let sd = fwdp as *mut ddsi_serdata;
+                (*sd).statusinfo = statusinfo;
+                (*sd).timestamp.v = match timestamp {
+                    Some(ts) => ts,
+                    None => dds_time(),
+                };
+                dds_forwardcdr(self.wr, sd);
+            }
+        }
+    }
+}
+
+impl Drop for DDSWriter {
+    fn drop(&mut self) {
+        unsafe { cdds_sertopic_unref(self.st) };
+    }
 }
 
 impl Writer for DDSWriter {
     fn write(&self, buf: &[u8]) {
-        unsafe {
-            // As per the Vec documentation (see https://doc.rust-lang.org/std/vec/struct.Vec.html#method.into_raw_parts)
-            // the only way to correctly releasing it is to create a vec using from_raw_parts
-            // and then have its destructor do the cleanup.
-            // Thus, while tempting to just pass the raw pointer to cyclone and then free it from C,
-            // that is not necessarily safe or guaranteed to be leak free.
-            let (ptr, len, capacity) = buf.to_vec().into_raw_parts();
-            let cton = CString::new(self.ton.clone()).unwrap().into_raw();
-            let ctyn = CString::new(self.tyn.clone()).unwrap().into_raw();
-            let st = cdds_create_blob_sertopic(
-                self.dp,
-                cton as *mut std::os::raw::c_char,
-                ctyn as *mut std::os::raw::c_char,
-                self.keyless,
-            );
-            drop(CString::from_raw(cton));
-            drop(CString::from_raw(ctyn));
-            let fwdp = cdds_ddsi_payload_create(
-                st,
-                ddsi_serdata_kind_SDK_DATA,
-                ptr,
-                len as u64,
-            );
-            dds_writecdr(self.wr, fwdp as *mut ddsi_serdata);
-            drop(Vec::from_raw_parts(ptr, len, capacity));
-            cdds_sertopic_unref(st);
+        self.write_serdata(buf, ddsi_serdata_kind_SDK_DATA, 0, None);
+    }
+
+    fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+        match meta.kind {
+            SampleKind::Put => {
+                self.write_serdata(buf, ddsi_serdata_kind_SDK_DATA, 0, meta.timestamp)
+            }
+            kind => self.write_serdata(
+                buf,
+                ddsi_serdata_kind_SDK_KEY,
+                kind.statusinfo(),
+                meta.timestamp,
+            ),
         }
     }
 }
 
 pub struct Coders {
     coders: Vec<yaml_rust::Yaml>,  
+    types: Arc<TypeRegistry>,
 }
 
 impl Coders {
     pub fn new() -> Self {
         Coders {
             coders: vec![],
+            types: Arc::new(TypeRegistry::default()),
         }
     }
 
@@ -96,14 +337,48 @@ impl Coders {
 
         Coders {
             coders: docs[0].as_vec().unwrap().to_vec(),
+            types: Arc::new(TypeRegistry::default()),
         }
     }
 
-    fn create_coder(&self, topic_name: &str, _type_name: &str, writer: Box<dyn Writer + Send>, encoder: bool) -> Box<dyn Coder + Send> {
+    /// Sets the types known to the coders, whose samples they may decode and encode.
+    pub fn with_types(mut self, types: Arc<TypeRegistry>) -> Self {
+        self.types = types;
+        self
+    }
+
+    pub fn types(&self) -> &TypeRegistry {
+        &self.types
+    }
+
+    fn create_coder(&self, topic_name: &str, type_name: &str, writer: Box<dyn Writer + Send>, encoder: bool) -> Box<dyn Coder + Send> {
         for pipe in &self.coders {
             let topics: Vec<&str> = pipe["topics"].as_vec().unwrap().iter().map(|y| y.as_str().unwrap()).collect();
             let matches = topics.contains(&topic_name);
 
+            if matches && pipe["coder"].as_str() == Some("tf_prefix") {
+                match FramePrefix::new(pipe["prefix"].as_str().unwrap_or(""), type_name, &self.types) {
+                    Ok(prefix) => {
+                        log::error!("[coders] Selected {:?} coder for {}", pipe, topic_name);
+                        return Box::new(TfCoder::new(writer, prefix));
+                    }
+                    // routed as is, the frames would collide with the ones of the other robots
+                    Err(e) => {
+                        log::error!("[coders] Invalid tf_prefix coder for {}, its samples are dropped: {}", topic_name, e);
+                        return Box::new(DroppingCoder);
+                    }
+                }
+            }
+
+            if let (true, Some(format)) = (matches, pipe["coder"].as_str().and_then(WireFormat::from_coder)) {
+                if self.types.get(type_name).is_some() {
+                    log::error!("[coders] Selected {:?} coder for {}", pipe, topic_name);
+                    return Box::new(TranscodingCoder::new(writer, type_name, self.types.clone(), format));
+                }
+                log::error!("[coders] Unknown type {} for the {:?} coder of {}", type_name, format, topic_name);
+                break;
+            }
+
             if matches {
                 let pipe_description = match encoder {
                     true => &pipe["encoder"],
@@ -132,6 +407,30 @@ impl Coders {
 pub trait Coder {
     fn encode(&self, data: Vec<u8>);
     fn decode(&self, data: Vec<u8>);
+
+    // Coders that can't keep the metadata of the samples through their processing ignore it,
+    // and only process the data samples
+    fn encode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        if meta.kind == SampleKind::Put {
+            self.encode(data);
+        }
+    }
+
+    fn decode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        if meta.kind == SampleKind::Put {
+            self.decode(data);
+        }
+    }
+}
+
+// The coder of the topics whose configured coder can't be used, for their samples not to be
+// routed unchanged
+struct DroppingCoder;
+
+impl Coder for DroppingCoder {
+    fn encode(&self, _data: Vec<u8>) {}
+
+    fn decode(&self, _data: Vec<u8>) {}
 }
 
 struct IdentityCoder {
@@ -146,4 +445,12 @@ impl Coder for IdentityCoder {
     fn decode(&self, data: Vec<u8>) {
         self.writer.write(&data);
     }
+
+    fn encode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        self.writer.write_with(&data, meta);
+    }
+
+    fn decode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        self.writer.write_with(&data, meta);
+    }
 }
@@ -0,0 +1,162 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::downsampling::RateLimits;
+use crate::namespace::Namespace;
+use crate::partitions::PartitionMapping;
+use crate::policy::Policy;
+use crate::redundancy::RedundancyConfig;
+use crate::ros2_names::demangle_topic;
+use crate::services::ServicesConfig;
+use crate::sim_clock::SimTimeConfig;
+use crate::static_routes::StaticRoutes;
+use crate::tf_aggregation::TfConfig;
+use crate::type_registry::TypeRegistry;
+use crate::zenoh_qos::ZenohQosOverrides;
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::Arc;
+use yaml_rust::YamlLoader;
+
+/// The bridge configuration, loaded from the YAML file given with `--bridge-config`.
+#[derive(Debug, Default)]
+pub struct BridgeConfig {
+    pub policy: Policy,
+    pub partitions: PartitionMapping,
+    // if true, the DDS source timestamps are carried over zenoh
+    pub source_timestamps: bool,
+    pub redundancy: RedundancyConfig,
+    pub rate_limits: RateLimits,
+    pub zenoh_qos: ZenohQosOverrides,
+    pub routes: StaticRoutes,
+    pub services: ServicesConfig,
+    // if true, the zenoh keys and the published type names are in their ROS 2 form
+    pub ros2_names: bool,
+    pub namespace: Namespace,
+    pub tf: TfConfig,
+    pub sim_time: SimTimeConfig,
+    // the types whose samples can be decoded and encoded without their code
+    pub types: Arc<TypeRegistry>,
+}
+
+impl BridgeConfig {
+    pub fn from_config(config_path: &str) -> Result<Self, String> {
+        let mut file = File::open(config_path)
+            .map_err(|e| format!("Unable to open {}: {}", config_path, e))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .map_err(|e| format!("Unable to read {}: {}", config_path, e))?;
+        let docs = YamlLoader::load_from_str(&contents)
+            .map_err(|e| format!("Invalid YAML in {}: {}", config_path, e))?;
+        let doc = match docs.get(0) {
+            Some(doc) => doc,
+            None => return Ok(BridgeConfig::default()),
+        };
+
+        let policy = if doc["policy"].is_badvalue() {
+            Policy::default()
+        } else {
+            Policy::from_yaml(&doc["policy"])?
+        };
+
+        let partitions = if doc["partitions"].is_badvalue() {
+            PartitionMapping::default()
+        } else {
+            PartitionMapping::from_yaml(&doc["partitions"])?
+        };
+
+        let source_timestamps = doc["source_timestamps"].as_bool().unwrap_or(false);
+
+        let ros2_names = doc["ros2_names"].as_bool().unwrap_or(false);
+
+        let redundancy = if doc["redundancy"].is_badvalue() {
+            RedundancyConfig::default()
+        } else {
+            RedundancyConfig::from_yaml(&doc["redundancy"])?
+        };
+
+        let rate_limits = if doc["rate_limits"].is_badvalue() {
+            RateLimits::default()
+        } else {
+            RateLimits::from_yaml(&doc["rate_limits"])?
+        };
+
+        let zenoh_qos = if doc["zenoh_qos"].is_badvalue() {
+            ZenohQosOverrides::default()
+        } else {
+            ZenohQosOverrides::from_yaml(&doc["zenoh_qos"])?
+        };
+
+        let routes = if doc["routes"].is_badvalue() {
+            StaticRoutes::default()
+        } else {
+            StaticRoutes::from_yaml(&doc["routes"])?
+        };
+
+        let services = if doc["services"].is_badvalue() {
+            ServicesConfig::default()
+        } else {
+            ServicesConfig::from_yaml(&doc["services"])?
+        };
+
+        let namespace = if doc["namespace"].is_badvalue() {
+            Namespace::default()
+        } else {
+            Namespace::from_yaml(&doc["namespace"])?
+        };
+
+        let tf = if doc["tf"].is_badvalue() {
+            TfConfig::default()
+        } else {
+            TfConfig::from_yaml(&doc["tf"])?
+        };
+
+        let sim_time = if doc["sim_time"].is_badvalue() {
+            SimTimeConfig::default()
+        } else {
+            SimTimeConfig::from_yaml(&doc["sim_time"])?
+        };
+
+        let types = if doc["types"].is_badvalue() {
+            TypeRegistry::default()
+        } else {
+            TypeRegistry::from_yaml(&doc["types"])?
+        };
+
+        Ok(BridgeConfig {
+            policy,
+            partitions,
+            source_timestamps,
+            redundancy,
+            rate_limits,
+            zenoh_qos,
+            routes,
+            services,
+            ros2_names,
+            namespace,
+            tf,
+            sim_time,
+            types: Arc::new(types),
+        })
+    }
+
+    /// The zenoh key for the topic `topic_name` in `partition`.
+    pub fn key(&self, scope: &str, partition: Option<&str>, topic_name: &str) -> String {
+        if self.ros2_names {
+            self.partitions
+                .key(scope, partition, &demangle_topic(topic_name))
+        } else {
+            self.partitions.key(scope, partition, topic_name)
+        }
+    }
+}
@@ -0,0 +1,653 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::coders::{SampleKind, SampleMeta, Writer};
+use crate::remote_discovery::{expired, ADV_LEASE, ADV_REFRESH};
+use crate::sim_clock::{elapsed, Clock};
+use async_std::stream;
+use async_std::task;
+use cdr::{CdrLe, Infinite};
+use cyclors::dds_duration_t;
+use futures::prelude::*;
+use log::{debug, warn};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use yaml_rust::Yaml;
+use zenoh::net::*;
+
+// The bridges advertise the time_based_filter of the DDS subscriptions they route under
+// "<scope>/@dds_bridge/<bridge_id>/filter/<endpoint_key>", refreshed and expired as the
+// advertisements of the publications (see remote_discovery)
+const ADV_CHUNK: &str = "@dds_bridge";
+
+// How often the samples kept by a downsampler without period are checked
+const IDLE_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct RateLimit {
+    topic: Regex,
+    period: Duration,
+}
+
+/// The maximum rates of the DDS => zenoh routes, by topic name pattern.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    limits: Vec<RateLimit>,
+}
+
+impl RateLimits {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let mut limits = vec![];
+        for l in yaml.as_vec().map(|v| v.as_slice()).unwrap_or_default() {
+            let topic = match l["topic"].as_str() {
+                Some(t) => Regex::new(t)
+                    .map_err(|e| format!("Invalid regular expression '{}': {}", t, e))?,
+                None => return Err(format!("Missing topic in rate limit {:?}", l)),
+            };
+            let max_rate = match &l["max_rate"] {
+                Yaml::Real(_) => l["max_rate"].as_f64(),
+                Yaml::Integer(i) => Some(*i as f64),
+                _ => None,
+            };
+            match max_rate {
+                Some(r) if r > 0.0 => limits.push(RateLimit {
+                    topic,
+                    period: Duration::from_secs_f64(1.0 / r),
+                }),
+                _ => {
+                    return Err(format!(
+                        "Invalid max_rate in rate limit {:?}: a positive number of Hz is expected",
+                        l
+                    ))
+                }
+            }
+        }
+        Ok(RateLimits { limits })
+    }
+
+    /// The minimum period between two samples of topic `topic_name`, if its rate is limited.
+    /// The first matching limit applies.
+    pub fn period(&self, topic_name: &str) -> Option<Duration> {
+        self.limits
+            .iter()
+            .find(|l| l.topic.is_match(topic_name))
+            .map(|l| l.period)
+    }
+}
+
+/// The time_based_filter of a DDS subscription routed by a bridge, advertised to the other
+/// bridges with the zenoh keys it is routed from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    pub keys: Vec<String>,
+    // 0 if the subscription has no filter
+    pub time_based_filter: dds_duration_t,
+}
+
+impl SubscriptionFilter {
+    pub fn encode(&self) -> Vec<u8> {
+        cdr::serialize::<_, _, CdrLe>(self, Infinite).unwrap()
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match cdr::deserialize_from::<_, SubscriptionFilter, _>(buf, Infinite) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                warn!(
+                    "Failed to decode a subscription filter advertisement: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// The zenoh key used by the bridge `bridge_id` to advertise the filter of its DDS subscription `key`.
+pub fn filter_adv_key(scope: &str, bridge_id: &str, key: &str) -> String {
+    format!("{}/{}/{}/filter/{}", scope, ADV_CHUNK, bridge_id, key)
+}
+
+/// The time_based_filter of the DDS subscriptions of the other bridges, by advertisement key,
+/// with the time they were last refreshed.
+#[derive(Debug, Default)]
+pub struct RemoteFilters {
+    filters: Mutex<HashMap<String, (SubscriptionFilter, Instant)>>,
+}
+
+impl RemoteFilters {
+    /// The period to which the samples routed to `key` can be downsampled: the smallest
+    /// time_based_filter of the remote subscriptions routed from it, if they all have one.
+    pub fn period(&self, key: &str) -> Option<Duration> {
+        self.filters
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(f, _)| f)
+            .filter(|f| f.keys.iter().any(|k| k == key))
+            .map(|f| f.time_based_filter)
+            .min()
+            .filter(|f| *f > 0)
+            .map(|f| Duration::from_nanos(f as u64))
+    }
+
+    fn handle_advertisement(&self, sample: Sample) {
+        let is_delete = sample
+            .data_info
+            .as_ref()
+            .and_then(|i| i.kind)
+            .map(|k| k == data_kind::DELETE)
+            .unwrap_or(false);
+        let mut filters = self.filters.lock().unwrap();
+        if is_delete {
+            filters.remove(&sample.res_name);
+        } else if let Some(f) = SubscriptionFilter::decode(&sample.payload.to_vec()) {
+            if filters.get(&sample.res_name).map(|(known, _)| known) != Some(&f) {
+                debug!("Remote subscription filter {}: {:?}", sample.res_name, f);
+            }
+            filters.insert(sample.res_name, (f, Instant::now()));
+        }
+    }
+
+    // Forgets the filters that were not refreshed within `lease`, e.g. as their bridge crashed
+    fn expire(&self, now: Instant, lease: Duration) {
+        let mut filters = self.filters.lock().unwrap();
+        for key in expired(&filters, now, lease) {
+            debug!("Remote subscription filter {} expired", key);
+            filters.remove(&key);
+        }
+    }
+}
+
+enum Event {
+    Advertisement(Sample),
+    Tick,
+}
+
+/// Advertises over zenoh the filters stored in `adverts` (by advertisement key)
+/// and stores the ones advertised by the other bridges of the same scope in `remote`.
+/// The filters of a bridge that stopped refreshing them are forgotten after a lease.
+pub fn run_filters_exchange(
+    z: Arc<Session>,
+    scope: String,
+    bridge_id: String,
+    adverts: Arc<Mutex<HashMap<String, SubscriptionFilter>>>,
+    remote: Arc<RemoteFilters>,
+) {
+    let selector = format!("{}/{}/*/filter/*", scope, ADV_CHUNK);
+    let own_prefix = format!("{}/{}/{}/", scope, ADV_CHUNK, bridge_id);
+
+    // Periodically refresh our advertisements
+    let zr = z.clone();
+    let refreshed = adverts.clone();
+    task::spawn(async move {
+        let mut ticks = stream::interval(ADV_REFRESH);
+        while ticks.next().await.is_some() {
+            let refreshes: Vec<(String, Vec<u8>)> = refreshed
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, f)| (k.clone(), f.encode()))
+                .collect();
+            for (adv_key, payload) in refreshes {
+                if let Err(e) = zr.write(&ResKey::RName(adv_key), payload.into()).await {
+                    warn!("Failed to refresh a DDS subscription filter: {}", e);
+                }
+            }
+        }
+    });
+
+    // Reply to the bridges that start after us with our current advertisements
+    let zq = z.clone();
+    let own_keys = format!("{}filter/*", own_prefix);
+    task::spawn(async move {
+        let mut queryable = zq
+            .declare_queryable(&ResKey::RName(own_keys), queryable::EVAL)
+            .await
+            .unwrap();
+        while let Some(query) = queryable.stream().next().await {
+            let replies: Vec<(String, Vec<u8>)> = adverts
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, f)| (k.clone(), f.encode()))
+                .collect();
+            for (res_name, payload) in replies {
+                query
+                    .reply(Sample {
+                        res_name,
+                        payload: payload.into(),
+                        data_info: None,
+                    })
+                    .await;
+            }
+        }
+    });
+
+    task::spawn(async move {
+        let sub_info = SubInfo {
+            reliability: Reliability::Reliable,
+            mode: SubMode::Push,
+            period: None,
+        };
+        let mut sub = z
+            .declare_subscriber(&ResKey::RName(selector.clone()), &sub_info)
+            .await
+            .unwrap();
+
+        // Fetch the filters advertised before we joined
+        let mut replies = z
+            .query(
+                &ResKey::RName(selector),
+                "",
+                QueryTarget::default(),
+                QueryConsolidation::default(),
+            )
+            .await
+            .unwrap();
+        while let Some(reply) = replies.next().await {
+            if !reply.data.res_name.starts_with(&own_prefix) {
+                remote.handle_advertisement(reply.data);
+            }
+        }
+
+        let ticks = stream::interval(ADV_REFRESH).map(|_| Event::Tick);
+        let mut events = futures::stream::select(sub.stream().map(Event::Advertisement), ticks);
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Advertisement(sample) => {
+                    if !sample.res_name.starts_with(&own_prefix) {
+                        remote.handle_advertisement(sample);
+                    }
+                }
+                Event::Tick => remote.expire(Instant::now(), ADV_LEASE),
+            }
+        }
+    });
+}
+
+/// Advertises the filter of a DDS subscription to the other bridges.
+pub async fn advertise_filter(
+    z: &Session,
+    adverts: &Mutex<HashMap<String, SubscriptionFilter>>,
+    adv_key: String,
+    filter: SubscriptionFilter,
+) {
+    let payload = filter.encode();
+    adverts.lock().unwrap().insert(adv_key.clone(), filter);
+    if let Err(e) = z.write(&ResKey::RName(adv_key), payload.into()).await {
+        warn!("Failed to advertise a DDS subscription filter: {}", e);
+    }
+}
+
+/// Withdraws a previously advertised DDS subscription filter, if any.
+pub async fn withdraw_filter(
+    z: &Session,
+    adverts: &Mutex<HashMap<String, SubscriptionFilter>>,
+    adv_key: String,
+) {
+    if adverts.lock().unwrap().remove(&adv_key).is_some() {
+        if let Err(e) = z
+            .write_ext(
+                &ResKey::RName(adv_key),
+                RBuf::empty(),
+                encoding::APP_OCTET_STREAM,
+                data_kind::DELETE,
+                CongestionControl::Block,
+            )
+            .await
+        {
+            warn!("Failed to withdraw a DDS subscription filter: {}", e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct InstanceState {
+    last_sent: Option<Duration>,
+    // the latest sample received since the last one was sent
+    pending: Option<(Vec<u8>, SampleMeta)>,
+}
+
+struct DownsamplerInner {
+    writer: Box<dyn Writer + Send + Sync>,
+    // the key of the route, to look up the remote filters
+    key: String,
+    max_rate_period: Option<Duration>,
+    remote_filters: Arc<RemoteFilters>,
+    clock: Clock,
+    instances: Mutex<HashMap<Option<String>, InstanceState>>,
+}
+
+impl DownsamplerInner {
+    fn period(&self) -> Option<Duration> {
+        match (self.max_rate_period, self.remote_filters.period(&self.key)) {
+            (Some(p), Some(f)) => Some(p.max(f)),
+            (p, f) => p.or(f),
+        }
+    }
+
+    // Sends the pending samples whose instance didn't send any for a period
+    fn flush(&self, period: Duration) {
+        let now = self.clock.now();
+        let ready: Vec<(Vec<u8>, SampleMeta)> = self
+            .instances
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter(|i| {
+                i.pending.is_some() && i.last_sent.map(|t| elapsed(t, now, period)).unwrap_or(true)
+            })
+            .filter_map(|i| {
+                i.last_sent = Some(now);
+                i.pending.take()
+            })
+            .collect();
+        for (buf, meta) in ready {
+            self.writer.write_with(&buf, &meta);
+        }
+    }
+}
+
+/// A writer forwarding at most one sample per period, per instance for the keyed topics.
+/// The latest sample received during a period is sent at its end. The period is the largest of
+/// the topic's configured rate limit and of the remote subscriptions' time_based_filter,
+/// in the time of `clock`.
+pub struct Downsampler {
+    inner: Arc<DownsamplerInner>,
+}
+
+impl Downsampler {
+    pub fn new(
+        writer: Box<dyn Writer + Send + Sync>,
+        key: String,
+        max_rate_period: Option<Duration>,
+        remote_filters: Arc<RemoteFilters>,
+        clock: Clock,
+    ) -> Self {
+        let inner = Arc::new(DownsamplerInner {
+            writer,
+            key,
+            max_rate_period,
+            remote_filters,
+            clock: clock.clone(),
+            instances: Mutex::new(HashMap::new()),
+        });
+        let weak: Weak<DownsamplerInner> = Arc::downgrade(&inner);
+        task::spawn(async move {
+            loop {
+                let period = match weak.upgrade() {
+                    Some(inner) => inner.period(),
+                    None => break,
+                };
+                // without period, only the samples kept when it was removed are left to send,
+                // thus there's no need to poll the simulation time
+                match period {
+                    Some(period) => clock.sleep(period / 4).await,
+                    None => task::sleep(IDLE_PERIOD).await,
+                }
+                match (weak.upgrade(), period) {
+                    (Some(inner), Some(period)) => inner.flush(period),
+                    (Some(inner), None) => inner.flush(Duration::from_secs(0)),
+                    (None, _) => break,
+                }
+            }
+        });
+        Downsampler { inner }
+    }
+}
+
+impl Writer for Downsampler {
+    fn write(&self, buf: &[u8]) {
+        self.write_with(buf, &SampleMeta::default());
+    }
+
+    fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+        let period = match self.inner.period() {
+            Some(period) => period,
+            None => return self.inner.writer.write_with(buf, meta),
+        };
+        let mut instances = self.inner.instances.lock().unwrap();
+        if meta.kind != SampleKind::Put {
+            // the change of state of the instance supersedes its pending sample
+            instances.remove(&meta.instance);
+            drop(instances);
+            return self.inner.writer.write_with(buf, meta);
+        }
+        let now = self.inner.clock.now();
+        let instance = instances.entry(meta.instance.clone()).or_default();
+        if instance
+            .last_sent
+            .map(|t| elapsed(t, now, period))
+            .unwrap_or(true)
+        {
+            instance.last_sent = Some(now);
+            instance.pending = None;
+            drop(instances);
+            self.inner.writer.write_with(buf, meta);
+        } else {
+            instance.pending = Some((buf.to_vec(), meta.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    // the samples written, with their instance and kind
+    type Written = Arc<Mutex<Vec<(Vec<u8>, Option<String>, SampleKind)>>>;
+
+    struct Samples(Written);
+
+    impl Writer for Samples {
+        fn write(&self, buf: &[u8]) {
+            self.write_with(buf, &SampleMeta::default());
+        }
+
+        fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((buf.to_vec(), meta.instance.clone(), meta.kind));
+        }
+    }
+
+    // A downsampler of the route "/s/rt/imu" without flushing task, flushed by the tests
+    fn downsampler(
+        written: &Written,
+        max_rate_period: Option<Duration>,
+        remote_filters: &Arc<RemoteFilters>,
+        clock: &Clock,
+    ) -> Downsampler {
+        Downsampler {
+            inner: Arc::new(DownsamplerInner {
+                writer: Box::new(Samples(written.clone())),
+                key: "/s/rt/imu".to_string(),
+                max_rate_period,
+                remote_filters: remote_filters.clone(),
+                clock: clock.clone(),
+                instances: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    fn meta(instance: &str, kind: SampleKind) -> SampleMeta {
+        SampleMeta {
+            instance: Some(instance.to_string()),
+            kind,
+            ..Default::default()
+        }
+    }
+
+    fn filter(keys: &[&str], time_based_filter: dds_duration_t) -> SubscriptionFilter {
+        SubscriptionFilter {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            time_based_filter,
+        }
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+- topic: "^rt/imu$"
+  max_rate: 5
+- topic: "^rt/camera/"
+  max_rate: 0.5
+"#,
+        )
+        .unwrap();
+        let limits = RateLimits::from_yaml(&yaml[0]).unwrap();
+        assert_eq!(limits.period("rt/imu"), Some(Duration::from_millis(200)));
+        assert_eq!(
+            limits.period("rt/camera/image_raw"),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(limits.period("rt/chatter"), None);
+
+        let yaml = YamlLoader::load_from_str("- topic: imu\n  max_rate: 0\n").unwrap();
+        assert!(RateLimits::from_yaml(&yaml[0]).is_err());
+        let yaml = YamlLoader::load_from_str("- max_rate: 10\n").unwrap();
+        assert!(RateLimits::from_yaml(&yaml[0]).is_err());
+    }
+
+    #[test]
+    fn test_remote_filters_period() {
+        let remote = RemoteFilters::default();
+        let now = Instant::now();
+        assert_eq!(remote.period("/s/rt/imu"), None);
+        {
+            let mut filters = remote.filters.lock().unwrap();
+            filters.insert("a".into(), (filter(&["/s/rt/imu"], 200_000_000), now));
+            filters.insert(
+                "b".into(),
+                (filter(&["/s/rt/imu", "/s/rt/odom"], 100_000_000), now),
+            );
+        }
+        assert_eq!(remote.period("/s/rt/imu"), Some(Duration::from_millis(100)));
+        assert_eq!(
+            remote.period("/s/rt/odom"),
+            Some(Duration::from_millis(100))
+        );
+        // a subscription without filter needs all the samples
+        remote
+            .filters
+            .lock()
+            .unwrap()
+            .insert("c".into(), (filter(&["/s/rt/imu"], 0), now));
+        assert_eq!(remote.period("/s/rt/imu"), None);
+    }
+
+    #[test]
+    fn test_remote_filters_expiry() {
+        let remote = RemoteFilters::default();
+        let start = Instant::now();
+        remote
+            .filters
+            .lock()
+            .unwrap()
+            .insert("a".into(), (filter(&["/s/rt/imu"], 200_000_000), start));
+        remote.expire(start + Duration::from_secs(14), Duration::from_secs(15));
+        assert_eq!(remote.period("/s/rt/imu"), Some(Duration::from_millis(200)));
+        remote.expire(start + Duration::from_secs(15), Duration::from_secs(15));
+        assert_eq!(remote.period("/s/rt/imu"), None);
+    }
+
+    #[test]
+    fn test_latest_per_interval_and_instance() {
+        let clock = Clock::sim();
+        let written = Written::default();
+        let remote = Arc::new(RemoteFilters::default());
+        let period = Duration::from_secs(1);
+        let ds = downsampler(&written, Some(period), &remote, &clock);
+        ds.write_with(&[1], &meta("a", SampleKind::Put));
+        ds.write_with(&[2], &meta("a", SampleKind::Put));
+        ds.write_with(&[3], &meta("a", SampleKind::Put));
+        // each instance has its own period
+        ds.write_with(&[10], &meta("b", SampleKind::Put));
+        ds.inner.flush(period);
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![
+                (vec![1], Some("a".to_string()), SampleKind::Put),
+                (vec![10], Some("b".to_string()), SampleKind::Put),
+            ]
+        );
+        // the latest sample of the period is sent at its end
+        clock.set(Duration::from_secs(1));
+        ds.inner.flush(period);
+        assert_eq!(
+            written.lock().unwrap().last(),
+            Some(&(vec![3], Some("a".to_string()), SampleKind::Put))
+        );
+        assert_eq!(written.lock().unwrap().len(), 3);
+        // a sample received after a period without samples is sent at once
+        clock.set(Duration::from_secs(3));
+        ds.write_with(&[4], &meta("b", SampleKind::Put));
+        assert_eq!(written.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_dispose_drops_pending() {
+        let clock = Clock::sim();
+        let written = Written::default();
+        let remote = Arc::new(RemoteFilters::default());
+        let period = Duration::from_secs(1);
+        let ds = downsampler(&written, Some(period), &remote, &clock);
+        ds.write_with(&[1], &meta("a", SampleKind::Put));
+        ds.write_with(&[2], &meta("a", SampleKind::Put));
+        ds.write_with(&[], &meta("a", SampleKind::Dispose));
+        clock.set(Duration::from_secs(2));
+        ds.inner.flush(period);
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![
+                (vec![1], Some("a".to_string()), SampleKind::Put),
+                (vec![], Some("a".to_string()), SampleKind::Dispose),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_filter_period() {
+        let clock = Clock::sim();
+        let written = Written::default();
+        let remote = Arc::new(RemoteFilters::default());
+        let ds = downsampler(&written, None, &remote, &clock);
+        // without limit nor remote filter, all the samples are routed
+        ds.write_with(&[1], &meta("a", SampleKind::Put));
+        ds.write_with(&[2], &meta("a", SampleKind::Put));
+        assert_eq!(written.lock().unwrap().len(), 2);
+        // a remote filter received after the route was created applies at once
+        remote.filters.lock().unwrap().insert(
+            "f".into(),
+            (filter(&["/s/rt/imu"], 500_000_000), Instant::now()),
+        );
+        ds.write_with(&[3], &meta("a", SampleKind::Put));
+        ds.write_with(&[4], &meta("a", SampleKind::Put));
+        assert_eq!(written.lock().unwrap().len(), 3);
+        clock.set(Duration::from_millis(500));
+        ds.inner.flush(Duration::from_millis(500));
+        assert_eq!(written.lock().unwrap().len(), 4);
+        // and stops applying once withdrawn
+        remote.filters.lock().unwrap().clear();
+        ds.write_with(&[5], &meta("a", SampleKind::Put));
+        ds.write_with(&[6], &meta("a", SampleKind::Put));
+        assert_eq!(written.lock().unwrap().len(), 6);
+    }
+}
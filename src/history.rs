@@ -0,0 +1,319 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::coders::{data_info, is_topic_or_instance_key};
+use crate::sim_clock::{elapsed, Clock};
+use async_std::task;
+use cyclors::*;
+use futures::prelude::*;
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zenoh::net::*;
+
+/// Returns true if the durability of `qos` is TRANSIENT_LOCAL or above, i.e. if the
+/// late-joining readers expect to receive the samples written before they joined.
+///
+/// # Safety
+/// `qos` must point to a valid `dds_qos_t`.
+pub unsafe fn is_transient_local(qos: *const dds_qos_t) -> bool {
+    let mut durability: dds_durability_kind_t = dds_durability_kind_DDS_DURABILITY_VOLATILE;
+    dds_qget_durability(qos, &mut durability)
+        && durability != dds_durability_kind_DDS_DURABILITY_VOLATILE
+}
+
+/// The number of samples kept per instance for a KEEP_ALL history whose resource limits
+/// don't bound the samples per instance.
+pub const MAX_KEEP_ALL_DEPTH: usize = 1000;
+
+/// The number of samples to keep per instance as per the history of `qos`. For KEEP_ALL, the
+/// `max_samples_per_instance` resource limit, or `MAX_KEEP_ALL_DEPTH` if unlimited.
+///
+/// # Safety
+/// `qos` must point to a valid `dds_qos_t`.
+pub unsafe fn history_depth(qos: *const dds_qos_t) -> usize {
+    let mut kind: dds_history_kind_t = dds_history_kind_DDS_HISTORY_KEEP_LAST;
+    let mut depth: i32 = 1;
+    if dds_qget_history(qos, &mut kind, &mut depth) && kind == dds_history_kind_DDS_HISTORY_KEEP_ALL
+    {
+        let (mut max_samples, mut max_instances, mut max_samples_per_instance) = (-1, -1, -1);
+        if dds_qget_resource_limits(
+            qos,
+            &mut max_samples,
+            &mut max_instances,
+            &mut max_samples_per_instance,
+        ) && max_samples_per_instance > 0
+        {
+            max_samples_per_instance as usize
+        } else {
+            MAX_KEEP_ALL_DEPTH
+        }
+    } else {
+        depth.max(1) as usize
+    }
+}
+
+/// The lifespan of the samples as per `qos`, if not infinite.
+///
+/// # Safety
+/// `qos` must point to a valid `dds_qos_t`.
+pub unsafe fn lifespan(qos: *const dds_qos_t) -> Option<Duration> {
+    let mut duration: dds_duration_t = 0;
+    if dds_qget_lifespan(qos, &mut duration) && duration >= 0 && duration != dds_duration_t::MAX {
+        Some(Duration::from_nanos(duration as u64))
+    } else {
+        None
+    }
+}
+
+/// The last samples published on zenoh by a DDS => zenoh route, per instance,
+/// emulating the TRANSIENT_LOCAL durability for the remote readers.
+#[derive(Debug)]
+pub struct HistoryCache {
+    // the zenoh key of the route
+    key: String,
+    depth: usize,
+    // the samples expire after their lifespan, in the time of the clock
+    lifespan: Option<(Duration, Clock)>,
+    // the samples by instance key chunk, with the time they were cached, the oldest first
+    instances: Mutex<HashMap<Option<String>, VecDeque<(Duration, CachedSample)>>>,
+}
+
+// A sample as published on zenoh, with its source timestamp if carried over zenoh, and the
+// encoding of its payload if transcoded by a coder
+#[derive(Debug, Clone)]
+struct CachedSample {
+    payload: Vec<u8>,
+    timestamp: Option<dds_time_t>,
+    encoding: Option<ZInt>,
+}
+
+impl HistoryCache {
+    pub fn new(key: String, depth: usize) -> Self {
+        HistoryCache {
+            key,
+            depth,
+            lifespan: None,
+            instances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Expires the samples after `lifespan` of `clock`.
+    pub fn with_lifespan(mut self, lifespan: Duration, clock: Clock) -> Self {
+        self.lifespan = Some((lifespan, clock));
+        self
+    }
+
+    fn now(&self) -> Duration {
+        match &self.lifespan {
+            Some((_, clock)) => clock.now(),
+            None => Duration::default(),
+        }
+    }
+
+    pub fn insert(
+        &self,
+        instance: &Option<String>,
+        payload: Vec<u8>,
+        timestamp: Option<dds_time_t>,
+        encoding: Option<ZInt>,
+    ) {
+        let mut instances = self.instances.lock().unwrap();
+        let samples = instances.entry(instance.clone()).or_default();
+        while samples.len() >= self.depth.max(1) {
+            samples.pop_front();
+        }
+        samples.push_back((
+            self.now(),
+            CachedSample {
+                payload,
+                timestamp,
+                encoding,
+            },
+        ));
+    }
+
+    /// Forgets the samples of a disposed instance.
+    pub fn dispose(&self, instance: &Option<String>) {
+        self.instances.lock().unwrap().remove(instance);
+    }
+
+    /// The cached samples not expired, with the zenoh key they were published on.
+    pub fn samples(&self) -> Vec<(String, Vec<u8>)> {
+        self.replies()
+            .into_iter()
+            .map(|s| (s.res_name, s.payload.to_vec()))
+            .collect()
+    }
+
+    /// The cached samples not expired, as replies to the queries on the key of the route.
+    pub fn replies(&self) -> Vec<Sample> {
+        let now = self.now();
+        let mut instances = self.instances.lock().unwrap();
+        if let Some((lifespan, _)) = self.lifespan {
+            for samples in instances.values_mut() {
+                samples.retain(|(t, _)| !elapsed(*t, now, lifespan));
+            }
+        }
+        instances
+            .iter()
+            .flat_map(|(instance, samples)| {
+                let res_name = match instance {
+                    Some(i) => format!("{}/{}", self.key, i),
+                    None => self.key.clone(),
+                };
+                samples.iter().map(move |(_, s)| Sample {
+                    res_name: res_name.clone(),
+                    payload: s.payload.clone().into(),
+                    data_info: data_info(s.timestamp, s.encoding),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Replies with the cached samples to the queries on the key of the route, or on its instances.
+pub fn run_history_queryable(z: Arc<Session>, cache: Arc<HistoryCache>) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        let mut queryable = z
+            .declare_queryable(&ResKey::RName(cache.key.clone()), queryable::STORAGE)
+            .await
+            .unwrap();
+        let mut iqueryable = z
+            .declare_queryable(
+                &ResKey::RName(format!("{}/*", cache.key)),
+                queryable::STORAGE,
+            )
+            .await
+            .unwrap();
+        let mut queries = futures::stream::select(queryable.stream(), iqueryable.stream());
+        while let Some(query) = queries.next().await {
+            for sample in cache.replies() {
+                if utils::resource_name::intersect(&query.res_name, &sample.res_name) {
+                    query.reply(sample).await;
+                }
+            }
+        }
+    })
+}
+
+/// Fetches the samples cached by the remote bridges for the route `key`.
+pub async fn query_history(z: &Session, key: &str, keyless: bool) -> Vec<Sample> {
+    let mut selectors = vec![String::from(key)];
+    if !keyless {
+        selectors.push(format!("{}/*", key));
+    }
+    let mut samples = vec![];
+    for selector in selectors {
+        let mut replies = match z
+            .query(
+                &ResKey::RName(selector),
+                "",
+                QueryTarget {
+                    kind: queryable::STORAGE,
+                    target: Target::All,
+                },
+                QueryConsolidation::none(),
+            )
+            .await
+        {
+            Ok(replies) => replies,
+            Err(e) => {
+                log::warn!("Failed to query the history of {}: {}", key, e);
+                continue;
+            }
+        };
+        while let Some(reply) = replies.next().await {
+            if is_topic_or_instance_key(key, &reply.data.res_name) {
+                samples.push(reply.data);
+            }
+        }
+    }
+    debug!("Fetched {} historical samples for {}", samples.len(), key);
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coders::source_timestamp;
+
+    #[test]
+    fn test_history_depth_per_instance() {
+        let cache = HistoryCache::new(String::from("/demo/rt/tf_static"), 2);
+        let (a, b) = (Some(String::from("aa")), Some(String::from("bb")));
+        cache.insert(&a, vec![1], None, None);
+        cache.insert(&a, vec![2], None, None);
+        cache.insert(&a, vec![3], None, None);
+        cache.insert(&b, vec![4], None, None);
+        let mut samples = cache.samples();
+        samples.sort();
+        assert_eq!(
+            samples,
+            vec![
+                (String::from("/demo/rt/tf_static/aa"), vec![2]),
+                (String::from("/demo/rt/tf_static/aa"), vec![3]),
+                (String::from("/demo/rt/tf_static/bb"), vec![4]),
+            ]
+        );
+        cache.dispose(&a);
+        assert_eq!(cache.samples().len(), 1);
+    }
+
+    #[test]
+    fn test_keep_all_capped() {
+        let cache = HistoryCache::new(String::from("/demo/rt/map"), MAX_KEEP_ALL_DEPTH);
+        for i in 0..MAX_KEEP_ALL_DEPTH + 10 {
+            cache.insert(&None, (i as u32).to_le_bytes().to_vec(), None, None);
+        }
+        let samples = cache.samples();
+        assert_eq!(samples.len(), MAX_KEEP_ALL_DEPTH);
+        assert_eq!(samples[0].1, 10u32.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_lifespan() {
+        let clock = Clock::sim();
+        let cache = HistoryCache::new(String::from("/demo/rt/map"), 10)
+            .with_lifespan(Duration::from_secs(2), clock.clone());
+        cache.insert(&None, vec![1], None, None);
+        clock.set(Duration::from_secs(1));
+        cache.insert(&None, vec![2], None, None);
+        assert_eq!(cache.samples().len(), 2);
+        clock.set(Duration::from_secs(2));
+        assert_eq!(
+            cache.samples(),
+            vec![(String::from("/demo/rt/map"), vec![2])]
+        );
+        clock.set(Duration::from_secs(3));
+        assert!(cache.samples().is_empty());
+    }
+
+    #[test]
+    fn test_replies_info() {
+        let cache = HistoryCache::new(String::from("/demo/rt/map"), 2);
+        cache.insert(&None, vec![1], None, None);
+        cache.insert(
+            &None,
+            vec![2],
+            Some(1_500_000_000),
+            Some(encoding::APP_JSON),
+        );
+        let replies = cache.replies();
+        assert!(replies[0].data_info.is_none());
+        let info = replies[1].data_info.as_ref().unwrap();
+        assert_eq!(info.encoding, Some(encoding::APP_JSON));
+        assert_eq!(source_timestamp(&replies[1].data_info), Some(1_500_000_000));
+    }
+}
@@ -0,0 +1,203 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::type_registry::{BaseType, Field, MessageType, Multiplicity, TypeRegistry, Value};
+use serde_json::{Map, Number, Value as Json};
+use std::convert::TryFrom;
+
+/// The JSON form of a value: the structures are objects and the arrays and sequences are arrays.
+/// The floats without JSON form, NaN and the infinities, are null.
+pub fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Bool(b) => Json::Bool(*b),
+        Value::I8(v) => Json::from(*v),
+        Value::U8(v) => Json::from(*v),
+        Value::I16(v) => Json::from(*v),
+        Value::U16(v) => Json::from(*v),
+        Value::I32(v) => Json::from(*v),
+        Value::U32(v) => Json::from(*v),
+        Value::I64(v) => Json::from(*v),
+        Value::U64(v) => Json::from(*v),
+        // the shortest decimal form of a float32, e.g. 0.1 rather than 0.10000000149011612
+        Value::F32(v) => float_to_json(v.to_string().parse().unwrap_or(f64::NAN)),
+        Value::F64(v) => float_to_json(*v),
+        Value::String(s) => Json::String(s.clone()),
+        Value::Array(elements) => Json::Array(elements.iter().map(to_json).collect()),
+        Value::Struct(fields) => Json::Object(
+            fields
+                .iter()
+                .map(|(name, v)| (name.clone(), to_json(v)))
+                .collect::<Map<String, Json>>(),
+        ),
+    }
+}
+
+fn float_to_json(v: f64) -> Json {
+    Number::from_f64(v).map(Json::Number).unwrap_or(Json::Null)
+}
+
+/// The value of the type `type_name` given in JSON. The missing fields take their default value,
+/// so that e.g. `{"linear": {"x": 0.5}}` is a valid geometry_msgs/msg/Twist.
+pub fn from_json(types: &TypeRegistry, type_name: &str, json: &Json) -> Result<Value, String> {
+    let t = types
+        .get(type_name)
+        .ok_or_else(|| format!("Unknown type {}", type_name))?;
+    struct_from_json(types, t, json)
+}
+
+fn struct_from_json(types: &TypeRegistry, t: &MessageType, json: &Json) -> Result<Value, String> {
+    let object = json
+        .as_object()
+        .ok_or_else(|| format!("Invalid {} for a {}: an object is expected", json, t.name))?;
+    if let Some(name) = object
+        .keys()
+        .find(|name| !t.fields.iter().any(|f| &&f.name == name))
+    {
+        return Err(format!("Unknown field {} of {}", name, t.name));
+    }
+    let mut fields = Vec::with_capacity(t.fields.len());
+    for f in &t.fields {
+        let value = match object.get(&f.name) {
+            Some(json) => field_from_json(types, f, json)
+                .map_err(|e| format!("{} in the field {} of {}", e, f.name, t.name))?,
+            None => types.default_value(&f.base, f.multiplicity)?,
+        };
+        fields.push((f.name.clone(), value));
+    }
+    Ok(Value::Struct(fields))
+}
+
+fn field_from_json(types: &TypeRegistry, f: &Field, json: &Json) -> Result<Value, String> {
+    match (f.multiplicity, json) {
+        (Multiplicity::Single, json) => single_from_json(types, &f.base, json),
+        (_, Json::Array(elements)) => Ok(Value::Array(
+            elements
+                .iter()
+                .map(|e| single_from_json(types, &f.base, e))
+                .collect::<Result<_, _>>()?,
+        )),
+        (_, json) => Err(format!("Invalid {}: an array is expected", json)),
+    }
+}
+
+fn int<T: TryFrom<i64>>(json: &Json) -> Option<T> {
+    json.as_i64().and_then(|i| T::try_from(i).ok())
+}
+
+// The floats without JSON form are given as null
+fn float(json: &Json) -> Option<f64> {
+    match json {
+        Json::Null => Some(f64::NAN),
+        json => json.as_f64(),
+    }
+}
+
+fn single_from_json(types: &TypeRegistry, base: &BaseType, json: &Json) -> Result<Value, String> {
+    let invalid = || format!("Invalid {} for a {:?}", json, base);
+    Ok(match base {
+        BaseType::Bool => Value::Bool(json.as_bool().ok_or_else(invalid)?),
+        BaseType::I8 => Value::I8(int(json).ok_or_else(invalid)?),
+        BaseType::U8 => Value::U8(int(json).ok_or_else(invalid)?),
+        BaseType::I16 => Value::I16(int(json).ok_or_else(invalid)?),
+        BaseType::U16 => Value::U16(int(json).ok_or_else(invalid)?),
+        BaseType::I32 => Value::I32(int(json).ok_or_else(invalid)?),
+        BaseType::U32 => Value::U32(int(json).ok_or_else(invalid)?),
+        BaseType::I64 => Value::I64(json.as_i64().ok_or_else(invalid)?),
+        BaseType::U64 => Value::U64(json.as_u64().ok_or_else(invalid)?),
+        BaseType::F32 => Value::F32(float(json).ok_or_else(invalid)? as f32),
+        BaseType::F64 => Value::F64(float(json).ok_or_else(invalid)?),
+        BaseType::String => Value::String(json.as_str().ok_or_else(invalid)?.to_string()),
+        BaseType::Message(name) => struct_from_json(
+            types,
+            types
+                .get(name)
+                .ok_or_else(|| format!("Unknown type {}", name))?,
+            json,
+        )?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        registry
+            .add_msg(
+                "geometry_msgs",
+                "Vector3",
+                "float64 x\nfloat64 y\nfloat64 z\n",
+            )
+            .unwrap();
+        registry
+            .add_msg(
+                "geometry_msgs",
+                "Twist",
+                "Vector3 linear\nVector3 angular\n",
+            )
+            .unwrap();
+        registry
+            .add_msg(
+                "demo",
+                "Status",
+                "string name\nuint8 level\nint64 id\nuint64 count\nfloat32[2] gains\nbool[] flags\n",
+            )
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_json() {
+        let registry = registry();
+        let twist = from_json(
+            &registry,
+            "geometry_msgs::msg::dds_::Twist_",
+            &serde_json::json!({"linear": {"x": 0.5}, "angular": {"z": -1}}),
+        )
+        .unwrap();
+        assert_eq!(twist.field("linear.x"), Some(&Value::F64(0.5)));
+        assert_eq!(twist.field("linear.y"), Some(&Value::F64(0.0)));
+        assert_eq!(twist.field("angular.z"), Some(&Value::F64(-1.0)));
+        let cdr = registry.encode("geometry_msgs/msg/Twist", &twist).unwrap();
+        assert_eq!(
+            to_json(&registry.decode("geometry_msgs/msg/Twist", &cdr).unwrap()),
+            serde_json::json!({
+                "linear": {"x": 0.5, "y": 0.0, "z": 0.0},
+                "angular": {"x": 0.0, "y": 0.0, "z": -1.0}
+            })
+        );
+
+        let status = serde_json::json!({
+            "name": "motor",
+            "level": 2,
+            "id": -7,
+            "count": u64::MAX,
+            "gains": [0.1, null],
+            "flags": [true, false]
+        });
+        let value = from_json(&registry, "demo/msg/Status", &status).unwrap();
+        assert_eq!(value.field("count"), Some(&Value::U64(u64::MAX)));
+        assert_eq!(to_json(&value)["gains"][0], serde_json::json!(0.1));
+        assert_eq!(to_json(&value), status);
+
+        let invalid = |json: Json| from_json(&registry, "demo/msg/Status", &json).is_err();
+        assert!(invalid(serde_json::json!({"level": 256})));
+        assert!(invalid(serde_json::json!({"level": -1})));
+        assert!(invalid(serde_json::json!({"name": 1})));
+        assert!(invalid(serde_json::json!({"flags": true})));
+        assert!(invalid(serde_json::json!({"unknown": 1})));
+        assert!(invalid(serde_json::json!([])));
+        assert!(from_json(&registry, "demo/msg/Unknown", &serde_json::json!({})).is_err());
+    }
+}
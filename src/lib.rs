@@ -12,21 +12,46 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 
-#![feature(vec_into_raw_parts)]
-
+pub mod actions;
+pub mod bridges;
+pub mod cbor;
 pub mod coders;
+pub mod config;
+pub mod downsampling;
 pub mod gst_coder;
+pub mod history;
+pub mod json;
+pub mod namespace;
+pub mod partitions;
+pub mod policy;
+pub mod protobuf;
+pub mod qos;
+pub mod redundancy;
+pub mod remote_discovery;
+pub mod ros2_names;
+pub mod services;
+pub mod sim_clock;
+pub mod static_routes;
+pub mod tf_aggregation;
+pub mod tf_coder;
+pub mod transcoding;
+pub mod type_registry;
+pub mod types;
+pub mod zenoh_qos;
 
 use cyclors::*;
 use log::debug;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::mem::MaybeUninit;
 use std::os::raw;
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
-use zenoh::net::{ResKey, Session};
+use zenoh::net::ResKey;
 
-use crate::coders::{Coder, Coders, ZenohWriter};
+use crate::bridges::{is_bridge_participant, BridgeWriters};
+use crate::coders::{Coder, Coders, SampleKind, SampleMeta, Writer};
+use crate::partitions::set_partitions;
+use crate::qos::Qos;
 
 
 
@@ -34,12 +59,33 @@ const MAX_SAMPLES: usize = 32;
 
 #[derive(Debug)]
 pub struct QosHolder(pub *mut dds_qos_t);
-// unsafe impl Send for QosHolder {}
+// The QoS is only ever accessed by the thread that receives the MatchedEntity
+unsafe impl Send for QosHolder {}
 // unsafe impl Sync for QosHolder {}
 
-#[derive(Debug)]
+impl Clone for QosHolder {
+    fn clone(&self) -> Self {
+        unsafe {
+            let qos = dds_create_qos();
+            dds_copy_qos(qos, self.0);
+            QosHolder(qos)
+        }
+    }
+}
+
+impl Drop for QosHolder {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { dds_delete_qos(self.0) };
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum MatchedEntity {
     DiscoveredPublication {
+        key: String,
+        participant: String,
         topic_name: String,
         type_name: String,
         keyless: bool,
@@ -47,11 +93,15 @@ pub enum MatchedEntity {
         qos: QosHolder,
     },
     UndiscoveredPublication {
-        topic_name: String,
-        type_name: String,
-        partition: Option<String>,
+        key: String,
+    },
+    // The QoS of a discovered publication changed, its new entities following
+    ChangedPublication {
+        key: String,
     },
     DiscoveredSubscription {
+        key: String,
+        participant: String,
         topic_name: String,
         type_name: String,
         keyless: bool,
@@ -59,9 +109,28 @@ pub enum MatchedEntity {
         qos: QosHolder,
     },
     UndiscoveredSubscription {
+        key: String,
+    },
+    // The QoS of a discovered subscription changed, its new entities following
+    ChangedSubscription {
+        key: String,
+    },
+    // A DDS publication discovered and advertised over zenoh by a remote bridge
+    DiscoveredRemotePublication {
+        key: String,
+        participant: String,
         topic_name: String,
         type_name: String,
+        keyless: bool,
         partition: Option<String>,
+        qos: QosHolder,
+    },
+    UndiscoveredRemotePublication {
+        key: String,
+    },
+    // In redundancy mode, this bridge became the leader or a standby
+    LeadershipChanged {
+        leader: bool,
     },
 }
 
@@ -84,8 +153,20 @@ pub fn print_qos_partitions(qos: *const dds_qos_t) {
     }
 }
 
+fn key_to_string(key: &dds_builtintopic_guid_t) -> String {
+    key.v.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 unsafe extern "C" fn on_data(dr: dds_entity_t, arg: *mut std::os::raw::c_void) {
-    let btx = Box::from_raw(arg as *mut (bool, Sender<MatchedEntity>));
+    // the QoS of the endpoints discovered so far are kept to detect their changes
+    let mut btx = Box::from_raw(
+        arg as *mut (
+            bool,
+            Sender<MatchedEntity>,
+            HashMap<String, Qos>,
+            dds_entity_t,
+        ),
+    );
     let dp = dds_get_participant(dr);
     let mut dpih: dds_instance_handle_t = 0;
     let _ = dds_get_instance_handle(dp, &mut dpih);
@@ -105,8 +186,21 @@ unsafe extern "C" fn on_data(dr: dds_entity_t, arg: *mut std::os::raw::c_void) {
         MAX_SAMPLES as u32,
     );
     for i in 0..n {
-        if si[i as usize].valid_data {
-            let sample = samples[i as usize] as *mut dds_builtintopic_endpoint_t;
+        let sample = samples[i as usize] as *mut dds_builtintopic_endpoint_t;
+        let key = key_to_string(&(*sample).key);
+        if si[i as usize].instance_state != dds_instance_state_DDS_IST_ALIVE {
+            // Only the key is valid for a disposed or unregistered endpoint.
+            btx.2.remove(&key);
+            if btx.0 {
+                (btx.1)
+                    .send(MatchedEntity::UndiscoveredPublication { key })
+                    .unwrap();
+            } else {
+                (btx.1)
+                    .send(MatchedEntity::UndiscoveredSubscription { key })
+                    .unwrap();
+            }
+        } else if si[i as usize].valid_data {
             debug!(
                 "Discovery data from Participant with IH = {:?}",
                 (*sample).participant_instance_handle
@@ -119,99 +213,84 @@ unsafe extern "C" fn on_data(dr: dds_entity_t, arg: *mut std::os::raw::c_void) {
                 // print_qos_partitions((*sample).qos);
                 continue;
             }
+            if is_bridge_participant(btx.3, &(*sample).participant_key) {
+                debug!("Ignoring discovery from another bridge: {}", topic_name);
+                continue;
+            }
             let type_name = CStr::from_ptr((*sample).type_name).to_str().unwrap();
+            let participant = key_to_string(&(*sample).participant_key);
+            let endpoint_qos = Qos::from_qos_native((*sample).qos);
+            match btx.2.get(&key) {
+                Some(known_qos) if *known_qos == endpoint_qos => {
+                    debug!("Discovered endpoint {} is unchanged", key);
+                    continue;
+                }
+                Some(_) => {
+                    // Re-create the routes of the endpoint, including the ones it shares with
+                    // other endpoints, with its new QoS
+                    debug!("QoS of discovered endpoint {} changed", key);
+                    if btx.0 {
+                        (btx.1)
+                            .send(MatchedEntity::ChangedPublication { key: key.clone() })
+                            .unwrap();
+                    } else {
+                        (btx.1)
+                            .send(MatchedEntity::ChangedSubscription { key: key.clone() })
+                            .unwrap();
+                    }
+                }
+                None => (),
+            }
+            btx.2.insert(key.clone(), endpoint_qos);
             let mut n = 0u32;
             let mut ps: *mut *mut ::std::os::raw::c_char = std::ptr::null_mut();
-            let qos = dds_create_qos();
-            dds_copy_qos(qos, (*sample).qos);
-            dds_qset_ignorelocal(qos, dds_ignorelocal_kind_DDS_IGNORELOCAL_PARTICIPANT);
-            dds_qset_history(qos, dds_history_kind_DDS_HISTORY_KEEP_ALL, 0);
             let _ = dds_qget_partition(
                 (*sample).qos,
                 &mut n as *mut u32,
                 &mut ps as *mut *mut *mut ::std::os::raw::c_char,
             );
-            if n > 0 {
-                for k in 0..n {
-                    let p = CStr::from_ptr(*(ps.offset(k as isize))).to_str().unwrap();
-                    if si[i as usize].instance_state == dds_instance_state_DDS_IST_ALIVE {
-                        if btx.0 {
-                            (btx.1)
-                                .send(MatchedEntity::DiscoveredPublication {
-                                    topic_name: String::from(topic_name),
-                                    type_name: String::from(type_name),
-                                    keyless,
-                                    partition: Some(String::from(p)),
-                                    qos: QosHolder(qos),
-                                })
-                                .unwrap();
-                        } else {
-                            (btx.1)
-                                .send(MatchedEntity::DiscoveredSubscription {
-                                    topic_name: String::from(topic_name),
-                                    type_name: String::from(type_name),
-                                    keyless,
-                                    partition: Some(String::from(p)),
-                                    qos: QosHolder(qos),
-                                })
-                                .unwrap();
-                        }
-                    } else if btx.0 {
-                        (btx.1)
-                            .send(MatchedEntity::UndiscoveredPublication {
-                                topic_name: String::from(topic_name),
-                                type_name: String::from(type_name),
-                                partition: Some(String::from(p)),
-                            })
-                            .unwrap();
-                    } else {
-                        (btx.1)
-                            .send(MatchedEntity::UndiscoveredSubscription {
-                                topic_name: String::from(topic_name),
-                                type_name: String::from(type_name),
-                                partition: Some(String::from(p)),
-                            })
-                            .unwrap();
-                    }
+            let partitions: Vec<Option<String>> = if n > 0 {
+                (0..n)
+                    .map(|k| {
+                        let p = CStr::from_ptr(*(ps.offset(k as isize))).to_str().unwrap();
+                        Some(String::from(p))
+                    })
+                    .collect()
+            } else {
+                vec![None]
+            };
+            for partition in partitions {
+                // Each partition is routed separately, with a QoS restricted to it
+                let qos = dds_create_qos();
+                dds_copy_qos(qos, (*sample).qos);
+                if let Some(p) = &partition {
+                    set_partitions(qos, &[p.as_str()]);
                 }
-            } else if si[i as usize].instance_state == dds_instance_state_DDS_IST_ALIVE {
                 if btx.0 {
                     (btx.1)
                         .send(MatchedEntity::DiscoveredPublication {
+                            key: key.clone(),
+                            participant: participant.clone(),
                             topic_name: String::from(topic_name),
                             type_name: String::from(type_name),
                             keyless,
-                            partition: None,
+                            partition,
                             qos: QosHolder(qos),
                         })
                         .unwrap();
                 } else {
                     (btx.1)
                         .send(MatchedEntity::DiscoveredSubscription {
+                            key: key.clone(),
+                            participant: participant.clone(),
                             topic_name: String::from(topic_name),
                             type_name: String::from(type_name),
                             keyless,
-                            partition: None,
+                            partition,
                             qos: QosHolder(qos),
                         })
                         .unwrap();
                 }
-            } else if btx.0 {
-                (btx.1)
-                    .send(MatchedEntity::UndiscoveredPublication {
-                        topic_name: String::from(topic_name),
-                        type_name: String::from(type_name),
-                        partition: None,
-                    })
-                    .unwrap();
-            } else {
-                (btx.1)
-                    .send(MatchedEntity::UndiscoveredSubscription {
-                        topic_name: String::from(topic_name),
-                        type_name: String::from(type_name),
-                        partition: None,
-                    })
-                    .unwrap();
             }
         }
     }
@@ -222,10 +301,21 @@ unsafe extern "C" fn on_data(dr: dds_entity_t, arg: *mut std::os::raw::c_void) {
     );
     Box::into_raw(btx);
 }
-pub fn run_discovery(dp: dds_entity_t, tx: Sender<MatchedEntity>) {
+/// A string identifying the given DDS participant, used as the bridge identifier.
+pub fn participant_id(dp: dds_entity_t) -> String {
+    let mut dpih: dds_instance_handle_t = 0;
     unsafe {
-        let ptx = Box::new((true, tx.clone()));
-        let stx = Box::new((false, tx));
+        let _ = dds_get_instance_handle(dp, &mut dpih);
+    }
+    format!("{:x}", dpih)
+}
+
+/// Runs the discovery of the DDS endpoints, ignoring those of the bridges.
+/// `pr` is the reader of the participants created with `bridges::create_participants_reader`.
+pub fn run_discovery(dp: dds_entity_t, pr: dds_entity_t, tx: Sender<MatchedEntity>) {
+    unsafe {
+        let ptx = Box::new((true, tx.clone(), HashMap::<String, Qos>::new(), pr));
+        let stx = Box::new((false, tx, HashMap::<String, Qos>::new(), pr));
         let sub_listener = dds_create_listener(Box::into_raw(ptx) as *mut std::os::raw::c_void);
         dds_lset_data_available(sub_listener, Some(on_data));
 
@@ -247,21 +337,60 @@ pub fn run_discovery(dp: dds_entity_t, tx: Sender<MatchedEntity>) {
     }
 }
 
+// The zenoh key chunk of a DDS instance: its key hash in hexadecimal
+fn instance_chunk(keyhash: &ddsi_keyhash_t) -> String {
+    keyhash.value.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 unsafe extern "C" fn data_forwarder_listener(dr: dds_entity_t, arg: *mut std::os::raw::c_void) {
-    let pa = arg as *mut (ResKey, Arc<Session>, &Box<dyn Coder>);
+    let pa = arg as *mut (ResKey, &Box<dyn Coder>, bool, dds_entity_t, BridgeWriters);
     let mut zp: *mut cdds_ddsi_payload = std::ptr::null_mut();
     #[allow(clippy::uninit_assumed_init)]
     let mut si: [dds_sample_info_t; 1] = { MaybeUninit::uninit().assume_init() };
     while cdds_take_blob(dr, &mut zp, si.as_mut_ptr()) > 0 {
-        if si[0].valid_data {
-            log::trace!("Route data to zenoh resource with rid={}", &(*pa).0);
-            let bs = Vec::from_raw_parts((*zp).payload, (*zp).size as usize, (*zp).size as usize);
-            (*pa).2.encode(bs);
+        // The data written by another bridge comes from zenoh: routing it back would loop
+        if (*pa)
+            .4
+            .is_bridge_writer((*pa).3, dr, si[0].publication_handle)
+        {
+            cdds_serdata_unref(zp as *mut ddsi_serdata);
+            continue;
+        }
+        // An invalid sample notifies a change of state of its instance, its payload being the key
+        let kind = if si[0].valid_data {
+            Some(SampleKind::Put)
+        } else if si[0].instance_state == dds_instance_state_DDS_IST_NOT_ALIVE_DISPOSED {
+            Some(SampleKind::Dispose)
+        } else if si[0].instance_state == dds_instance_state_DDS_IST_NOT_ALIVE_NO_WRITERS {
+            Some(SampleKind::Unregister)
+        } else {
+            None
+        };
+        if let Some(kind) = kind {
+            log::trace!("Route {:?} to zenoh resource with rid={}", kind, &(*pa).0);
+            let bs = if (*zp).payload.is_null() {
+                Vec::new()
+            } else {
+                Vec::from_raw_parts((*zp).payload, (*zp).size as usize, (*zp).size as usize)
+            };
+            // each instance of a keyed topic is published on its own key
+            let meta = SampleMeta {
+                instance: if (*pa).2 {
+                    None
+                } else {
+                    Some(instance_chunk(&(*zp).keyhash))
+                },
+                kind,
+                timestamp: Some(si[0].source_timestamp),
+                encoding: None,
+            };
+            (*pa).1.encode_with(bs, &meta);
             (*zp).payload = std::ptr::null_mut();
         }
         cdds_serdata_unref(zp as *mut ddsi_serdata);
     }
 }
+#[allow(clippy::too_many_arguments)]
 pub fn create_forwarding_dds_reader(
     dp: dds_entity_t,
     topic_name: String,
@@ -269,17 +398,31 @@ pub fn create_forwarding_dds_reader(
     keyless: bool,
     qos: QosHolder,
     z_key: ResKey,
-    z: Arc<Session>,
+    writer: Box<dyn Writer + Send>,
     coders: &Coders,
+    pr: dds_entity_t,
 ) -> dds_entity_t {
-    let writer = ZenohWriter::new(z.clone(), z_key.clone());
-    let encoder: Box<dyn Coder> = coders.new_encoder(&topic_name, &type_name, Box::new(writer));
-    let cton = CString::new(topic_name).unwrap().into_raw();
-    let ctyn = CString::new(type_name).unwrap().into_raw();
+    let encoder: Box<dyn Coder> = coders.new_encoder(&topic_name, &type_name, writer);
+    let cton = CString::new(topic_name).unwrap();
+    let ctyn = CString::new(type_name).unwrap();
 
     unsafe {
-        let t = cdds_create_blob_topic(dp, cton, ctyn, keyless);
-        let arg = Box::new((z_key, z, Box::into_raw(Box::new(encoder))));
+        dds_qset_ignorelocal(qos.0, dds_ignorelocal_kind_DDS_IGNORELOCAL_PARTICIPANT);
+        dds_qset_history(qos.0, dds_history_kind_DDS_HISTORY_KEEP_ALL, 0);
+        // the topic keeps its own copies of the names
+        let t = cdds_create_blob_topic(
+            dp,
+            cton.as_ptr() as *mut raw::c_char,
+            ctyn.as_ptr() as *mut raw::c_char,
+            keyless,
+        );
+        let arg = Box::new((
+            z_key,
+            Box::into_raw(Box::new(encoder)),
+            keyless,
+            pr,
+            BridgeWriters::default(),
+        ));
         let sub_listener = dds_create_listener(Box::into_raw(arg) as *mut std::os::raw::c_void);
         dds_lset_data_available(sub_listener, Some(data_forwarder_listener));
         dds_create_reader(dp, t, qos.0, sub_listener)
@@ -293,11 +436,18 @@ pub fn create_forwarding_dds_writer(
     keyless: bool,
     qos: QosHolder,
 ) -> dds_entity_t {
-    let cton = CString::new(topic_name).unwrap().into_raw();
-    let ctyn = CString::new(type_name).unwrap().into_raw();
+    let cton = CString::new(topic_name).unwrap();
+    let ctyn = CString::new(type_name).unwrap();
 
     unsafe {
-        let t = cdds_create_blob_topic(dp, cton, ctyn, keyless);
+        dds_qset_ignorelocal(qos.0, dds_ignorelocal_kind_DDS_IGNORELOCAL_PARTICIPANT);
+        // the topic keeps its own copies of the names
+        let t = cdds_create_blob_topic(
+            dp,
+            cton.as_ptr() as *mut raw::c_char,
+            ctyn.as_ptr() as *mut raw::c_char,
+            keyless,
+        );
         dds_create_writer(dp, t, qos.0, std::ptr::null_mut())
     }
 }
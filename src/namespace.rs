@@ -0,0 +1,175 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::actions::{action_service, action_topic};
+use regex::Regex;
+use yaml_rust::Yaml;
+
+// The DDS topic names of the ROS 2 topics, service requests and service replies
+const ROS2_TOPICS: [(&str, &str); 3] = [("rt/", ""), ("rq/", "Request"), ("rr/", "Reply")];
+
+/// The ROS 2 namespace of the robot of this bridge in a fleet: its local topics, services and
+/// actions are known to the fleet under this namespace, e.g. "/cmd_vel" as "/robot7/cmd_vel",
+/// except the ones shared by the whole fleet.
+#[derive(Debug, Default)]
+pub struct Namespace {
+    name: Option<String>,
+    // the ROS 2 names of the shared topics, services and actions
+    shared: Vec<Regex>,
+}
+
+// Splits a DDS topic name into its ROS 2 prefix, name and suffix
+fn split_ros2(topic_name: &str) -> Option<(&str, &str, &str)> {
+    ROS2_TOPICS.iter().find_map(|(prefix, suffix)| {
+        topic_name
+            .strip_prefix(prefix)
+            .and_then(|t| t.strip_suffix(suffix))
+            .map(|name| (*prefix, name, *suffix))
+    })
+}
+
+impl Namespace {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        // a namespace may be given alone
+        let (name, shared) = match yaml {
+            Yaml::String(name) => (name.as_str(), None),
+            _ => match yaml["name"].as_str() {
+                Some(name) => (name, yaml["shared"].as_vec()),
+                None => return Err(format!("Missing name in namespace {:?}", yaml)),
+            },
+        };
+        let name = name.trim_matches('/');
+        if name.is_empty() {
+            return Err(String::from("Invalid empty namespace"));
+        }
+        let mut regexes = vec![];
+        for s in shared.map(|v| v.as_slice()).unwrap_or_default() {
+            match s.as_str() {
+                Some(re) => regexes.push(
+                    Regex::new(re)
+                        .map_err(|e| format!("Invalid regular expression '{}': {}", re, e))?,
+                ),
+                None => return Err(format!("Invalid shared name {:?} in namespace", s)),
+            }
+        }
+        Ok(Namespace {
+            name: Some(name.to_string()),
+            shared: regexes,
+        })
+    }
+
+    // An action is shared as a whole: its services and topics are matched by the action name
+    fn is_shared(&self, topic_name: &str, name: &str) -> bool {
+        let action = match action_topic(topic_name) {
+            Some((action, _)) => Some(action),
+            None => action_service(name).map(|(action, _)| action),
+        };
+        let ros_name = format!("/{}", action.unwrap_or(name));
+        self.shared.iter().any(|re| re.is_match(&ros_name))
+    }
+
+    /// The name in the fleet of the local DDS topic `topic_name`.
+    pub fn to_fleet(&self, topic_name: &str) -> String {
+        match (&self.name, split_ros2(topic_name)) {
+            (Some(ns), Some((prefix, name, suffix))) if !self.is_shared(topic_name, name) => {
+                format!("{}{}/{}{}", prefix, ns, name, suffix)
+            }
+            _ => topic_name.to_string(),
+        }
+    }
+
+    /// The local DDS topic of the topic named `topic_name` in the fleet, if any. The topics of
+    /// the other namespaces, and those of this namespace's robot unknown to the fleet, have none.
+    pub fn to_local(&self, topic_name: &str) -> Option<String> {
+        let local = match (&self.name, split_ros2(topic_name)) {
+            (Some(ns), Some((prefix, name, suffix))) => match name.strip_prefix(ns.as_str()) {
+                Some(n) if n.starts_with('/') => format!("{}{}{}", prefix, &n[1..], suffix),
+                _ => topic_name.to_string(),
+            },
+            _ => topic_name.to_string(),
+        };
+        if self.to_fleet(&local) == topic_name {
+            Some(local)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn namespace(yaml: &str) -> Result<Namespace, String> {
+        Namespace::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    #[test]
+    fn test_remapping() {
+        let ns = namespace("name: /robot7\nshared: [\"^/clock$\", \"^/navigate$\"]\n").unwrap();
+        assert_eq!(ns.to_fleet("rt/cmd_vel"), "rt/robot7/cmd_vel");
+        assert_eq!(
+            ns.to_fleet("rq/add_two_intsRequest"),
+            "rq/robot7/add_two_intsRequest"
+        );
+        assert_eq!(
+            ns.to_fleet("rr/add_two_intsReply"),
+            "rr/robot7/add_two_intsReply"
+        );
+        assert_eq!(
+            ns.to_fleet("rt/fibonacci/_action/status"),
+            "rt/robot7/fibonacci/_action/status"
+        );
+        assert_eq!(ns.to_fleet("rt/clock"), "rt/clock");
+        assert_eq!(
+            ns.to_fleet("rq/navigate/_action/send_goalRequest"),
+            "rq/navigate/_action/send_goalRequest"
+        );
+        assert_eq!(
+            ns.to_fleet("rt/navigate/_action/feedback"),
+            "rt/navigate/_action/feedback"
+        );
+        assert_eq!(ns.to_fleet("Square"), "Square");
+
+        assert_eq!(
+            ns.to_local("rt/robot7/cmd_vel").as_deref(),
+            Some("rt/cmd_vel")
+        );
+        assert_eq!(
+            ns.to_local("rr/robot7/add_two_intsReply").as_deref(),
+            Some("rr/add_two_intsReply")
+        );
+        assert_eq!(ns.to_local("rt/clock").as_deref(), Some("rt/clock"));
+        assert_eq!(ns.to_local("rt/robot8/cmd_vel"), None);
+        assert_eq!(ns.to_local("rt/cmd_vel"), None);
+        assert_eq!(ns.to_local("rt/robot7/clock"), None);
+        assert_eq!(ns.to_local("Square").as_deref(), Some("Square"));
+    }
+
+    #[test]
+    fn test_no_namespace() {
+        let ns = Namespace::default();
+        assert_eq!(ns.to_fleet("rt/cmd_vel"), "rt/cmd_vel");
+        assert_eq!(
+            ns.to_local("rt/robot8/cmd_vel").as_deref(),
+            Some("rt/robot8/cmd_vel")
+        );
+        assert_eq!(
+            namespace("robot7").unwrap().to_fleet("rt/odom"),
+            "rt/robot7/odom"
+        );
+        assert!(namespace("/").is_err());
+        assert!(namespace("shared: []").is_err());
+    }
+}
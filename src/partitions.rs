@@ -0,0 +1,288 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use cyclors::*;
+use std::ffi::CString;
+use std::os::raw;
+use yaml_rust::Yaml;
+
+// The partitions used by the forwarding readers and writers when the partitions are ignored:
+// the wildcard matches any named partition and "" the default one.
+const ALL_PARTITIONS: [&str; 2] = ["", "*"];
+
+/// Matches a DDS partition name against a DDS partition expression, where
+/// '*' matches any sequence of characters and '?' any single character.
+pub fn partition_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // position of the last '*' in the pattern and the matching position in the name
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[derive(Debug)]
+struct MappingRule {
+    partition: String,
+    // the key chunk replacing the partition, none if empty
+    key: String,
+}
+
+/// How the DDS partitions are mapped to zenoh keys.
+/// By default a partition `P` of topic `A` is mapped to `<scope>/P/A`.
+#[derive(Debug, Default)]
+pub struct PartitionMapping {
+    // if true, all the partitions of a topic are mapped to `<scope>/A`
+    pub ignore: bool,
+    rules: Vec<MappingRule>,
+}
+
+impl PartitionMapping {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let ignore = yaml["ignore"].as_bool().unwrap_or(false);
+        let mut rules = vec![];
+        if let Some(map) = yaml["map"].as_vec() {
+            for m in map {
+                match (m["partition"].as_str(), m["key"].as_str()) {
+                    (Some(partition), Some(key)) => rules.push(MappingRule {
+                        partition: String::from(partition),
+                        key: String::from(key.trim_matches('/')),
+                    }),
+                    _ => {
+                        return Err(format!(
+                            "Invalid partition mapping {:?}: 'partition' and 'key' are required",
+                            m
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(PartitionMapping { ignore, rules })
+    }
+
+    /// The zenoh key chunk for a partition, if any. The first mapping rule whose
+    /// partition expression matches applies. Otherwise the partition name is used,
+    /// with its DDS wildcards turned into zenoh ones.
+    pub fn key_chunk(&self, partition: Option<&str>) -> Option<String> {
+        if self.ignore {
+            return None;
+        }
+        let partition = partition?;
+        match self
+            .rules
+            .iter()
+            .find(|r| partition_match(&r.partition, partition))
+        {
+            Some(r) if r.key.is_empty() => None,
+            Some(r) => Some(r.key.clone()),
+            None if partition.is_empty() => None,
+            None => Some(partition.replace('?', "*")),
+        }
+    }
+
+    /// The zenoh key for the topic `topic_name` in `partition`.
+    pub fn key(&self, scope: &str, partition: Option<&str>, topic_name: &str) -> String {
+        match self.key_chunk(partition) {
+            Some(p) => format!("{}/{}/{}", scope, p, topic_name),
+            None => format!("{}/{}", scope, topic_name),
+        }
+    }
+
+    /// The partitions of the forwarding reader or writer of the route serving `partition`,
+    /// if other partitions are mapped to the same key: all of them if the partitions are
+    /// ignored, otherwise the expressions of the rules mapping to its key chunk, with the
+    /// default partition if that chunk is empty. None if the route serves `partition` only.
+    pub fn route_partitions(&self, partition: Option<&str>) -> Option<Vec<String>> {
+        if self.ignore {
+            return Some(ALL_PARTITIONS.iter().map(|p| String::from(*p)).collect());
+        }
+        let chunk = self.key_chunk(partition);
+        let mut partitions: Vec<String> = self
+            .rules
+            .iter()
+            .filter(|r| match &chunk {
+                Some(c) => r.key == *c,
+                None => r.key.is_empty(),
+            })
+            .map(|r| r.partition.clone())
+            .collect();
+        if partitions.is_empty() {
+            return None;
+        }
+        if chunk.is_none() {
+            partitions.push(String::new());
+        }
+        if let Some(p) = partition {
+            if !partitions.iter().any(|e| partition_match(e, p)) {
+                partitions.push(String::from(p));
+            }
+        }
+        Some(partitions)
+    }
+
+    /// Adjusts the QoS of the forwarding reader or writer of the route serving `partition` to
+    /// the mapping: as a single route serves all the partitions mapped to its key, it is set
+    /// to match all of them.
+    pub fn adjust_qos(&self, qos: *mut dds_qos_t, partition: Option<&str>) {
+        if let Some(partitions) = self.route_partitions(partition) {
+            let ps: Vec<&str> = partitions.iter().map(|p| p.as_str()).collect();
+            set_partitions(qos, &ps);
+        }
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn set_partitions(qos: *mut dds_qos_t, partitions: &[&str]) {
+    let cps: Vec<CString> = partitions
+        .iter()
+        .map(|p| CString::new(*p).unwrap())
+        .collect();
+    let mut ps: Vec<*const raw::c_char> = cps.iter().map(|p| p.as_ptr()).collect();
+    unsafe {
+        dds_qset_partition(qos, ps.len() as u32, ps.as_mut_ptr());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn mapping(yaml: &str) -> PartitionMapping {
+        PartitionMapping::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap()
+    }
+
+    #[test]
+    fn test_partition_match() {
+        assert!(partition_match("robot1", "robot1"));
+        assert!(!partition_match("robot1", "robot12"));
+        assert!(partition_match("robot*", "robot12"));
+        assert!(partition_match("robot*", "robot"));
+        assert!(partition_match("*", ""));
+        assert!(partition_match("", ""));
+        assert!(!partition_match("", "robot1"));
+        assert!(partition_match("robot?", "robot1"));
+        assert!(!partition_match("robot?", "robot"));
+        assert!(!partition_match("robot?", "robot12"));
+        assert!(partition_match("*/sensors/*", "fleet/robot1/sensors/imu"));
+        assert!(!partition_match("*/sensors/*", "fleet/robot1/actuators"));
+        assert!(partition_match("a*b?c", "axxbybzc"));
+        assert!(partition_match("**a", "bba"));
+    }
+
+    #[test]
+    fn test_keys() {
+        let m = PartitionMapping::default();
+        assert_eq!(m.key("/s", None, "rt/chatter"), "/s/rt/chatter");
+        assert_eq!(m.key("/s", Some(""), "rt/chatter"), "/s/rt/chatter");
+        assert_eq!(m.key("/s", Some("P"), "rt/chatter"), "/s/P/rt/chatter");
+        // the DDS wildcards become zenoh ones
+        assert_eq!(
+            m.key("/s", Some("robot?"), "rt/chatter"),
+            "/s/robot*/rt/chatter"
+        );
+
+        let m = mapping(
+            r#"
+map:
+  - partition: "robot?"
+    key: "/fleet/robots/"
+  - partition: "debug*"
+    key: ""
+"#,
+        );
+        assert_eq!(
+            m.key("/s", Some("robot1"), "rt/chatter"),
+            "/s/fleet/robots/rt/chatter"
+        );
+        assert_eq!(m.key("/s", Some("debug_1"), "rt/chatter"), "/s/rt/chatter");
+        assert_eq!(m.key_chunk(Some("other")), Some(String::from("other")));
+        assert_eq!(m.key_chunk(None), None);
+
+        let yaml = YamlLoader::load_from_str("map:\n  - partition: P\n").unwrap();
+        assert!(PartitionMapping::from_yaml(&yaml[0]).is_err());
+    }
+
+    #[test]
+    fn test_ignore() {
+        let m = mapping(
+            r#"
+ignore: true
+map:
+  - partition: "robot?"
+    key: "fleet"
+"#,
+        );
+        assert!(m.ignore);
+        assert_eq!(m.key_chunk(Some("robot1")), None);
+        assert_eq!(m.key("/s", Some("P"), "rt/chatter"), "/s/rt/chatter");
+        assert_eq!(m.key("/s", None, "rt/chatter"), "/s/rt/chatter");
+        assert_eq!(
+            m.route_partitions(Some("P")),
+            Some(vec![String::from(""), String::from("*")])
+        );
+    }
+
+    #[test]
+    fn test_route_partitions() {
+        let m = mapping(
+            r#"
+map:
+  - partition: "robot_*"
+    key: "robots"
+  - partition: "fleet"
+    key: "robots"
+  - partition: "debug*"
+    key: ""
+"#,
+        );
+        // the partitions mapped to one key share a route matching all of them
+        for p in &["robot_1", "robot_2", "fleet"] {
+            assert_eq!(m.key("/s", Some(p), "rt/chatter"), "/s/robots/rt/chatter");
+            assert_eq!(
+                m.route_partitions(Some(p)),
+                Some(vec![String::from("robot_*"), String::from("fleet")])
+            );
+        }
+        // as the default partition and the ones mapped to an empty key
+        for p in &[None, Some(""), Some("debug_1")] {
+            assert_eq!(m.key("/s", *p, "rt/chatter"), "/s/rt/chatter");
+            assert_eq!(
+                m.route_partitions(*p),
+                Some(vec![String::from("debug*"), String::from("")])
+            );
+        }
+        // the other partitions have their own routes
+        assert_eq!(m.route_partitions(Some("other")), None);
+        assert_eq!(
+            PartitionMapping::default().route_partitions(Some("P")),
+            None
+        );
+        assert_eq!(PartitionMapping::default().route_partitions(None), None);
+    }
+}
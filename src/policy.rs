@@ -0,0 +1,345 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use regex::Regex;
+use yaml_rust::Yaml;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    DdsToZenoh,
+    ZenohToDds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// The description of a discovered endpoint the policy is evaluated against.
+#[derive(Debug)]
+pub struct Endpoint<'a> {
+    pub direction: Direction,
+    pub topic_name: &'a str,
+    pub type_name: &'a str,
+    pub partition: Option<&'a str>,
+    pub participant: &'a str,
+    // the zenoh key the endpoint is routed to
+    pub key: &'a str,
+}
+
+/// A rule applies its action to the endpoints matching all of its criteria.
+/// A missing criterion matches any value, a missing partition is matched as "".
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub direction: Option<Direction>,
+    pub topic: Option<Regex>,
+    pub type_name: Option<Regex>,
+    pub partition: Option<Regex>,
+    pub participant: Option<Regex>,
+    pub key: Option<Regex>,
+}
+
+// The keys of a rule
+const RULE_KEYS: [&str; 7] = [
+    "action",
+    "direction",
+    "topic",
+    "type",
+    "partition",
+    "participant",
+    "key",
+];
+
+fn is_match(re: &Option<Regex>, value: &str) -> bool {
+    re.as_ref().map(|re| re.is_match(value)).unwrap_or(true)
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Rule {
+            action,
+            direction: None,
+            topic: None,
+            type_name: None,
+            partition: None,
+            participant: None,
+            key: None,
+        }
+    }
+
+    pub fn matches(&self, ep: &Endpoint) -> bool {
+        self.direction.map(|d| d == ep.direction).unwrap_or(true)
+            && is_match(&self.topic, ep.topic_name)
+            && is_match(&self.type_name, ep.type_name)
+            && is_match(&self.partition, ep.partition.unwrap_or(""))
+            && is_match(&self.participant, ep.participant)
+            && is_match(&self.key, ep.key)
+    }
+
+    fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        // a mistyped criterion would make the rule match any endpoint
+        match yaml.as_hash() {
+            Some(rule) => {
+                for (k, _) in rule {
+                    match k.as_str() {
+                        Some(k) if RULE_KEYS.contains(&k) => (),
+                        _ => return Err(format!("Invalid key {:?} in rule {:?}", k, yaml)),
+                    }
+                }
+            }
+            None => return Err(format!("Invalid rule {:?}", yaml)),
+        }
+        let action = match yaml["action"].as_str() {
+            Some(a) => parse_action(a)?,
+            None => return Err(format!("Missing action in rule {:?}", yaml)),
+        };
+        let mut rule = Rule::new(action);
+        rule.direction = match yaml["direction"].as_str() {
+            Some("dds_to_zenoh") => Some(Direction::DdsToZenoh),
+            Some("zenoh_to_dds") => Some(Direction::ZenohToDds),
+            Some(d) => return Err(format!("Invalid direction '{}' in rule", d)),
+            None => None,
+        };
+        rule.topic = parse_regex(&yaml["topic"])?;
+        rule.type_name = parse_regex(&yaml["type"])?;
+        rule.partition = parse_regex(&yaml["partition"])?;
+        rule.participant = parse_regex(&yaml["participant"])?;
+        rule.key = parse_regex(&yaml["key"])?;
+        Ok(rule)
+    }
+}
+
+fn parse_action(action: &str) -> Result<Action, String> {
+    match action {
+        "allow" => Ok(Action::Allow),
+        "deny" => Ok(Action::Deny),
+        a => Err(format!(
+            "Invalid action '{}', expected 'allow' or 'deny'",
+            a
+        )),
+    }
+}
+
+fn parse_regex(yaml: &Yaml) -> Result<Option<Regex>, String> {
+    match yaml {
+        Yaml::String(s) => Regex::new(s)
+            .map(Some)
+            .map_err(|e| format!("Invalid regular expression '{}': {}", s, e)),
+        Yaml::BadValue => Ok(None),
+        v => Err(format!(
+            "Invalid criterion {:?}: a regular expression is expected",
+            v
+        )),
+    }
+}
+
+/// An ordered list of allow/deny rules. The first rule matching an endpoint decides
+/// if it is bridged; if none matches the default action applies.
+#[derive(Debug)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+    pub default: Action,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            rules: vec![],
+            default: Action::Allow,
+        }
+    }
+}
+
+impl Policy {
+    /// The policy equivalent to the `--allow` option: only the keys matching `re` are bridged.
+    pub fn from_allow_regex(re: Regex) -> Self {
+        let mut rule = Rule::new(Action::Allow);
+        rule.key = Some(re);
+        Policy {
+            rules: vec![rule],
+            default: Action::Deny,
+        }
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let default = match yaml["default"].as_str() {
+            Some(a) => parse_action(a)?,
+            None => Action::Allow,
+        };
+        let rules = match &yaml["rules"] {
+            Yaml::Array(rules) => rules
+                .iter()
+                .map(Rule::from_yaml)
+                .collect::<Result<Vec<Rule>, String>>()?,
+            Yaml::BadValue => vec![],
+            r => return Err(format!("Invalid policy rules {:?}: a list is expected", r)),
+        };
+        Ok(Policy { rules, default })
+    }
+
+    pub fn evaluate(&self, ep: &Endpoint) -> Action {
+        self.rules
+            .iter()
+            .find(|r| r.matches(ep))
+            .map(|r| r.action)
+            .unwrap_or(self.default)
+    }
+
+    pub fn is_allowed(&self, ep: &Endpoint) -> bool {
+        self.evaluate(ep) == Action::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn endpoint<'a>(direction: Direction, topic_name: &'a str, key: &'a str) -> Endpoint<'a> {
+        Endpoint {
+            direction,
+            topic_name,
+            type_name: "std_msgs::msg::dds_::String_",
+            partition: None,
+            participant: "0110aabb",
+            key,
+        }
+    }
+
+    fn policy(yaml: &str) -> Policy {
+        Policy::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap()
+    }
+
+    #[test]
+    fn test_empty_policy_allows_everything() {
+        let p = Policy::default();
+        assert!(p.is_allowed(&endpoint(
+            Direction::DdsToZenoh,
+            "rt/chatter",
+            "/rt/chatter"
+        )));
+        assert!(p.is_allowed(&endpoint(
+            Direction::ZenohToDds,
+            "rt/chatter",
+            "/rt/chatter"
+        )));
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let p = policy(
+            r#"
+rules:
+  - action: deny
+    topic: "^rt/parameter_events$"
+  - action: allow
+    topic: "^rt/"
+  - action: allow
+    topic: "^rt/parameter_events$"
+default: deny
+"#,
+        );
+        let denied = endpoint(Direction::DdsToZenoh, "rt/parameter_events", "");
+        assert_eq!(p.evaluate(&denied), Action::Deny);
+        let allowed = endpoint(Direction::DdsToZenoh, "rt/chatter", "");
+        assert_eq!(p.evaluate(&allowed), Action::Allow);
+    }
+
+    #[test]
+    fn test_default_applies_when_no_rule_matches() {
+        let p = policy(
+            r#"
+rules:
+  - action: allow
+    topic: "^rt/"
+default: deny
+"#,
+        );
+        assert!(!p.is_allowed(&endpoint(Direction::DdsToZenoh, "rq/get_parameters", "")));
+
+        let p = policy(
+            r#"
+rules:
+  - action: deny
+    topic: "^rq/"
+"#,
+        );
+        assert!(p.is_allowed(&endpoint(Direction::DdsToZenoh, "rt/chatter", "")));
+    }
+
+    #[test]
+    fn test_rule_criteria() {
+        let p = policy(
+            r#"
+rules:
+  - action: deny
+    direction: zenoh_to_dds
+    topic: "^rt/cmd_vel$"
+  - action: deny
+    partition: "^private$"
+  - action: deny
+    type: "Image"
+  - action: deny
+    participant: "^dead"
+"#,
+        );
+        assert!(p.is_allowed(&endpoint(Direction::DdsToZenoh, "rt/cmd_vel", "")));
+        assert!(!p.is_allowed(&endpoint(Direction::ZenohToDds, "rt/cmd_vel", "")));
+
+        let mut ep = endpoint(Direction::DdsToZenoh, "rt/chatter", "");
+        assert!(p.is_allowed(&ep));
+        ep.partition = Some("private");
+        assert!(!p.is_allowed(&ep));
+        ep.partition = Some("public");
+        assert!(p.is_allowed(&ep));
+        ep.type_name = "sensor_msgs::msg::dds_::Image_";
+        assert!(!p.is_allowed(&ep));
+        ep.type_name = "std_msgs::msg::dds_::String_";
+        ep.participant = "deadbeef";
+        assert!(!p.is_allowed(&ep));
+    }
+
+    #[test]
+    fn test_allow_regex() {
+        let p = Policy::from_allow_regex(Regex::new("^/demo/rt/").unwrap());
+        assert!(p.is_allowed(&endpoint(
+            Direction::DdsToZenoh,
+            "rt/chatter",
+            "/demo/rt/chatter"
+        )));
+        assert!(!p.is_allowed(&endpoint(Direction::ZenohToDds, "rq/srv", "/demo/rq/srv")));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let yaml = YamlLoader::load_from_str("rules:\n  - action: drop\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        let yaml =
+            YamlLoader::load_from_str("rules:\n  - action: deny\n    topic: \"(\"\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        let yaml = YamlLoader::load_from_str("rules:\n  - topic: \"rt/\"\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        // a mistyped criterion is rejected rather than matching everything
+        let yaml =
+            YamlLoader::load_from_str("rules:\n  - action: deny\n    topc: \"rt/\"\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        let yaml = YamlLoader::load_from_str("rules:\n  - action: deny\n    topic: 5\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        let yaml = YamlLoader::load_from_str("rules:\n  action: deny\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+        let yaml = YamlLoader::load_from_str("rules:\n  - deny\n").unwrap();
+        assert!(Policy::from_yaml(&yaml[0]).is_err());
+    }
+}
@@ -0,0 +1,571 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::type_registry::{BaseType, MessageType, Multiplicity, TypeRegistry, Value};
+use std::convert::TryFrom;
+
+// The wire types of Protobuf
+const VARINT: u64 = 0;
+const I64: u64 = 1;
+const LEN: u64 = 2;
+const I32: u64 = 5;
+
+// The max nesting of the messages
+const MAX_DEPTH: usize = 64;
+
+/// The name of the Protobuf message of a type, e.g. "geometry_msgs__msg__Twist".
+pub fn message_name(type_name: &str) -> String {
+    type_name.replace('/', "__")
+}
+
+// The Protobuf type of a field of the type `base`: the signed integers are zigzag encoded
+fn proto_type(base: &BaseType) -> String {
+    match base {
+        BaseType::Bool => String::from("bool"),
+        BaseType::I8 | BaseType::I16 | BaseType::I32 => String::from("sint32"),
+        BaseType::U8 | BaseType::U16 | BaseType::U32 => String::from("uint32"),
+        BaseType::I64 => String::from("sint64"),
+        BaseType::U64 => String::from("uint64"),
+        BaseType::F32 => String::from("float"),
+        BaseType::F64 => String::from("double"),
+        BaseType::String => String::from("string"),
+        BaseType::Message(name) => message_name(name),
+    }
+}
+
+fn wire_type(base: &BaseType) -> u64 {
+    match base {
+        BaseType::F32 => I32,
+        BaseType::F64 => I64,
+        BaseType::String | BaseType::Message(_) => LEN,
+        _ => VARINT,
+    }
+}
+
+// The arrays of bytes are Protobuf bytes rather than repeated integers
+fn is_bytes(base: &BaseType, multiplicity: Multiplicity) -> bool {
+    *base == BaseType::U8 && multiplicity != Multiplicity::Single
+}
+
+/// The proto3 schema of the type `type_name` and of the types of its fields, its message being
+/// the first one. The field numbers follow the order of the fields in the type definition.
+pub fn proto_schema(types: &TypeRegistry, type_name: &str) -> Result<String, String> {
+    let t = types
+        .get(type_name)
+        .ok_or_else(|| format!("Unknown type {}", type_name))?;
+    let mut messages: Vec<&MessageType> = vec![t];
+    let mut i = 0;
+    while i < messages.len() {
+        for f in &messages[i].fields {
+            if let BaseType::Message(name) = &f.base {
+                if !messages.iter().any(|m| &m.name == name) {
+                    messages.push(
+                        types
+                            .get(name)
+                            .ok_or_else(|| format!("Unknown type {}", name))?,
+                    );
+                }
+            }
+        }
+        i += 1;
+    }
+    let mut schema = format!(
+        "// Generated from the ROS 2 type {}\nsyntax = \"proto3\";\n",
+        t.name
+    );
+    for m in messages {
+        schema.push_str(&format!("\nmessage {} {{\n", message_name(&m.name)));
+        for (i, f) in m.fields.iter().enumerate() {
+            let field_type = if is_bytes(&f.base, f.multiplicity) {
+                String::from("bytes")
+            } else if f.multiplicity != Multiplicity::Single {
+                format!("repeated {}", proto_type(&f.base))
+            } else {
+                proto_type(&f.base)
+            };
+            schema.push_str(&format!("  {} {} = {};\n", field_type, f.name, i + 1));
+        }
+        schema.push_str("}\n");
+    }
+    Ok(schema)
+}
+
+fn varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn key(buf: &mut Vec<u8>, number: usize, wire_type: u64) {
+    varint(buf, (number as u64) << 3 | wire_type);
+}
+
+fn length_delimited(buf: &mut Vec<u8>, number: usize, bytes: &[u8]) {
+    key(buf, number, LEN);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+// The scalars with their default value are omitted, as in proto3: -0.0 is kept
+fn is_default(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => !b,
+        Value::I8(v) => *v == 0,
+        Value::U8(v) => *v == 0,
+        Value::I16(v) => *v == 0,
+        Value::U16(v) => *v == 0,
+        Value::I32(v) => *v == 0,
+        Value::U32(v) => *v == 0,
+        Value::I64(v) => *v == 0,
+        Value::U64(v) => *v == 0,
+        Value::F32(v) => v.to_bits() == 0,
+        Value::F64(v) => v.to_bits() == 0,
+        Value::String(s) => s.is_empty(),
+        Value::Array(_) | Value::Struct(_) => false,
+    }
+}
+
+// Writes a scalar without its key
+fn scalar(buf: &mut Vec<u8>, base: &BaseType, value: &Value) -> Result<(), String> {
+    match (base, value) {
+        (BaseType::Bool, Value::Bool(v)) => varint(buf, *v as u64),
+        (BaseType::I8, Value::I8(v)) => varint(buf, zigzag(*v as i64)),
+        (BaseType::U8, Value::U8(v)) => varint(buf, *v as u64),
+        (BaseType::I16, Value::I16(v)) => varint(buf, zigzag(*v as i64)),
+        (BaseType::U16, Value::U16(v)) => varint(buf, *v as u64),
+        (BaseType::I32, Value::I32(v)) => varint(buf, zigzag(*v as i64)),
+        (BaseType::U32, Value::U32(v)) => varint(buf, *v as u64),
+        (BaseType::I64, Value::I64(v)) => varint(buf, zigzag(*v)),
+        (BaseType::U64, Value::U64(v)) => varint(buf, *v),
+        (BaseType::F32, Value::F32(v)) => buf.extend_from_slice(&v.to_bits().to_le_bytes()),
+        (BaseType::F64, Value::F64(v)) => buf.extend_from_slice(&v.to_bits().to_le_bytes()),
+        (base, v) => return Err(format!("Invalid value {:?} for a {:?}", v, base)),
+    }
+    Ok(())
+}
+
+/// The Protobuf form of a value of the type `type_name`, as described by its `proto_schema`.
+pub fn to_protobuf(
+    types: &TypeRegistry,
+    type_name: &str,
+    value: &Value,
+) -> Result<Vec<u8>, String> {
+    let t = types
+        .get(type_name)
+        .ok_or_else(|| format!("Unknown type {}", type_name))?;
+    let mut buf = vec![];
+    write_message(types, &mut buf, t, value, 0)?;
+    Ok(buf)
+}
+
+fn write_message(
+    types: &TypeRegistry,
+    buf: &mut Vec<u8>,
+    t: &MessageType,
+    value: &Value,
+    depth: usize,
+) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("Too deeply nested type {}", t.name));
+    }
+    let values = match value {
+        Value::Struct(values) => values,
+        v => return Err(format!("Invalid value {:?} for a {}", v, t.name)),
+    };
+    for (i, f) in t.fields.iter().enumerate() {
+        let number = i + 1;
+        let value = values
+            .iter()
+            .find(|(n, _)| n == &f.name)
+            .map(|(_, v)| v)
+            .ok_or_else(|| format!("Missing field {} of {}", f.name, t.name))?;
+        let elements = match (f.multiplicity, value) {
+            (Multiplicity::Single, v) => {
+                write_field(types, buf, number, &f.base, v, true, depth)?;
+                continue;
+            }
+            (_, Value::Array(elements)) => elements,
+            (_, v) => return Err(format!("Invalid value {:?} for the field {}", v, f.name)),
+        };
+        if elements.is_empty() {
+            continue;
+        }
+        if is_bytes(&f.base, f.multiplicity) {
+            let bytes = elements
+                .iter()
+                .map(|e| match e {
+                    Value::U8(b) => Ok(*b),
+                    e => Err(format!("Invalid value {:?} for a byte", e)),
+                })
+                .collect::<Result<Vec<u8>, _>>()?;
+            length_delimited(buf, number, &bytes);
+        } else if wire_type(&f.base) != LEN {
+            // the repeated scalars are packed
+            let mut packed = vec![];
+            for e in elements {
+                scalar(&mut packed, &f.base, e)?;
+            }
+            length_delimited(buf, number, &packed);
+        } else {
+            for e in elements {
+                write_field(types, buf, number, &f.base, e, false, depth)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_field(
+    types: &TypeRegistry,
+    buf: &mut Vec<u8>,
+    number: usize,
+    base: &BaseType,
+    value: &Value,
+    omit_default: bool,
+    depth: usize,
+) -> Result<(), String> {
+    match (base, value) {
+        (BaseType::Message(name), v) => {
+            let t = types
+                .get(name)
+                .ok_or_else(|| format!("Unknown type {}", name))?;
+            let mut message = vec![];
+            write_message(types, &mut message, t, v, depth + 1)?;
+            length_delimited(buf, number, &message);
+        }
+        (_, v) if omit_default && is_default(v) => (),
+        (BaseType::String, Value::String(s)) => length_delimited(buf, number, s.as_bytes()),
+        (base, v) => {
+            key(buf, number, wire_type(base));
+            scalar(buf, base, v)?;
+        }
+    }
+    Ok(())
+}
+
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, n: u64) -> Result<&'a [u8], String> {
+        let end = usize::try_from(n)
+            .ok()
+            .and_then(|n| n.checked_add(self.pos))
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| String::from("Truncated Protobuf data"))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.bytes(1)?[0];
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(String::from("Invalid varint in Protobuf data"))
+    }
+
+    fn length_delimited(&mut self) -> Result<&'a [u8], String> {
+        let n = self.varint()?;
+        self.bytes(n)
+    }
+
+    fn skip(&mut self, wire_type: u64) -> Result<(), String> {
+        match wire_type {
+            VARINT => self.varint().map(|_| ()),
+            I64 => self.bytes(8).map(|_| ()),
+            LEN => self.length_delimited().map(|_| ()),
+            I32 => self.bytes(4).map(|_| ()),
+            w => Err(format!("Unsupported Protobuf wire type {}", w)),
+        }
+    }
+
+    fn scalar(&mut self, base: &BaseType) -> Result<Value, String> {
+        let invalid = |n| format!("Invalid {} for a {:?}", n, base);
+        let unzigzag = |n: u64| (n >> 1) as i64 ^ -((n & 1) as i64);
+        Ok(match base {
+            BaseType::Bool => Value::Bool(self.varint()? != 0),
+            BaseType::I8 => {
+                let n = unzigzag(self.varint()?);
+                Value::I8(i8::try_from(n).map_err(|_| invalid(n))?)
+            }
+            BaseType::U8 => {
+                let n = self.varint()?;
+                Value::U8(u8::try_from(n).map_err(|_| invalid(n as i64))?)
+            }
+            BaseType::I16 => {
+                let n = unzigzag(self.varint()?);
+                Value::I16(i16::try_from(n).map_err(|_| invalid(n))?)
+            }
+            BaseType::U16 => {
+                let n = self.varint()?;
+                Value::U16(u16::try_from(n).map_err(|_| invalid(n as i64))?)
+            }
+            BaseType::I32 => {
+                let n = unzigzag(self.varint()?);
+                Value::I32(i32::try_from(n).map_err(|_| invalid(n))?)
+            }
+            BaseType::U32 => {
+                let n = self.varint()?;
+                Value::U32(u32::try_from(n).map_err(|_| invalid(n as i64))?)
+            }
+            BaseType::I64 => Value::I64(unzigzag(self.varint()?)),
+            BaseType::U64 => Value::U64(self.varint()?),
+            BaseType::F32 => {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(self.bytes(4)?);
+                Value::F32(f32::from_bits(u32::from_le_bytes(bytes)))
+            }
+            BaseType::F64 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(self.bytes(8)?);
+                Value::F64(f64::from_bits(u64::from_le_bytes(bytes)))
+            }
+            base => return Err(format!("{:?} isn't a Protobuf scalar", base)),
+        })
+    }
+}
+
+/// The value of the type `type_name` given in Protobuf. The missing fields take their default
+/// value, and the unknown ones are skipped.
+pub fn from_protobuf(types: &TypeRegistry, type_name: &str, data: &[u8]) -> Result<Value, String> {
+    let t = types
+        .get(type_name)
+        .ok_or_else(|| format!("Unknown type {}", type_name))?;
+    read_message(types, t, data, 0)
+}
+
+fn read_message(
+    types: &TypeRegistry,
+    t: &MessageType,
+    data: &[u8],
+    depth: usize,
+) -> Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("Too deeply nested type {}", t.name));
+    }
+    let mut values: Vec<Option<Value>> = vec![None; t.fields.len()];
+    let mut r = ProtoReader { buf: data, pos: 0 };
+    while !r.is_empty() {
+        let key = r.varint()?;
+        let (number, wire) = (key >> 3, key & 7);
+        let i = match number.checked_sub(1).map(|i| i as usize) {
+            Some(i) if i < t.fields.len() => i,
+            _ => {
+                r.skip(wire)?;
+                continue;
+            }
+        };
+        let f = &t.fields[i];
+        if f.multiplicity == Multiplicity::Single {
+            values[i] = Some(read_field(types, &mut r, &f.base, wire, depth)?);
+            continue;
+        }
+        let elements = match values[i].get_or_insert_with(|| Value::Array(vec![])) {
+            Value::Array(elements) => elements,
+            _ => unreachable!(),
+        };
+        if is_bytes(&f.base, f.multiplicity) {
+            if wire != LEN {
+                return Err(format!("Unexpected wire type {} for {}", wire, f.name));
+            }
+            *elements = r
+                .length_delimited()?
+                .iter()
+                .map(|b| Value::U8(*b))
+                .collect();
+        } else if wire == LEN && wire_type(&f.base) != LEN {
+            let mut packed = ProtoReader {
+                buf: r.length_delimited()?,
+                pos: 0,
+            };
+            while !packed.is_empty() {
+                elements.push(packed.scalar(&f.base)?);
+            }
+        } else {
+            elements.push(read_field(types, &mut r, &f.base, wire, depth)?);
+        }
+    }
+    let mut fields = Vec::with_capacity(t.fields.len());
+    for (f, value) in t.fields.iter().zip(values) {
+        let value = match value {
+            Some(value) => value,
+            None => types.default_value(&f.base, f.multiplicity)?,
+        };
+        fields.push((f.name.clone(), value));
+    }
+    Ok(Value::Struct(fields))
+}
+
+fn read_field(
+    types: &TypeRegistry,
+    r: &mut ProtoReader,
+    base: &BaseType,
+    wire: u64,
+    depth: usize,
+) -> Result<Value, String> {
+    if wire != wire_type(base) {
+        return Err(format!("Unexpected wire type {} for a {:?}", wire, base));
+    }
+    Ok(match base {
+        BaseType::String => Value::String(
+            String::from_utf8(r.length_delimited()?.to_vec())
+                .map_err(|_| String::from("Invalid UTF-8 string in Protobuf data"))?,
+        ),
+        BaseType::Message(name) => {
+            let t = types
+                .get(name)
+                .ok_or_else(|| format!("Unknown type {}", name))?;
+            read_message(types, t, r.length_delimited()?, depth + 1)?
+        }
+        base => r.scalar(base)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        registry
+            .add_msg(
+                "demo",
+                "Sample",
+                "bool ok\nint8 a\nint64 b\nuint64 c\nfloat32 d\nfloat64 e\nstring f\n\
+                 uint8[] data\nint16[2] g\nPoint[] points\nPoint origin\n",
+            )
+            .unwrap();
+        registry
+            .add_msg("demo", "Point", "int32 x\nstring label\n")
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_schema() {
+        assert_eq!(
+            proto_schema(&registry(), "demo::msg::dds_::Sample_").unwrap(),
+            r#"// Generated from the ROS 2 type demo/msg/Sample
+syntax = "proto3";
+
+message demo__msg__Sample {
+  bool ok = 1;
+  sint32 a = 2;
+  sint64 b = 3;
+  uint64 c = 4;
+  float d = 5;
+  double e = 6;
+  string f = 7;
+  bytes data = 8;
+  repeated sint32 g = 9;
+  repeated demo__msg__Point points = 10;
+  demo__msg__Point origin = 11;
+}
+
+message demo__msg__Point {
+  sint32 x = 1;
+  string label = 2;
+}
+"#
+        );
+        assert!(proto_schema(&registry(), "demo/msg/Unknown").is_err());
+    }
+
+    #[test]
+    fn test_protobuf() {
+        let registry = registry();
+        let point = |x, label: &str| {
+            Value::Struct(vec![
+                (String::from("x"), Value::I32(x)),
+                (String::from("label"), Value::String(label.to_string())),
+            ])
+        };
+        let value = Value::Struct(vec![
+            (String::from("ok"), Value::Bool(false)),
+            (String::from("a"), Value::I8(-1)),
+            (String::from("b"), Value::I64(i64::MIN)),
+            (String::from("c"), Value::U64(300)),
+            (String::from("d"), Value::F32(0.0)),
+            (String::from("e"), Value::F64(-0.0)),
+            (String::from("f"), Value::String(String::new())),
+            (
+                String::from("data"),
+                Value::Array(vec![Value::U8(0), Value::U8(255)]),
+            ),
+            (
+                String::from("g"),
+                Value::Array(vec![Value::I16(0), Value::I16(-2)]),
+            ),
+            (
+                String::from("points"),
+                Value::Array(vec![point(0, ""), point(1, "a")]),
+            ),
+            (String::from("origin"), point(0, "")),
+        ]);
+        let proto = to_protobuf(&registry, "demo/msg/Sample", &value).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            proto,
+            vec![
+                0x10, 0x01,
+                0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+                0x20, 0xac, 0x02,
+                0x31, 0, 0, 0, 0, 0, 0, 0, 0x80,
+                0x42, 0x02, 0x00, 0xff,
+                0x4a, 0x02, 0x00, 0x03,
+                0x52, 0x00,
+                0x52, 0x05, 0x08, 0x02, 0x12, 0x01, b'a',
+                0x5a, 0x00,
+            ]
+        );
+        let decoded = from_protobuf(&registry, "demo/msg/Sample", &proto).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(
+            registry.encode("demo/msg/Sample", &decoded).unwrap(),
+            registry.encode("demo/msg/Sample", &value).unwrap()
+        );
+
+        // the unknown fields are skipped and the repeated scalars may be unpacked
+        let decoded = from_protobuf(
+            &registry,
+            "demo/msg/Sample",
+            &[0x48, 0x03, 0x48, 0x04, 0x60, 0x01, 0x6a, 0x00],
+        )
+        .unwrap();
+        assert_eq!(
+            decoded.field("g"),
+            Some(&Value::Array(vec![Value::I16(-2), Value::I16(2)]))
+        );
+        assert!(from_protobuf(&registry, "demo/msg/Sample", &[0x10, 0x80, 0x02]).is_err());
+        assert!(from_protobuf(&registry, "demo/msg/Sample", &[0x3a, 0x05, b'a']).is_err());
+        assert!(from_protobuf(&registry, "demo/msg/Sample", &[0x15, 0, 0, 0, 0]).is_err());
+    }
+}
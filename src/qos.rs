@@ -0,0 +1,204 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::partitions::set_partitions;
+use cyclors::*;
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::os::raw;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    pub kind: dds_history_kind_t,
+    pub depth: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Reliability {
+    pub kind: dds_reliability_kind_t,
+    pub max_blocking_time: dds_duration_t,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Liveliness {
+    pub kind: dds_liveliness_kind_t,
+    pub lease_duration: dds_duration_t,
+}
+
+/// A serializable copy of the QoS policies of a DDS endpoint that matter to the bridge.
+/// It allows a QoS to be sent over zenoh and re-created on a remote bridge.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Qos {
+    pub durability: Option<dds_durability_kind_t>,
+    pub history: Option<History>,
+    pub reliability: Option<Reliability>,
+    pub deadline: Option<dds_duration_t>,
+    pub latency_budget: Option<dds_duration_t>,
+    pub liveliness: Option<Liveliness>,
+    pub destination_order: Option<dds_destination_order_kind_t>,
+    pub ownership: Option<dds_ownership_kind_t>,
+    pub ownership_strength: Option<i32>,
+    pub transport_priority: Option<i32>,
+    pub lifespan: Option<dds_duration_t>,
+    pub time_based_filter: Option<dds_duration_t>,
+    pub partitions: Option<Vec<String>>,
+    pub user_data: Option<Vec<u8>>,
+}
+
+impl Qos {
+    /// # Safety
+    /// `qos` must point to a valid `dds_qos_t`.
+    pub unsafe fn from_qos_native(qos: *const dds_qos_t) -> Self {
+        let mut result = Qos::default();
+
+        let mut durability: dds_durability_kind_t = 0;
+        if dds_qget_durability(qos, &mut durability) {
+            result.durability = Some(durability);
+        }
+        let mut history = History { kind: 0, depth: 0 };
+        if dds_qget_history(qos, &mut history.kind, &mut history.depth) {
+            result.history = Some(history);
+        }
+        let mut reliability = Reliability {
+            kind: 0,
+            max_blocking_time: 0,
+        };
+        if dds_qget_reliability(
+            qos,
+            &mut reliability.kind,
+            &mut reliability.max_blocking_time,
+        ) {
+            result.reliability = Some(reliability);
+        }
+        let mut duration: dds_duration_t = 0;
+        if dds_qget_deadline(qos, &mut duration) {
+            result.deadline = Some(duration);
+        }
+        if dds_qget_latency_budget(qos, &mut duration) {
+            result.latency_budget = Some(duration);
+        }
+        let mut liveliness = Liveliness {
+            kind: 0,
+            lease_duration: 0,
+        };
+        if dds_qget_liveliness(qos, &mut liveliness.kind, &mut liveliness.lease_duration) {
+            result.liveliness = Some(liveliness);
+        }
+        let mut destination_order: dds_destination_order_kind_t = 0;
+        if dds_qget_destination_order(qos, &mut destination_order) {
+            result.destination_order = Some(destination_order);
+        }
+        let mut ownership: dds_ownership_kind_t = 0;
+        if dds_qget_ownership(qos, &mut ownership) {
+            result.ownership = Some(ownership);
+        }
+        let mut value: i32 = 0;
+        if dds_qget_ownership_strength(qos, &mut value) {
+            result.ownership_strength = Some(value);
+        }
+        if dds_qget_transport_priority(qos, &mut value) {
+            result.transport_priority = Some(value);
+        }
+        if dds_qget_lifespan(qos, &mut duration) {
+            result.lifespan = Some(duration);
+        }
+        if dds_qget_time_based_filter(qos, &mut duration) {
+            result.time_based_filter = Some(duration);
+        }
+
+        let mut n = 0u32;
+        let mut ps: *mut *mut raw::c_char = std::ptr::null_mut();
+        if dds_qget_partition(qos, &mut n, &mut ps) {
+            let mut partitions = Vec::with_capacity(n as usize);
+            for k in 0..n {
+                let p = *(ps.offset(k as isize));
+                partitions.push(String::from(CStr::from_ptr(p).to_str().unwrap()));
+                dds_free(p as *mut raw::c_void);
+            }
+            if !ps.is_null() {
+                dds_free(ps as *mut raw::c_void);
+            }
+            result.partitions = Some(partitions);
+        }
+
+        let mut ud: *mut raw::c_void = std::ptr::null_mut();
+        let mut sz = 0u64;
+        if dds_qget_userdata(qos, &mut ud, &mut sz) {
+            let mut user_data = Vec::with_capacity(sz as usize);
+            if !ud.is_null() {
+                user_data
+                    .extend_from_slice(std::slice::from_raw_parts(ud as *const u8, sz as usize));
+                dds_free(ud);
+            }
+            result.user_data = Some(user_data);
+        }
+
+        result
+    }
+
+    /// Creates a new `dds_qos_t` with the policies of this `Qos`.
+    /// The caller is responsible for releasing it with `dds_delete_qos`.
+    pub fn to_qos_native(&self) -> *mut dds_qos_t {
+        unsafe {
+            let qos = dds_create_qos();
+            if let Some(durability) = self.durability {
+                dds_qset_durability(qos, durability);
+            }
+            if let Some(h) = self.history {
+                dds_qset_history(qos, h.kind, h.depth);
+            }
+            if let Some(r) = self.reliability {
+                dds_qset_reliability(qos, r.kind, r.max_blocking_time);
+            }
+            if let Some(d) = self.deadline {
+                dds_qset_deadline(qos, d);
+            }
+            if let Some(d) = self.latency_budget {
+                dds_qset_latency_budget(qos, d);
+            }
+            if let Some(l) = self.liveliness {
+                dds_qset_liveliness(qos, l.kind, l.lease_duration);
+            }
+            if let Some(kind) = self.destination_order {
+                dds_qset_destination_order(qos, kind);
+            }
+            if let Some(kind) = self.ownership {
+                dds_qset_ownership(qos, kind);
+            }
+            if let Some(v) = self.ownership_strength {
+                dds_qset_ownership_strength(qos, v);
+            }
+            if let Some(v) = self.transport_priority {
+                dds_qset_transport_priority(qos, v);
+            }
+            if let Some(d) = self.lifespan {
+                dds_qset_lifespan(qos, d);
+            }
+            if let Some(d) = self.time_based_filter {
+                dds_qset_time_based_filter(qos, d);
+            }
+            if let Some(partitions) = &self.partitions {
+                let ps: Vec<&str> = partitions.iter().map(|p| p.as_str()).collect();
+                set_partitions(qos, &ps);
+            }
+            if let Some(user_data) = &self.user_data {
+                dds_qset_userdata(
+                    qos,
+                    user_data.as_ptr() as *const raw::c_void,
+                    user_data.len() as u64,
+                );
+            }
+            qos
+        }
+    }
+}
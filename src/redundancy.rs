@@ -0,0 +1,220 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::MatchedEntity;
+use async_std::stream;
+use async_std::task;
+use futures::prelude::*;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use yaml_rust::Yaml;
+use zenoh::net::*;
+
+// The bridges of a scope in redundancy mode periodically publish a heartbeat on
+// "<scope>/@dds_bridge/alive/<bridge_id>", telling whether they are the leader
+const ALIVE_CHUNK: &str = "@dds_bridge/alive";
+
+const DEFAULT_LEASE: Duration = Duration::from_secs(3);
+
+/// The active/standby redundancy configuration.
+#[derive(Debug, Clone)]
+pub struct RedundancyConfig {
+    pub enabled: bool,
+    // a bridge whose heartbeats are not received for this duration is considered gone
+    pub lease: Duration,
+}
+
+impl Default for RedundancyConfig {
+    fn default() -> Self {
+        RedundancyConfig {
+            enabled: false,
+            lease: DEFAULT_LEASE,
+        }
+    }
+}
+
+impl RedundancyConfig {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let enabled = yaml["enabled"].as_bool().unwrap_or(false);
+        let lease = match &yaml["lease"] {
+            Yaml::Real(_) => yaml["lease"].as_f64(),
+            Yaml::Integer(i) => Some(*i as f64),
+            Yaml::BadValue => Some(DEFAULT_LEASE.as_secs_f64()),
+            _ => None,
+        };
+        match lease {
+            Some(l) if l > 0.0 => Ok(RedundancyConfig {
+                enabled,
+                lease: Duration::from_secs_f64(l),
+            }),
+            _ => Err(format!(
+                "Invalid redundancy lease {:?}: a positive number of seconds is expected",
+                yaml["lease"]
+            )),
+        }
+    }
+}
+
+/// Elects the leader among the bridge `bridge_id` and its alive `peers`, given with their
+/// leadership claims. A leader keeps its leadership as long as it is alive; if several bridges
+/// claim it, or none does, the one with the lowest identifier is elected.
+pub fn elect(bridge_id: &str, leader: bool, peers: &HashMap<String, bool>) -> bool {
+    let mut claims: Vec<&str> = peers
+        .iter()
+        .filter(|(_, claim)| **claim)
+        .map(|(id, _)| id.as_str())
+        .collect();
+    if leader {
+        claims.push(bridge_id);
+    }
+    let candidates = if claims.is_empty() {
+        peers
+            .keys()
+            .map(|id| id.as_str())
+            .chain(std::iter::once(bridge_id))
+            .collect()
+    } else {
+        claims
+    };
+    candidates.into_iter().min() == Some(bridge_id)
+}
+
+enum Event {
+    Heartbeat(Sample),
+    Tick,
+}
+
+/// Runs the leader election between the bridges of `scope`, sending a
+/// `MatchedEntity::LeadershipChanged` each time this bridge gains or loses the leadership.
+/// A standby bridge takes over at most `lease * 5 / 4` after the last heartbeat of the leader.
+pub fn run_leader_election(
+    z: Arc<Session>,
+    scope: String,
+    bridge_id: String,
+    lease: Duration,
+    tx: Sender<MatchedEntity>,
+) {
+    task::spawn(async move {
+        let alive_key = format!("{}/{}/{}", scope, ALIVE_CHUNK, bridge_id);
+        let sub_info = SubInfo {
+            reliability: Reliability::Reliable,
+            mode: SubMode::Push,
+            period: None,
+        };
+        let mut sub = z
+            .declare_subscriber(
+                &ResKey::RName(format!("{}/{}/*", scope, ALIVE_CHUNK)),
+                &sub_info,
+            )
+            .await
+            .unwrap();
+        let ticks = stream::interval(lease / 4).map(|_| Event::Tick);
+        let mut events = futures::stream::select(sub.stream().map(Event::Heartbeat), ticks);
+
+        // the last heartbeat of the other bridges, with their leadership claim
+        let mut peers: HashMap<String, (Instant, bool)> = HashMap::new();
+        let mut leader = false;
+        // wait for the heartbeats of the running bridges before the first election
+        let started = Instant::now();
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Heartbeat(sample) => {
+                    let id = match sample.res_name.rsplit('/').next() {
+                        Some(id) if id != bridge_id => id.to_string(),
+                        _ => continue,
+                    };
+                    let is_delete = sample
+                        .data_info
+                        .as_ref()
+                        .and_then(|i| i.kind)
+                        .map(|k| k == data_kind::DELETE)
+                        .unwrap_or(false);
+                    if is_delete {
+                        peers.remove(&id);
+                    } else {
+                        let claim = sample.payload.to_vec().first() == Some(&1);
+                        peers.insert(id, (Instant::now(), claim));
+                    }
+                }
+                Event::Tick => {
+                    peers.retain(|_, (last, _)| last.elapsed() < lease);
+                    if started.elapsed() >= lease {
+                        let claims = peers
+                            .iter()
+                            .map(|(id, (_, claim))| (id.clone(), *claim))
+                            .collect();
+                        let elected = elect(&bridge_id, leader, &claims);
+                        if elected != leader {
+                            leader = elected;
+                            info!(
+                                "Bridge {} is now {}",
+                                bridge_id,
+                                if leader { "the leader" } else { "standby" }
+                            );
+                            tx.send(MatchedEntity::LeadershipChanged { leader })
+                                .unwrap();
+                        }
+                    }
+                    if let Err(e) = z
+                        .write(&ResKey::RName(alive_key.clone()), vec![leader as u8].into())
+                        .await
+                    {
+                        warn!("Failed to publish the bridge heartbeat: {}", e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(list: &[(&str, bool)]) -> HashMap<String, bool> {
+        list.iter().map(|(id, c)| (id.to_string(), *c)).collect()
+    }
+
+    #[test]
+    fn test_lowest_id_elected_without_leader() {
+        assert!(elect("a1", false, &peers(&[])));
+        assert!(elect("a1", false, &peers(&[("b2", false)])));
+        assert!(!elect("b2", false, &peers(&[("a1", false)])));
+    }
+
+    #[test]
+    fn test_leader_keeps_leadership() {
+        // a bridge with a lower identifier joining doesn't take over
+        assert!(elect("b2", true, &peers(&[("a1", false)])));
+        assert!(!elect("a1", false, &peers(&[("b2", true)])));
+    }
+
+    #[test]
+    fn test_conflicting_claims() {
+        assert!(elect("a1", true, &peers(&[("b2", true)])));
+        assert!(!elect("b2", true, &peers(&[("a1", true), ("c3", false)])));
+    }
+
+    #[test]
+    fn test_config() {
+        let yaml = yaml_rust::YamlLoader::load_from_str("enabled: true\nlease: 1.5\n").unwrap();
+        let config = RedundancyConfig::from_yaml(&yaml[0]).unwrap();
+        assert!(config.enabled);
+        assert_eq!(config.lease, Duration::from_millis(1500));
+        let yaml = yaml_rust::YamlLoader::load_from_str("enabled: true\nlease: 0\n").unwrap();
+        assert!(RedundancyConfig::from_yaml(&yaml[0]).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::qos::Qos;
+use crate::{MatchedEntity, QosHolder};
+use async_std::stream;
+use async_std::task;
+use cdr::{CdrLe, Infinite};
+use futures::prelude::*;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zenoh::net::*;
+
+// The bridges exchange the DDS publications they discover under
+// "<scope>/@dds_bridge/<bridge_id>/pub/<endpoint_key>"
+const ADV_CHUNK: &str = "@dds_bridge";
+
+// The advertisements are re-published periodically, and the ones that are not refreshed
+// within the lease (e.g. because their bridge crashed) are considered withdrawn
+pub(crate) const ADV_REFRESH: Duration = Duration::from_secs(5);
+pub(crate) const ADV_LEASE: Duration = Duration::from_secs(15);
+
+/// A DDS publication discovered by a bridge and advertised to its peers over zenoh.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemotePublication {
+    pub topic_name: String,
+    pub type_name: String,
+    pub keyless: bool,
+    // the key of the DDS participant of the publication
+    pub participant: String,
+    pub qos: Qos,
+}
+
+impl RemotePublication {
+    pub fn encode(&self) -> Vec<u8> {
+        cdr::serialize::<_, _, CdrLe>(self, Infinite).unwrap()
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match cdr::deserialize_from::<_, RemotePublication, _>(buf, Infinite) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("Failed to decode a remote publication advertisement: {}", e);
+                None
+            }
+        }
+    }
+
+    // One MatchedEntity per partition, as for the locally discovered publications
+    fn to_matched_entities(&self, adv_key: &str) -> Vec<MatchedEntity> {
+        let partitions: Vec<Option<String>> = match &self.qos.partitions {
+            Some(ps) if !ps.is_empty() => ps.iter().cloned().map(Some).collect(),
+            _ => vec![None],
+        };
+        partitions
+            .into_iter()
+            .map(|partition| {
+                // restrict the QoS to the partition, as for the local publications
+                let mut qos = self.qos.clone();
+                if let Some(p) = &partition {
+                    qos.partitions = Some(vec![p.clone()]);
+                }
+                MatchedEntity::DiscoveredRemotePublication {
+                    key: String::from(adv_key),
+                    participant: self.participant.clone(),
+                    topic_name: self.topic_name.clone(),
+                    type_name: self.type_name.clone(),
+                    keyless: self.keyless,
+                    partition,
+                    qos: QosHolder(qos.to_qos_native()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The zenoh key used by the bridge `bridge_id` to advertise its DDS publication `key`.
+pub fn publication_adv_key(scope: &str, bridge_id: &str, key: &str) -> String {
+    format!("{}/{}/{}/pub/{}", scope, ADV_CHUNK, bridge_id, key)
+}
+
+fn is_from_bridge(scope: &str, bridge_id: &str, adv_key: &str) -> bool {
+    adv_key.starts_with(&format!("{}/{}/{}/", scope, ADV_CHUNK, bridge_id))
+}
+
+// Whether the routes of a remote publication must be re-created to apply the new advertisement,
+// i.e. if a QoS other than the partitions changed, or if some partitions were removed.
+fn needs_recreate(known: &RemotePublication, advertised: &RemotePublication) -> bool {
+    let (mut known_qos, mut advertised_qos) = (known.qos.clone(), advertised.qos.clone());
+    let known_partitions = known_qos.partitions.take().unwrap_or_default();
+    let advertised_partitions = advertised_qos.partitions.take().unwrap_or_default();
+    known.topic_name != advertised.topic_name
+        || known.type_name != advertised.type_name
+        || known.keyless != advertised.keyless
+        || known_qos != advertised_qos
+        || known_partitions
+            .iter()
+            .any(|p| !advertised_partitions.contains(p))
+}
+
+// The keys of the known advertisements that were not refreshed since `now - lease`
+pub(crate) fn expired<T>(
+    known: &HashMap<String, (T, Instant)>,
+    now: Instant,
+    lease: Duration,
+) -> Vec<String> {
+    known
+        .iter()
+        .filter(|(_, (_, last))| now.saturating_duration_since(*last) >= lease)
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+fn handle_advertisement(
+    sample: Sample,
+    tx: &Sender<MatchedEntity>,
+    known: &mut HashMap<String, (RemotePublication, Instant)>,
+) {
+    let is_delete = sample
+        .data_info
+        .as_ref()
+        .and_then(|i| i.kind)
+        .map(|k| k == data_kind::DELETE)
+        .unwrap_or(false);
+    if is_delete {
+        debug!("Remote publication {} withdrawn", sample.res_name);
+        known.remove(&sample.res_name);
+        tx.send(MatchedEntity::UndiscoveredRemotePublication {
+            key: sample.res_name,
+        })
+        .unwrap();
+    } else if let Some(p) = RemotePublication::decode(&sample.payload.to_vec()) {
+        match known.get_mut(&sample.res_name) {
+            Some((k, last)) if *k == p => {
+                *last = Instant::now();
+                return;
+            }
+            Some((k, _)) if needs_recreate(k, &p) => {
+                debug!("Remote publication {} changed: {:?}", sample.res_name, p);
+                tx.send(MatchedEntity::UndiscoveredRemotePublication {
+                    key: sample.res_name.clone(),
+                })
+                .unwrap();
+            }
+            _ => debug!("Remote publication {} advertised: {:?}", sample.res_name, p),
+        }
+        for me in p.to_matched_entities(&sample.res_name) {
+            tx.send(me).unwrap();
+        }
+        known.insert(sample.res_name, (p, Instant::now()));
+    }
+}
+
+enum Event {
+    Advertisement(Sample),
+    Tick,
+}
+
+/// Advertises over zenoh the publications stored in `adverts` (by advertisement key)
+/// and forwards the ones advertised by the other bridges of the same scope to `tx`.
+/// The advertisements of a bridge that stopped refreshing them are undiscovered after a lease.
+pub fn run_remote_discovery(
+    z: Arc<Session>,
+    scope: String,
+    bridge_id: String,
+    adverts: Arc<Mutex<HashMap<String, RemotePublication>>>,
+    tx: Sender<MatchedEntity>,
+) {
+    let selector = format!("{}/{}/*/pub/*", scope, ADV_CHUNK);
+
+    // Periodically refresh our advertisements
+    let zr = z.clone();
+    let refreshed = adverts.clone();
+    task::spawn(async move {
+        let mut ticks = stream::interval(ADV_REFRESH);
+        while ticks.next().await.is_some() {
+            let refreshes: Vec<(String, Vec<u8>)> = refreshed
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, p)| (k.clone(), p.encode()))
+                .collect();
+            for (adv_key, payload) in refreshes {
+                if let Err(e) = zr.write(&ResKey::RName(adv_key), payload.into()).await {
+                    warn!("Failed to refresh a DDS publication advertisement: {}", e);
+                }
+            }
+        }
+    });
+
+    // Reply to the bridges that start after us with our current advertisements
+    let zq = z.clone();
+    let own_keys = format!("{}/{}/{}/pub/*", scope, ADV_CHUNK, bridge_id);
+    task::spawn(async move {
+        let mut queryable = zq
+            .declare_queryable(&ResKey::RName(own_keys), queryable::EVAL)
+            .await
+            .unwrap();
+        while let Some(query) = queryable.stream().next().await {
+            let replies: Vec<(String, Vec<u8>)> = adverts
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(k, p)| (k.clone(), p.encode()))
+                .collect();
+            for (res_name, payload) in replies {
+                query
+                    .reply(Sample {
+                        res_name,
+                        payload: payload.into(),
+                        data_info: None,
+                    })
+                    .await;
+            }
+        }
+    });
+
+    task::spawn(async move {
+        let sub_info = SubInfo {
+            reliability: Reliability::Reliable,
+            mode: SubMode::Push,
+            period: None,
+        };
+        let mut sub = z
+            .declare_subscriber(&ResKey::RName(selector.clone()), &sub_info)
+            .await
+            .unwrap();
+
+        let mut known = HashMap::<String, (RemotePublication, Instant)>::new();
+
+        // Fetch the publications advertised before we joined
+        let mut replies = z
+            .query(
+                &ResKey::RName(selector),
+                "",
+                QueryTarget::default(),
+                QueryConsolidation::default(),
+            )
+            .await
+            .unwrap();
+        while let Some(reply) = replies.next().await {
+            if !is_from_bridge(&scope, &bridge_id, &reply.data.res_name) {
+                handle_advertisement(reply.data, &tx, &mut known);
+            }
+        }
+
+        let ticks = stream::interval(ADV_REFRESH).map(|_| Event::Tick);
+        let mut events = futures::stream::select(sub.stream().map(Event::Advertisement), ticks);
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Advertisement(sample) => {
+                    if !is_from_bridge(&scope, &bridge_id, &sample.res_name) {
+                        handle_advertisement(sample, &tx, &mut known);
+                    }
+                }
+                Event::Tick => {
+                    for key in expired(&known, Instant::now(), ADV_LEASE) {
+                        debug!("Remote publication {} expired", key);
+                        known.remove(&key);
+                        tx.send(MatchedEntity::UndiscoveredRemotePublication { key })
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Advertises a locally discovered DDS publication to the other bridges.
+pub async fn advertise(
+    z: &Session,
+    adverts: &Mutex<HashMap<String, RemotePublication>>,
+    adv_key: String,
+    publication: RemotePublication,
+) {
+    let payload = publication.encode();
+    adverts.lock().unwrap().insert(adv_key.clone(), publication);
+    if let Err(e) = z.write(&ResKey::RName(adv_key), payload.into()).await {
+        warn!("Failed to advertise a DDS publication: {}", e);
+    }
+}
+
+/// Withdraws a previously advertised DDS publication, if any.
+pub async fn withdraw(
+    z: &Session,
+    adverts: &Mutex<HashMap<String, RemotePublication>>,
+    adv_key: String,
+) {
+    if adverts.lock().unwrap().remove(&adv_key).is_some() {
+        if let Err(e) = z
+            .write_ext(
+                &ResKey::RName(adv_key),
+                RBuf::empty(),
+                encoding::APP_OCTET_STREAM,
+                data_kind::DELETE,
+                CongestionControl::Block,
+            )
+            .await
+        {
+            warn!("Failed to withdraw a DDS publication advertisement: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publication(topic_name: &str) -> RemotePublication {
+        RemotePublication {
+            topic_name: topic_name.to_string(),
+            type_name: "std_msgs::msg::dds_::String_".to_string(),
+            keyless: true,
+            participant: "p1".to_string(),
+            qos: Qos::default(),
+        }
+    }
+
+    #[test]
+    fn test_expired() {
+        let start = Instant::now();
+        let mut known = HashMap::new();
+        known.insert("s/a".to_string(), (publication("rt/a"), start));
+        known.insert(
+            "s/b".to_string(),
+            (publication("rt/b"), start + Duration::from_secs(10)),
+        );
+        let lease = Duration::from_secs(15);
+        assert!(expired(&known, start + Duration::from_secs(14), lease).is_empty());
+        assert_eq!(
+            expired(&known, start + Duration::from_secs(20), lease),
+            vec!["s/a".to_string()]
+        );
+        let mut all = expired(&known, start + Duration::from_secs(30), lease);
+        all.sort();
+        assert_eq!(all, vec!["s/a".to_string(), "s/b".to_string()]);
+        // a refresh received before `now` was computed is not expired
+        assert!(expired(&known, start, lease).is_empty());
+    }
+}
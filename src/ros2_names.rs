@@ -0,0 +1,116 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::services::{service_key_topic, service_name, ServiceTopic};
+
+// ROS 2 prefixes the DDS topic names of its topics with "rt/"
+const TOPIC_PREFIX: &str = "rt/";
+// and suffixes the DDS type names in "::dds_::<Name>_"
+const DDS_TYPE_CHUNK: &str = "::dds_::";
+
+/// The ROS 2 form of a DDS topic name, used in the zenoh keys instead of the DDS name:
+///  - "rt/<name>" is "<name>",
+///  - "rq/<svc>Request" and "rr/<svc>Reply" are "srv/<svc>/request" and "srv/<svc>/reply",
+///  - the names of the other DDS topics are kept.
+pub fn demangle_topic(topic_name: &str) -> String {
+    if let Some(name) = topic_name.strip_prefix(TOPIC_PREFIX) {
+        return name.to_string();
+    }
+    match service_name(topic_name) {
+        Some((svc, ServiceTopic::Request)) => format!("{}/request", service_key_topic(svc)),
+        Some((svc, ServiceTopic::Reply)) => format!("{}/reply", service_key_topic(svc)),
+        None => topic_name.to_string(),
+    }
+}
+
+/// The DDS topic name of a ROS 2 topic given with its absolute ROS name, e.g. "/camera1/image_raw".
+pub fn mangle_topic(ros_name: &str) -> String {
+    format!("{}{}", TOPIC_PREFIX, ros_name.trim_start_matches('/'))
+}
+
+/// The ROS 2 form of a DDS type name, e.g. "sensor_msgs/msg/Image" for
+/// "sensor_msgs::msg::dds_::Image_". The names of the non ROS 2 types are kept.
+pub fn demangle_type(type_name: &str) -> String {
+    match type_name.rfind(DDS_TYPE_CHUNK) {
+        Some(i) => {
+            let name = &type_name[i + DDS_TYPE_CHUNK.len()..];
+            format!(
+                "{}/{}",
+                type_name[..i].replace("::", "/"),
+                name.strip_suffix('_').unwrap_or(name)
+            )
+        }
+        None => type_name.to_string(),
+    }
+}
+
+/// The DDS type name of a ROS 2 type given in its ROS 2 form, e.g. "sensor_msgs/msg/Image".
+pub fn mangle_type(ros_type: &str) -> String {
+    match ros_type.rfind('/') {
+        Some(i) => format!(
+            "{}{}{}_",
+            ros_type[..i].replace('/', "::"),
+            DDS_TYPE_CHUNK,
+            &ros_type[i + 1..]
+        ),
+        None => ros_type.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(
+            demangle_topic("rt/camera1/color/image_raw"),
+            "camera1/color/image_raw"
+        );
+        assert_eq!(
+            demangle_topic("rq/add_two_intsRequest"),
+            "srv/add_two_ints/request"
+        );
+        assert_eq!(
+            demangle_topic("rr/add_two_intsReply"),
+            "srv/add_two_ints/reply"
+        );
+        assert_eq!(demangle_topic("Square"), "Square");
+        assert_eq!(
+            mangle_topic("/camera1/color/image_raw"),
+            "rt/camera1/color/image_raw"
+        );
+        assert_eq!(
+            demangle_topic(&mangle_topic("/camera1/color/image_raw")),
+            "camera1/color/image_raw"
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        assert_eq!(
+            demangle_type("sensor_msgs::msg::dds_::Image_"),
+            "sensor_msgs/msg/Image"
+        );
+        assert_eq!(
+            demangle_type("example_interfaces::srv::dds_::AddTwoInts_Request_"),
+            "example_interfaces/srv/AddTwoInts_Request"
+        );
+        assert_eq!(demangle_type("ShapeType"), "ShapeType");
+        assert_eq!(
+            mangle_type("sensor_msgs/msg/Image"),
+            "sensor_msgs::msg::dds_::Image_"
+        );
+        assert_eq!(mangle_type("ShapeType"), "ShapeType");
+    }
+}
@@ -0,0 +1,578 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::actions::*;
+use crate::coders::{Coders, DDSWriter, Writer};
+use crate::{create_forwarding_dds_reader, create_forwarding_dds_writer, QosHolder};
+use async_std::future::timeout;
+use async_std::task;
+use cyclors::*;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use yaml_rust::Yaml;
+use zenoh::net::*;
+
+// A ROS 2 service <svc> is made of the DDS topics "rq/<svc>Request" and "rr/<svc>Reply"
+const REQUEST_PREFIX: &str = "rq/";
+const REQUEST_SUFFIX: &str = "Request";
+const REPLY_PREFIX: &str = "rr/";
+const REPLY_SUFFIX: &str = "Reply";
+
+// The zenoh key chunk of the service <svc> is "srv/<svc>"
+const SERVICE_CHUNK: &str = "srv";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The ROS 2 services configuration.
+#[derive(Debug, Clone)]
+pub struct ServicesConfig {
+    // if true, the ROS 2 services are routed as zenoh queries rather than as two topics
+    pub enabled: bool,
+    // the time after which a request without reply is given up
+    pub timeout: Duration,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        ServicesConfig {
+            enabled: false,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ServicesConfig {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let enabled = yaml["enabled"].as_bool().unwrap_or(false);
+        let timeout = match &yaml["timeout"] {
+            Yaml::Real(_) => yaml["timeout"].as_f64(),
+            Yaml::Integer(i) => Some(*i as f64),
+            Yaml::BadValue => Some(DEFAULT_TIMEOUT.as_secs_f64()),
+            _ => None,
+        };
+        match timeout {
+            Some(t) if t > 0.0 => Ok(ServicesConfig {
+                enabled,
+                timeout: Duration::from_secs_f64(t),
+            }),
+            _ => Err(format!(
+                "Invalid services timeout {:?}: a positive number of seconds is expected",
+                yaml["timeout"]
+            )),
+        }
+    }
+}
+
+/// The topic of a ROS 2 service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServiceTopic {
+    Request,
+    Reply,
+}
+
+/// The name of the ROS 2 service `topic_name` belongs to, if any, and which of its topics it is.
+pub fn service_name(topic_name: &str) -> Option<(&str, ServiceTopic)> {
+    if let Some(svc) = topic_name
+        .strip_prefix(REQUEST_PREFIX)
+        .and_then(|t| t.strip_suffix(REQUEST_SUFFIX))
+    {
+        Some((svc, ServiceTopic::Request))
+    } else {
+        topic_name
+            .strip_prefix(REPLY_PREFIX)
+            .and_then(|t| t.strip_suffix(REPLY_SUFFIX))
+            .map(|svc| (svc, ServiceTopic::Reply))
+    }
+}
+
+pub fn request_topic(service_name: &str) -> String {
+    format!("{}{}{}", REQUEST_PREFIX, service_name, REQUEST_SUFFIX)
+}
+
+pub fn reply_topic(service_name: &str) -> String {
+    format!("{}{}{}", REPLY_PREFIX, service_name, REPLY_SUFFIX)
+}
+
+/// The topic name the zenoh key of the service is made of, as for the topics.
+pub fn service_key_topic(service_name: &str) -> String {
+    format!("{}/{}", SERVICE_CHUNK, service_name)
+}
+
+/// The reply type of a service given its request type,
+/// e.g. "example_interfaces::srv::dds_::AddTwoInts_Response_" for "...AddTwoInts_Request_".
+pub fn reply_type(request_type: &str) -> Option<String> {
+    request_type
+        .strip_suffix("_Request_")
+        .map(|t| format!("{}_Response_", t))
+}
+
+const ENCAPSULATION_LEN: usize = 4;
+const REQUEST_HEADER_LEN: usize = 16;
+
+/// The header that precedes the requests and replies of a service in their CDR payloads: the
+/// GUID of the client's request writer and the sequence number of the request. The server copies
+/// it in the reply, for the client to recognize it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestHeader {
+    pub guid: u64,
+    pub seq: i64,
+}
+
+// The header is encoded with the endianness of its payload, as given by the encapsulation
+fn is_little_endian(buf: &[u8]) -> bool {
+    buf[1] & 1 == 1
+}
+
+impl RequestHeader {
+    /// Splits a request or reply into its header and its payload without header.
+    pub fn split(buf: &[u8]) -> Option<(RequestHeader, Vec<u8>)> {
+        if buf.len() < ENCAPSULATION_LEN + REQUEST_HEADER_LEN {
+            return None;
+        }
+        let mut guid = [0u8; 8];
+        let mut seq = [0u8; 8];
+        guid.copy_from_slice(&buf[ENCAPSULATION_LEN..ENCAPSULATION_LEN + 8]);
+        seq.copy_from_slice(&buf[ENCAPSULATION_LEN + 8..ENCAPSULATION_LEN + REQUEST_HEADER_LEN]);
+        let header = if is_little_endian(buf) {
+            RequestHeader {
+                guid: u64::from_le_bytes(guid),
+                seq: i64::from_le_bytes(seq),
+            }
+        } else {
+            RequestHeader {
+                guid: u64::from_be_bytes(guid),
+                seq: i64::from_be_bytes(seq),
+            }
+        };
+        let mut payload = Vec::with_capacity(buf.len() - REQUEST_HEADER_LEN);
+        payload.extend_from_slice(&buf[..ENCAPSULATION_LEN]);
+        payload.extend_from_slice(&buf[ENCAPSULATION_LEN + REQUEST_HEADER_LEN..]);
+        Some((header, payload))
+    }
+
+    /// Inserts the header in a request or reply payload.
+    pub fn insert(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < ENCAPSULATION_LEN {
+            return None;
+        }
+        let mut buf = Vec::with_capacity(payload.len() + REQUEST_HEADER_LEN);
+        buf.extend_from_slice(&payload[..ENCAPSULATION_LEN]);
+        if is_little_endian(payload) {
+            buf.extend_from_slice(&self.guid.to_le_bytes());
+            buf.extend_from_slice(&self.seq.to_le_bytes());
+        } else {
+            buf.extend_from_slice(&self.guid.to_be_bytes());
+            buf.extend_from_slice(&self.seq.to_be_bytes());
+        }
+        buf.extend_from_slice(&payload[ENCAPSULATION_LEN..]);
+        Some(buf)
+    }
+}
+
+// The queries of this zenoh version have no payload: the requests are hex-encoded in their predicate
+pub fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+// The results of the actions are awaited as long as their goals run, without timeout
+fn request_timeout(service_name: &str, timeout: Duration) -> Option<Duration> {
+    match action_service(service_name) {
+        Some((_, ActionService::GetResult)) => None,
+        _ => Some(timeout),
+    }
+}
+
+/// Routes the requests of the local DDS clients of a service as zenoh queries,
+/// writing back the replies with the header of their request.
+struct ServiceClient {
+    z: Arc<Session>,
+    key: String,
+    replies: Arc<Mutex<DDSWriter>>,
+    timeout: Option<Duration>,
+    // the goals of the action the service belongs to, if any
+    action: Option<(ActionService, Arc<ActionGoals>)>,
+}
+
+impl Writer for ServiceClient {
+    fn write(&self, buf: &[u8]) {
+        let (header, request) = match RequestHeader::split(buf) {
+            Some(r) => r,
+            None => {
+                warn!("Invalid request of {}: no request header", self.key);
+                return;
+            }
+        };
+        // The requests about a goal are routed to the bridge of the server that accepted it,
+        // a request cancelling all the goals to all the servers
+        let goal = goal_id(&request);
+        let (selector, target) = match (&self.action, &goal) {
+            (Some((ActionService::CancelGoal, _)), Some(g)) if is_any_goal(g) => {
+                (self.key.clone(), Target::All)
+            }
+            (Some((ActionService::CancelGoal, goals)), Some(g))
+            | (Some((ActionService::GetResult, goals)), Some(g)) => match goals.server(g) {
+                Some(server) => (format!("{}/{}", self.key, server), Target::BestMatching),
+                None => (self.key.clone(), Target::BestMatching),
+            },
+            _ => (self.key.clone(), Target::BestMatching),
+        };
+        let z = self.z.clone();
+        let key = self.key.clone();
+        let replies = self.replies.clone();
+        let action = self.action.clone();
+        let t = self.timeout;
+        task::spawn(async move {
+            let mut stream = match z
+                .query(
+                    &ResKey::RName(selector),
+                    &to_hex(&request),
+                    QueryTarget {
+                        kind: queryable::EVAL,
+                        target,
+                    },
+                    QueryConsolidation::none(),
+                )
+                .await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to route the request of {}: {}", key, e);
+                    return;
+                }
+            };
+            let route_replies = async {
+                while let Some(reply) = stream.next().await {
+                    let payload = reply.data.payload.to_vec();
+                    // the replies are published on "<key>/<server bridge id>"
+                    if let (Some((service, goals)), Some(g)) = (&action, goal) {
+                        match service {
+                            ActionService::SendGoal if is_goal_accepted(&payload) => {
+                                if let Some(server) = reply.data.res_name.rsplit('/').next() {
+                                    goals.accepted(g, server.to_string());
+                                }
+                            }
+                            ActionService::GetResult => goals.done(&g),
+                            _ => (),
+                        }
+                    }
+                    match header.insert(&payload) {
+                        Some(buf) => replies.lock().unwrap().write(&buf),
+                        None => warn!("Invalid reply of {} received from zenoh", key),
+                    }
+                }
+            };
+            match t {
+                Some(t) => {
+                    if timeout(t, route_replies).await.is_err() {
+                        warn!("Request {} of {} timed out", header.seq, key);
+                    }
+                }
+                None => route_replies.await,
+            }
+        });
+    }
+}
+
+/// Creates the route of the local DDS clients of the service `service_name`: a DDS reader of
+/// their requests, routed as zenoh queries on `key`, and a DDS writer of the replies.
+/// The goals of an action are shared by the client routes of its services in `goals`.
+/// Returns the DDS reader and writer.
+#[allow(clippy::too_many_arguments)]
+pub fn create_service_client_route(
+    z: Arc<Session>,
+    dp: dds_entity_t,
+    key: String,
+    service_name: &str,
+    request_type: String,
+    qos: QosHolder,
+    coders: &Coders,
+    pr: dds_entity_t,
+    timeout: Duration,
+    goals: Option<Arc<ActionGoals>>,
+) -> Option<(dds_entity_t, dds_entity_t)> {
+    let reply_type = reply_type(&request_type)?;
+    let reply_topic = reply_topic(service_name);
+    let wr = create_forwarding_dds_writer(
+        dp,
+        reply_topic.clone(),
+        reply_type.clone(),
+        true,
+        qos.clone(),
+    );
+    let replies = DDSWriter::new(dp, wr, &reply_topic, &reply_type, true);
+    let client = ServiceClient {
+        z,
+        key: key.clone(),
+        replies: Arc::new(Mutex::new(replies)),
+        timeout: request_timeout(service_name, timeout),
+        action: action_service(service_name).and_then(|(_, s)| goals.map(|g| (s, g))),
+    };
+    let dr = create_forwarding_dds_reader(
+        dp,
+        request_topic(service_name),
+        request_type,
+        true,
+        qos,
+        ResKey::RName(key),
+        Box::new(client),
+        coders,
+        pr,
+    );
+    Some((dr, wr))
+}
+
+/// The requests routed from zenoh to a local DDS server, waiting for their reply.
+struct PendingRequests {
+    // the GUID of the requests written by the bridge
+    guid: u64,
+    next_seq: AtomicI64,
+    replies: Mutex<HashMap<i64, oneshot::Sender<Vec<u8>>>>,
+}
+
+impl PendingRequests {
+    fn register(&self) -> (RequestHeader, oneshot::Receiver<Vec<u8>>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().unwrap().insert(seq, tx);
+        (
+            RequestHeader {
+                guid: self.guid,
+                seq,
+            },
+            rx,
+        )
+    }
+
+    fn cancel(&self, seq: i64) {
+        self.replies.lock().unwrap().remove(&seq);
+    }
+}
+
+// The server replies to all the clients: only the replies to the requests of the bridge are routed
+struct ServiceReplies(Arc<PendingRequests>);
+
+impl Writer for ServiceReplies {
+    fn write(&self, buf: &[u8]) {
+        match RequestHeader::split(buf) {
+            Some((header, reply)) if header.guid == self.0.guid => {
+                if let Some(tx) = self.0.replies.lock().unwrap().remove(&header.seq) {
+                    let _ = tx.send(reply);
+                }
+            }
+            Some(_) => (),
+            None => warn!("Invalid reply received from DDS: no request header"),
+        }
+    }
+}
+
+// The service is served on its key and on "<key>/<bridge_id>", for the requests about an action's
+// goal to reach the server that accepted it. The replies are published on the latter.
+fn run_service_queryable(
+    z: Arc<Session>,
+    key: String,
+    bridge_id: &str,
+    requests: DDSWriter,
+    pending: Arc<PendingRequests>,
+    t: Option<Duration>,
+) -> task::JoinHandle<()> {
+    let reply_key = format!("{}/{}", key, bridge_id);
+    task::spawn(async move {
+        let mut queryable = z
+            .declare_queryable(&ResKey::RName(key.clone()), queryable::EVAL)
+            .await
+            .unwrap();
+        let mut bqueryable = z
+            .declare_queryable(&ResKey::RName(reply_key.clone()), queryable::EVAL)
+            .await
+            .unwrap();
+        let mut queries = futures::stream::select(queryable.stream(), bqueryable.stream());
+        while let Some(query) = queries.next().await {
+            let request = match from_hex(&query.predicate) {
+                Some(request) => request,
+                None => {
+                    warn!("Invalid request of {} received from zenoh", key);
+                    continue;
+                }
+            };
+            let (header, rx) = pending.register();
+            match header.insert(&request) {
+                Some(buf) => requests.write(&buf),
+                None => {
+                    warn!("Invalid request of {} received from zenoh", key);
+                    pending.cancel(header.seq);
+                    continue;
+                }
+            }
+            let key = key.clone();
+            let reply_key = reply_key.clone();
+            let pending = pending.clone();
+            task::spawn(async move {
+                let reply = match t {
+                    Some(t) => timeout(t, rx).await.ok().and_then(|r| r.ok()),
+                    None => rx.await.ok(),
+                };
+                match reply {
+                    Some(reply) => {
+                        query
+                            .reply(Sample {
+                                res_name: reply_key,
+                                payload: reply.into(),
+                                data_info: None,
+                            })
+                            .await
+                    }
+                    None => {
+                        debug!("Request {} of {} timed out", header.seq, key);
+                        pending.cancel(header.seq);
+                    }
+                }
+            });
+        }
+    })
+}
+
+/// Creates the route of a local DDS server of the service `service_name`: a zenoh queryable on
+/// `key` whose queries are written as requests to the server, and a DDS reader of its replies.
+/// `bridge_id` identifies this bridge to the clients of the goals of an action.
+/// Returns the DDS writer and reader, and the task of the queryable.
+#[allow(clippy::too_many_arguments)]
+pub fn create_service_server_route(
+    z: Arc<Session>,
+    dp: dds_entity_t,
+    key: String,
+    bridge_id: &str,
+    service_name: &str,
+    request_type: String,
+    qos: QosHolder,
+    coders: &Coders,
+    pr: dds_entity_t,
+    timeout: Duration,
+) -> Option<(dds_entity_t, dds_entity_t, task::JoinHandle<()>)> {
+    let reply_type = reply_type(&request_type)?;
+    let request_topic = request_topic(service_name);
+    let wr = create_forwarding_dds_writer(
+        dp,
+        request_topic.clone(),
+        request_type.clone(),
+        true,
+        qos.clone(),
+    );
+    let requests = DDSWriter::new(dp, wr, &request_topic, &request_type, true);
+    // the requests written by the bridge are identified by the instance handle of its writer
+    let mut guid: dds_instance_handle_t = 0;
+    unsafe {
+        dds_get_instance_handle(wr, &mut guid);
+    }
+    let pending = Arc::new(PendingRequests {
+        guid,
+        next_seq: AtomicI64::new(1),
+        replies: Mutex::new(HashMap::new()),
+    });
+    let dr = create_forwarding_dds_reader(
+        dp,
+        reply_topic(service_name),
+        reply_type,
+        true,
+        qos,
+        ResKey::RName(key.clone()),
+        Box::new(ServiceReplies(pending.clone())),
+        coders,
+        pr,
+    );
+    let task = run_service_queryable(
+        z,
+        key,
+        bridge_id,
+        requests,
+        pending,
+        request_timeout(service_name, timeout),
+    );
+    Some((wr, dr, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_name() {
+        assert_eq!(
+            service_name("rq/add_two_intsRequest"),
+            Some(("add_two_ints", ServiceTopic::Request))
+        );
+        assert_eq!(
+            service_name("rr/robot1/add_two_intsReply"),
+            Some(("robot1/add_two_ints", ServiceTopic::Reply))
+        );
+        assert_eq!(service_name("rt/chatter"), None);
+        assert_eq!(service_name("rq/add_two_intsReply"), None);
+        assert_eq!(request_topic("add_two_ints"), "rq/add_two_intsRequest");
+        assert_eq!(reply_topic("add_two_ints"), "rr/add_two_intsReply");
+        assert_eq!(
+            reply_type("example_interfaces::srv::dds_::AddTwoInts_Request_").as_deref(),
+            Some("example_interfaces::srv::dds_::AddTwoInts_Response_")
+        );
+        assert_eq!(reply_type("std_msgs::msg::dds_::String_"), None);
+    }
+
+    #[test]
+    fn test_request_header() {
+        let request = [
+            0x00, 0x01, 0x00, 0x00, // CDR little endian
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // guid
+            0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // seq
+            0x03, 0x00, 0x00, 0x00, // payload
+        ];
+        let (header, payload) = RequestHeader::split(&request).unwrap();
+        assert_eq!(header.guid, 0x0807060504030201);
+        assert_eq!(header.seq, 42);
+        assert_eq!(
+            payload,
+            vec![0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(header.insert(&payload).unwrap(), request.to_vec());
+
+        // the header of a big endian reply is big endian
+        let reply = header.insert(&[0x00, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(
+            &reply[4..12],
+            &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(RequestHeader::split(&reply).unwrap().0, header);
+        assert!(RequestHeader::split(&request[..12]).is_none());
+    }
+
+    #[test]
+    fn test_hex() {
+        let buf = vec![0x00, 0x01, 0xab, 0xff];
+        assert_eq!(to_hex(&buf), "0001abff");
+        assert_eq!(from_hex("0001abff"), Some(buf));
+        assert_eq!(from_hex("0001a"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
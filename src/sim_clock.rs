@@ -0,0 +1,191 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::coders::Writer;
+use async_std::task;
+use cdr::Infinite;
+use log::warn;
+use serde_derive::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use yaml_rust::Yaml;
+
+// The ROS 2 topic of the simulation time
+pub const CLOCK_TOPIC: &str = "rt/clock";
+pub const CLOCK_TYPE: &str = "rosgraph_msgs::msg::dds_::Clock_";
+
+// How often a sleep in simulation time checks if it is over
+const SIM_POLL_PERIOD: Duration = Duration::from_millis(5);
+
+/// The simulation time configuration: if enabled, the bridge runs on the time of /clock
+/// rather than on the wall time.
+#[derive(Debug, Default)]
+pub struct SimTimeConfig {
+    pub enabled: bool,
+    // the simulation instance of the bridge, whose bridges are separated from the other ones'
+    pub instance: Option<String>,
+}
+
+impl SimTimeConfig {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let enabled = yaml["enabled"].as_bool().unwrap_or(false);
+        let instance = match &yaml["instance"] {
+            Yaml::String(i) if !i.trim_matches('/').is_empty() => {
+                Some(i.trim_matches('/').to_string())
+            }
+            Yaml::BadValue => None,
+            i => return Err(format!("Invalid simulation instance {:?}", i)),
+        };
+        Ok(SimTimeConfig { enabled, instance })
+    }
+
+    /// The scope of the bridge: the bridges of a simulation instance route their data in a
+    /// sub-scope of their own.
+    pub fn scope(&self, scope: &str) -> String {
+        match &self.instance {
+            Some(instance) if self.enabled => format!("{}/{}", scope, instance),
+            _ => scope.to_string(),
+        }
+    }
+
+    /// The clock of the bridge.
+    pub fn clock(&self) -> Clock {
+        if self.enabled {
+            Clock::sim()
+        } else {
+            Clock::wall()
+        }
+    }
+}
+
+/// The time of the bridge: either the wall time or the simulation time of /clock.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    // the simulation time in nanoseconds, 0 until the first /clock sample
+    sim_time: Option<Arc<AtomicU64>>,
+}
+
+impl Clock {
+    pub fn wall() -> Self {
+        Clock::default()
+    }
+
+    pub fn sim() -> Self {
+        Clock {
+            sim_time: Some(Arc::new(AtomicU64::new(0))),
+        }
+    }
+
+    pub fn is_sim(&self) -> bool {
+        self.sim_time.is_some()
+    }
+
+    /// The current time, since the UNIX epoch for the wall time.
+    pub fn now(&self) -> Duration {
+        match &self.sim_time {
+            Some(t) => Duration::from_nanos(t.load(Ordering::Relaxed)),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Sets the time of a simulation clock, the wall time being left alone.
+    pub fn set(&self, time: Duration) {
+        if let Some(t) = &self.sim_time {
+            t.store(time.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for `duration` of this clock: a sleep in simulation time lasts while the simulation
+    /// is paused, and ends if it restarts.
+    pub async fn sleep(&self, duration: Duration) {
+        if !self.is_sim() {
+            return task::sleep(duration).await;
+        }
+        let start = self.now();
+        while !elapsed(start, self.now(), duration) {
+            task::sleep(SIM_POLL_PERIOD.min(duration)).await;
+        }
+    }
+}
+
+/// Returns true if `period` elapsed from `since` to `now`, or if the time went backwards,
+/// i.e. the simulation restarted.
+pub fn elapsed(since: Duration, now: Duration, period: Duration) -> bool {
+    now.checked_sub(since).map(|e| e >= period).unwrap_or(true)
+}
+
+// A rosgraph_msgs/msg/Clock: a builtin_interfaces/msg/Time
+#[derive(Deserialize)]
+struct ClockMsg {
+    sec: i32,
+    nanosec: u32,
+}
+
+/// A writer setting the time of a simulation clock to the /clock samples read on DDS.
+pub struct ClockWriter {
+    clock: Clock,
+}
+
+impl ClockWriter {
+    pub fn new(clock: Clock) -> Self {
+        ClockWriter { clock }
+    }
+}
+
+impl Writer for ClockWriter {
+    fn write(&self, buf: &[u8]) {
+        match cdr::deserialize_from::<_, ClockMsg, _>(buf, Infinite) {
+            Ok(c) => self
+                .clock
+                .set(Duration::new(c.sec.max(0) as u64, c.nanosec)),
+            Err(e) => warn!("Failed to decode a /clock sample: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_sim_clock() {
+        let clock = Clock::sim();
+        assert_eq!(clock.now(), Duration::from_secs(0));
+        ClockWriter::new(clock.clone())
+            .write(&[0x00, 0x01, 0x00, 0x00, 12, 0, 0, 0, 0, 0x65, 0xcd, 0x1d]);
+        assert_eq!(clock.now(), Duration::from_millis(12_500));
+        assert!(Clock::wall().now() > Duration::from_secs(1_600_000_000));
+
+        let (t0, t1) = (Duration::from_secs(10), Duration::from_secs(11));
+        assert!(elapsed(t0, t1, Duration::from_secs(1)));
+        assert!(!elapsed(t0, t1, Duration::from_secs(2)));
+        assert!(elapsed(t1, t0, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_sim_time_config() {
+        let config =
+            |yaml: &str| SimTimeConfig::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]);
+        let sim = config("enabled: true\ninstance: /world1\n").unwrap();
+        assert!(sim.clock().is_sim());
+        assert_eq!(sim.scope("/demo"), "/demo/world1");
+        let sim = config("enabled: false\ninstance: world1\n").unwrap();
+        assert!(!sim.clock().is_sim());
+        assert_eq!(sim.scope("/demo"), "/demo");
+        assert!(config("instance: 1\n").is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::policy::Direction;
+use crate::qos::{History, Qos, Reliability};
+use crate::ros2_names::{mangle_topic, mangle_type};
+use crate::{MatchedEntity, QosHolder};
+use cyclors::*;
+use yaml_rust::Yaml;
+
+// The participant the static routes are reported for, e.g. to the allow/deny policy
+pub const STATIC_PARTICIPANT: &str = "static";
+
+// The default max_blocking_time of the reliable DDS endpoints, as in Cyclone DDS
+const MAX_BLOCKING_TIME: dds_duration_t = 100_000_000;
+
+/// The QoS of a named profile, as the ROS 2 profiles of the same name:
+///  - "default": reliable, volatile, keep last 10
+///  - "sensor_data": best-effort, volatile, keep last 5
+///  - "latched": reliable, transient-local, keep last 1
+fn profile_qos(profile: &str) -> Result<Qos, String> {
+    let (reliability, durability, depth) = match profile {
+        "default" => (
+            dds_reliability_kind_DDS_RELIABILITY_RELIABLE,
+            dds_durability_kind_DDS_DURABILITY_VOLATILE,
+            10,
+        ),
+        "sensor_data" => (
+            dds_reliability_kind_DDS_RELIABILITY_BEST_EFFORT,
+            dds_durability_kind_DDS_DURABILITY_VOLATILE,
+            5,
+        ),
+        "latched" => (
+            dds_reliability_kind_DDS_RELIABILITY_RELIABLE,
+            dds_durability_kind_DDS_DURABILITY_TRANSIENT_LOCAL,
+            1,
+        ),
+        p => {
+            return Err(format!(
+                "Invalid QoS profile '{}', expected 'default', 'sensor_data' or 'latched'",
+                p
+            ))
+        }
+    };
+    Ok(Qos {
+        durability: Some(durability),
+        history: Some(History {
+            kind: dds_history_kind_DDS_HISTORY_KEEP_LAST,
+            depth,
+        }),
+        reliability: Some(Reliability {
+            kind: reliability,
+            max_blocking_time: MAX_BLOCKING_TIME,
+        }),
+        ..Default::default()
+    })
+}
+
+/// Parses a QoS: a named profile (see `profile_qos`), "default" if missing, possibly overridden
+/// by `reliability` (reliable | best_effort), `durability` (volatile | transient_local)
+/// and `history` (keep_all or a depth).
+fn qos_from_yaml(yaml: &Yaml) -> Result<Qos, String> {
+    let mut qos = profile_qos(yaml["profile"].as_str().unwrap_or("default"))?;
+    match yaml["reliability"].as_str() {
+        Some("reliable") => {
+            qos.reliability = Some(Reliability {
+                kind: dds_reliability_kind_DDS_RELIABILITY_RELIABLE,
+                max_blocking_time: MAX_BLOCKING_TIME,
+            })
+        }
+        Some("best_effort") => {
+            qos.reliability = Some(Reliability {
+                kind: dds_reliability_kind_DDS_RELIABILITY_BEST_EFFORT,
+                max_blocking_time: 0,
+            })
+        }
+        Some(r) => {
+            return Err(format!(
+                "Invalid reliability '{}', expected 'reliable' or 'best_effort'",
+                r
+            ))
+        }
+        None => (),
+    }
+    match yaml["durability"].as_str() {
+        Some("volatile") => qos.durability = Some(dds_durability_kind_DDS_DURABILITY_VOLATILE),
+        Some("transient_local") => {
+            qos.durability = Some(dds_durability_kind_DDS_DURABILITY_TRANSIENT_LOCAL)
+        }
+        Some(d) => {
+            return Err(format!(
+                "Invalid durability '{}', expected 'volatile' or 'transient_local'",
+                d
+            ))
+        }
+        None => (),
+    }
+    match &yaml["history"] {
+        Yaml::String(h) if h == "keep_all" => {
+            qos.history = Some(History {
+                kind: dds_history_kind_DDS_HISTORY_KEEP_ALL,
+                depth: 0,
+            })
+        }
+        Yaml::Integer(depth) if *depth > 0 => {
+            qos.history = Some(History {
+                kind: dds_history_kind_DDS_HISTORY_KEEP_LAST,
+                depth: *depth as i32,
+            })
+        }
+        Yaml::BadValue => (),
+        h => {
+            return Err(format!(
+                "Invalid history {:?}, expected 'keep_all' or a positive depth",
+                h
+            ))
+        }
+    }
+    Ok(qos)
+}
+
+/// A route declared in the configuration, created at startup whatever the discovered endpoints.
+#[derive(Debug, Clone)]
+pub struct StaticRoute {
+    // DdsToZenoh: the bridge creates a DDS reader on the topic, as for a discovered publication;
+    // ZenohToDds: the bridge creates a DDS writer on the topic, as for a discovered subscription
+    pub direction: Direction,
+    pub topic_name: String,
+    pub type_name: String,
+    pub keyless: bool,
+    pub partition: Option<String>,
+    pub qos: Qos,
+}
+
+impl StaticRoute {
+    fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let direction = match yaml["direction"].as_str() {
+            Some("dds_to_zenoh") => Direction::DdsToZenoh,
+            Some("zenoh_to_dds") => Direction::ZenohToDds,
+            Some(d) => return Err(format!("Invalid direction '{}' in static route", d)),
+            None => return Err(format!("Missing direction in static route {:?}", yaml)),
+        };
+        // The ROS 2 topics and types may be given in their ROS 2 form,
+        // e.g. "/scan" and "sensor_msgs/msg/LaserScan"
+        let topic_name = match yaml["topic"].as_str() {
+            Some(t) if t.starts_with('/') => mangle_topic(t),
+            Some(t) => t.to_string(),
+            None => return Err(format!("Missing topic in static route {:?}", yaml)),
+        };
+        let type_name = match yaml["type"].as_str() {
+            Some(t) if t.contains('/') => mangle_type(t),
+            Some(t) => t.to_string(),
+            None => return Err(format!("Missing type in static route {:?}", yaml)),
+        };
+        let keyless = yaml["keyless"].as_bool().unwrap_or(false);
+        let partition = yaml["partition"].as_str().map(String::from);
+        let mut qos = qos_from_yaml(&yaml["qos"])?;
+        if let Some(p) = &partition {
+            qos.partitions = Some(vec![p.clone()]);
+        }
+        Ok(StaticRoute {
+            direction,
+            topic_name,
+            type_name,
+            keyless,
+            partition,
+            qos,
+        })
+    }
+
+    /// The entity this route is created from, as if its endpoint had been discovered.
+    /// `key` identifies the endpoint, e.g. in the routes' endpoints.
+    pub fn to_matched_entity(&self, key: String) -> MatchedEntity {
+        let qos = QosHolder(self.qos.to_qos_native());
+        match self.direction {
+            Direction::DdsToZenoh => MatchedEntity::DiscoveredPublication {
+                key,
+                participant: STATIC_PARTICIPANT.to_string(),
+                topic_name: self.topic_name.clone(),
+                type_name: self.type_name.clone(),
+                keyless: self.keyless,
+                partition: self.partition.clone(),
+                qos,
+            },
+            Direction::ZenohToDds => MatchedEntity::DiscoveredSubscription {
+                key,
+                participant: STATIC_PARTICIPANT.to_string(),
+                topic_name: self.topic_name.clone(),
+                type_name: self.type_name.clone(),
+                keyless: self.keyless,
+                partition: self.partition.clone(),
+                qos,
+            },
+        }
+    }
+}
+
+/// The static routes of the configuration.
+#[derive(Debug, Default)]
+pub struct StaticRoutes {
+    pub routes: Vec<StaticRoute>,
+}
+
+impl StaticRoutes {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let mut routes = vec![];
+        for r in yaml.as_vec().map(|v| v.as_slice()).unwrap_or_default() {
+            routes.push(StaticRoute::from_yaml(r)?);
+        }
+        Ok(StaticRoutes { routes })
+    }
+
+    /// The entities the static routes are created from, the n-th route's endpoint being "static-<n>".
+    pub fn matched_entities(&self) -> Vec<MatchedEntity> {
+        self.routes
+            .iter()
+            .enumerate()
+            .map(|(i, r)| r.to_matched_entity(format!("{}-{}", STATIC_PARTICIPANT, i)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn routes(yaml: &str) -> Result<StaticRoutes, String> {
+        StaticRoutes::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    #[test]
+    fn test_static_routes() {
+        let r = routes(
+            r#"
+- direction: zenoh_to_dds
+  topic: rt/rosout
+  type: rcl_interfaces::msg::dds_::Log_
+  keyless: true
+  qos:
+    profile: latched
+    history: keep_all
+- direction: dds_to_zenoh
+  topic: /scan
+  type: sensor_msgs/msg/LaserScan
+  partition: robot1
+  qos:
+    profile: sensor_data
+    reliability: reliable
+"#,
+        )
+        .unwrap()
+        .routes;
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].direction, Direction::ZenohToDds);
+        assert!(r[0].keyless);
+        assert_eq!(
+            r[0].qos.durability,
+            Some(dds_durability_kind_DDS_DURABILITY_TRANSIENT_LOCAL)
+        );
+        assert_eq!(
+            r[0].qos.history.unwrap().kind,
+            dds_history_kind_DDS_HISTORY_KEEP_ALL
+        );
+        assert_eq!(r[1].direction, Direction::DdsToZenoh);
+        assert_eq!(r[1].topic_name, "rt/scan");
+        assert_eq!(r[1].type_name, "sensor_msgs::msg::dds_::LaserScan_");
+        assert!(!r[1].keyless);
+        assert_eq!(r[1].qos.history.unwrap().depth, 5);
+        assert_eq!(
+            r[1].qos.reliability.unwrap().kind,
+            dds_reliability_kind_DDS_RELIABILITY_RELIABLE
+        );
+        assert_eq!(r[1].qos.partitions, Some(vec![String::from("robot1")]));
+    }
+
+    #[test]
+    fn test_invalid_static_routes() {
+        assert!(routes("- topic: a\n  type: T\n").is_err());
+        assert!(routes("- direction: dds_to_zenoh\n  type: T\n").is_err());
+        assert!(routes("- direction: up\n  topic: a\n  type: T\n").is_err());
+        let with_qos = |qos: &str| {
+            routes(&format!(
+                "- direction: dds_to_zenoh\n  topic: a\n  type: T\n  qos:\n    {}\n",
+                qos
+            ))
+        };
+        assert!(with_qos("profile: fast").is_err());
+        assert!(with_qos("history: 0").is_err());
+        assert!(with_qos("durability: persistent").is_err());
+        assert!(with_qos("history: 3").is_ok());
+    }
+}
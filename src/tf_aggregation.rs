@@ -0,0 +1,341 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::coders::{SampleKind, SampleMeta, Writer};
+use crate::sim_clock::Clock;
+use crate::tf_coder::{TFMessage, TransformStamped, TF_MESSAGE_TYPE};
+use async_std::task;
+use cdr::{CdrLe, Infinite};
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use yaml_rust::Yaml;
+
+// The DDS topics of the dynamic and of the static transforms of ROS 2
+const TF_TOPIC: &str = "rt/tf";
+const TF_STATIC_TOPIC: &str = "rt/tf_static";
+
+/// The handling of the ROS 2 transforms routed from DDS to zenoh.
+#[derive(Debug, Default)]
+pub struct TfConfig {
+    // if true, the transforms of /tf_static are merged into one latched snapshot
+    pub aggregate_static: bool,
+    // if set, the transforms of /tf are batched, the latest of each frame pair being sent once per period
+    pub batch_period: Option<Duration>,
+}
+
+/// How the samples of a topic of transforms are routed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TfHandling {
+    AggregateStatic,
+    Batch(Duration),
+}
+
+impl TfConfig {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let aggregate_static = match &yaml["aggregate_static"] {
+            Yaml::Boolean(b) => *b,
+            Yaml::BadValue => false,
+            v => {
+                return Err(format!(
+                    "Invalid aggregate_static {:?}: a boolean is expected",
+                    v
+                ))
+            }
+        };
+        let batch_period = match &yaml["max_rate"] {
+            Yaml::BadValue => None,
+            r => match r.as_f64().or_else(|| r.as_i64().map(|i| i as f64)) {
+                Some(r) if r > 0.0 => Some(Duration::from_secs_f64(1.0 / r)),
+                _ => {
+                    return Err(format!(
+                        "Invalid max_rate {:?} for /tf: a positive number of Hz is expected",
+                        r
+                    ))
+                }
+            },
+        };
+        Ok(TfConfig {
+            aggregate_static,
+            batch_period,
+        })
+    }
+
+    /// The handling of the DDS topic `topic_name`, if it is one of the topics of the transforms
+    /// and their handling is configured.
+    pub fn handling(&self, topic_name: &str, type_name: &str) -> Option<TfHandling> {
+        if type_name != TF_MESSAGE_TYPE {
+            return None;
+        }
+        match topic_name {
+            TF_STATIC_TOPIC if self.aggregate_static => Some(TfHandling::AggregateStatic),
+            TF_TOPIC => self.batch_period.map(TfHandling::Batch),
+            _ => None,
+        }
+    }
+}
+
+/// The static transforms received so far, the latest of each child frame: a frame has a single parent.
+#[derive(Debug, Default)]
+struct TfSnapshot {
+    transforms: BTreeMap<String, TransformStamped>,
+}
+
+impl TfSnapshot {
+    /// Merges the transforms of `msg`, returning true if any of them is new.
+    fn merge(&mut self, msg: TFMessage) -> bool {
+        let mut changed = false;
+        for t in msg.transforms {
+            if self.transforms.get(&t.child_frame_id) != Some(&t) {
+                self.transforms.insert(t.child_frame_id.clone(), t);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn message(&self) -> TFMessage {
+        TFMessage {
+            transforms: self.transforms.values().cloned().collect(),
+        }
+    }
+}
+
+/// The transforms received since the last batch, the latest of each frame pair.
+#[derive(Debug, Default)]
+struct TfBatch {
+    transforms: BTreeMap<(String, String), TransformStamped>,
+    // the metadata of the latest sample
+    meta: Option<SampleMeta>,
+}
+
+impl TfBatch {
+    fn insert(&mut self, msg: TFMessage, meta: &SampleMeta) {
+        for t in msg.transforms {
+            self.transforms
+                .insert((t.header.frame_id.clone(), t.child_frame_id.clone()), t);
+        }
+        self.meta = Some(meta.clone());
+    }
+
+    fn take(&mut self) -> Option<(TFMessage, SampleMeta)> {
+        let meta = self.meta.take()?;
+        let transforms = std::mem::take(&mut self.transforms);
+        Some((
+            TFMessage {
+                transforms: transforms.into_iter().map(|(_, t)| t).collect(),
+            },
+            meta,
+        ))
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<TFMessage> {
+    match cdr::deserialize_from::<_, TFMessage, _>(buf, Infinite) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            warn!("Failed to decode a sample of transforms: {}", e);
+            None
+        }
+    }
+}
+
+/// A writer merging the transforms of the /tf_static samples into one snapshot, written each
+/// time it changes: the last sample routed, and kept for the late-joining remote readers,
+/// then holds all the static transforms whatever the nodes that published them.
+/// The static transforms are kept when their publishers leave, hence their changes of state
+/// aren't routed.
+pub struct TfStaticAggregator {
+    writer: Box<dyn Writer + Send>,
+    snapshot: Mutex<TfSnapshot>,
+}
+
+impl TfStaticAggregator {
+    pub fn new(writer: Box<dyn Writer + Send>) -> Self {
+        TfStaticAggregator {
+            writer,
+            snapshot: Mutex::new(TfSnapshot::default()),
+        }
+    }
+}
+
+impl Writer for TfStaticAggregator {
+    fn write(&self, buf: &[u8]) {
+        self.write_with(buf, &SampleMeta::default());
+    }
+
+    fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+        if meta.kind != SampleKind::Put {
+            return;
+        }
+        let msg = match decode(buf) {
+            Some(msg) => msg,
+            None => return self.writer.write_with(buf, meta),
+        };
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if snapshot.merge(msg) {
+            let data = cdr::serialize::<_, _, CdrLe>(&snapshot.message(), Infinite).unwrap();
+            drop(snapshot);
+            self.writer.write_with(&data, meta);
+        }
+    }
+}
+
+struct TfBatcherInner {
+    writer: Box<dyn Writer + Send + Sync>,
+    batch: Mutex<TfBatch>,
+}
+
+impl TfBatcherInner {
+    fn flush(&self) {
+        let batch = self.batch.lock().unwrap().take();
+        if let Some((msg, meta)) = batch {
+            let data = cdr::serialize::<_, _, CdrLe>(&msg, Infinite).unwrap();
+            self.writer.write_with(&data, &meta);
+        }
+    }
+}
+
+/// A writer batching the transforms of the /tf samples: the latest transform of each frame pair
+/// received during a period of `clock` is sent at its end, in a single sample.
+pub struct TfBatcher {
+    inner: Arc<TfBatcherInner>,
+}
+
+impl TfBatcher {
+    pub fn new(writer: Box<dyn Writer + Send + Sync>, period: Duration, clock: Clock) -> Self {
+        let inner = Arc::new(TfBatcherInner {
+            writer,
+            batch: Mutex::new(TfBatch::default()),
+        });
+        let weak: Weak<TfBatcherInner> = Arc::downgrade(&inner);
+        task::spawn(async move {
+            loop {
+                clock.sleep(period).await;
+                match weak.upgrade() {
+                    Some(inner) => inner.flush(),
+                    None => break,
+                }
+            }
+        });
+        TfBatcher { inner }
+    }
+}
+
+impl Writer for TfBatcher {
+    fn write(&self, buf: &[u8]) {
+        self.write_with(buf, &SampleMeta::default());
+    }
+
+    fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+        if meta.kind != SampleKind::Put {
+            return self.inner.writer.write_with(buf, meta);
+        }
+        match decode(buf) {
+            Some(msg) => self.inner.batch.lock().unwrap().insert(msg, meta),
+            None => self.inner.writer.write_with(buf, meta),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tf_coder::{Header, Quaternion, Time, Transform, Vector3};
+    use yaml_rust::YamlLoader;
+
+    fn transform(frame_id: &str, child_frame_id: &str, x: f64) -> TransformStamped {
+        TransformStamped {
+            header: Header {
+                stamp: Time { sec: 0, nanosec: 0 },
+                frame_id: frame_id.to_string(),
+            },
+            child_frame_id: child_frame_id.to_string(),
+            transform: Transform {
+                translation: Vector3 { x, y: 0.0, z: 0.0 },
+                rotation: Quaternion {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                    w: 1.0,
+                },
+            },
+        }
+    }
+
+    fn message(transforms: Vec<TransformStamped>) -> TFMessage {
+        TFMessage { transforms }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = TfSnapshot::default();
+        assert!(snapshot.merge(message(vec![transform("base_link", "laser", 1.0)])));
+        assert!(snapshot.merge(message(vec![transform("base_link", "camera", 2.0)])));
+        assert!(!snapshot.merge(message(vec![transform("base_link", "laser", 1.0)])));
+        assert!(snapshot.merge(message(vec![transform("base_link", "laser", 3.0)])));
+        assert_eq!(
+            snapshot.message(),
+            message(vec![
+                transform("base_link", "camera", 2.0),
+                transform("base_link", "laser", 3.0)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut batch = TfBatch::default();
+        assert!(batch.take().is_none());
+        let meta = SampleMeta::default();
+        batch.insert(
+            message(vec![
+                transform("odom", "base_link", 1.0),
+                transform("map", "odom", 2.0),
+            ]),
+            &meta,
+        );
+        batch.insert(message(vec![transform("odom", "base_link", 3.0)]), &meta);
+        let (msg, _) = batch.take().unwrap();
+        assert_eq!(
+            msg,
+            message(vec![
+                transform("map", "odom", 2.0),
+                transform("odom", "base_link", 3.0)
+            ])
+        );
+        assert!(batch.take().is_none());
+    }
+
+    #[test]
+    fn test_config() {
+        let config = |yaml: &str| TfConfig::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]);
+        let tf = config("aggregate_static: true\nmax_rate: 20\n").unwrap();
+        assert_eq!(
+            tf.handling("rt/tf_static", TF_MESSAGE_TYPE),
+            Some(TfHandling::AggregateStatic)
+        );
+        assert_eq!(
+            tf.handling("rt/tf", TF_MESSAGE_TYPE),
+            Some(TfHandling::Batch(Duration::from_millis(50)))
+        );
+        assert_eq!(tf.handling("rt/tf", "std_msgs::msg::dds_::String_"), None);
+        assert_eq!(tf.handling("rt/odom", TF_MESSAGE_TYPE), None);
+        let tf = config("aggregate_static: false\n").unwrap();
+        assert_eq!(tf.handling("rt/tf_static", TF_MESSAGE_TYPE), None);
+        assert_eq!(tf.handling("rt/tf", TF_MESSAGE_TYPE), None);
+        assert!(config("max_rate: 0\n").is_err());
+        assert!(config("aggregate_static: 1\n").is_err());
+    }
+}
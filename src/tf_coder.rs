@@ -0,0 +1,425 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::coders::{Coder, SampleKind, SampleMeta, Writer};
+use crate::type_registry::{TypeRegistry, Value};
+use cdr::{CdrBe, CdrLe, Infinite};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub const TF_MESSAGE_TYPE: &str = "tf2_msgs::msg::dds_::TFMessage_";
+// The messages whose Header is directly followed by a child_frame_id
+const CHILD_FRAME_TYPES: [&str; 2] = [
+    "nav_msgs::msg::dds_::Odometry_",
+    "geometry_msgs::msg::dds_::TransformStamped_",
+];
+// The fields of the frames in the messages of the registered types, at any depth
+const FRAME_FIELDS: [&str; 2] = ["frame_id", "child_frame_id"];
+
+// The CDR encapsulation header, whose second byte tells the endianness of the data
+const ENCAPSULATION_LEN: usize = 4;
+// A Header starts with its stamp (sec: i32, nanosec: u32), followed by its frame_id
+const FRAME_ID_OFFSET: usize = ENCAPSULATION_LEN + 8;
+// The max alignment of the CDR primitive types
+const MAX_ALIGNMENT: usize = 8;
+
+// The messages of the transforms, as tf2_msgs/msg/TFMessage
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Time {
+    pub sec: i32,
+    pub nanosec: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Header {
+    pub stamp: Time,
+    pub frame_id: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Vector3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Quaternion {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub w: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Transform {
+    pub translation: Vector3,
+    pub rotation: Quaternion,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TransformStamped {
+    pub header: Header,
+    pub child_frame_id: String,
+    pub transform: Transform,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TFMessage {
+    pub transforms: Vec<TransformStamped>,
+}
+
+#[derive(Debug, Clone)]
+enum FramesLayout {
+    // a tf2_msgs/TFMessage, deserialized as a whole
+    TfMessage,
+    // a message of a type known to the registry, decoded as a whole and serialized again
+    Registered {
+        type_name: String,
+        types: Arc<TypeRegistry>,
+    },
+    // a message starting with a Header, possibly followed by a child_frame_id: only its frames
+    // are rewritten, the rest of the message being kept as is
+    Header {
+        child_frame: bool,
+    },
+}
+
+/// The prefix of the frames of a robot, e.g. "robot7/" in "robot7/base_link", and where the frames
+/// are in the messages of a type.
+#[derive(Debug, Clone)]
+pub struct FramePrefix {
+    prefix: String,
+    layout: FramesLayout,
+}
+
+impl FramePrefix {
+    /// The prefix of the frames of the messages of type `type_name`. Unless it is a
+    /// TFMessage or a type known to `types`, the prefix with its '/' must be a multiple of the
+    /// max CDR alignment long, for the alignment of the rest of the messages to be kept.
+    pub fn new(prefix: &str, type_name: &str, types: &Arc<TypeRegistry>) -> Result<Self, String> {
+        let prefix = prefix.trim_matches('/');
+        if prefix.is_empty() {
+            return Err(String::from("Missing frame prefix"));
+        }
+        let prefix = format!("{}/", prefix);
+        let layout = if type_name == TF_MESSAGE_TYPE {
+            FramesLayout::TfMessage
+        } else if types.get(type_name).is_some() {
+            FramesLayout::Registered {
+                type_name: type_name.to_string(),
+                types: types.clone(),
+            }
+        } else {
+            if prefix.len() % MAX_ALIGNMENT != 0 {
+                return Err(format!(
+                    "The frame prefix '{}' of the {} messages must be a multiple of {} bytes long, \
+                     unless their type is loaded with the types of the bridge configuration",
+                    prefix, type_name, MAX_ALIGNMENT
+                ));
+            }
+            FramesLayout::Header {
+                child_frame: CHILD_FRAME_TYPES.contains(&type_name),
+            }
+        };
+        Ok(FramePrefix { prefix, layout })
+    }
+
+    fn add(&self, frame: &str) -> String {
+        if frame.is_empty() || frame.starts_with(&self.prefix) {
+            frame.to_string()
+        } else {
+            format!("{}{}", self.prefix, frame)
+        }
+    }
+
+    fn strip(&self, frame: &str) -> String {
+        frame
+            .strip_prefix(&self.prefix)
+            .unwrap_or(frame)
+            .to_string()
+    }
+
+    /// The message `data` with each of its frames rewritten by `f`, or None if it is malformed.
+    fn rewrite<F: Fn(&str) -> String>(&self, data: &[u8], f: F) -> Option<Vec<u8>> {
+        match &self.layout {
+            FramesLayout::TfMessage => rewrite_tf_message(data, f),
+            FramesLayout::Registered { type_name, types } => {
+                let mut msg = types.decode(type_name, data).ok()?;
+                rewrite_frames(&mut msg, &f);
+                types.encode(type_name, &msg).ok()
+            }
+            FramesLayout::Header { child_frame } => {
+                rewrite_header(data, if *child_frame { 2 } else { 1 }, f)
+            }
+        }
+    }
+}
+
+fn is_little_endian(data: &[u8]) -> bool {
+    data.get(1).map(|b| b & 1 == 1).unwrap_or(true)
+}
+
+fn rewrite_tf_message<F: Fn(&str) -> String>(data: &[u8], f: F) -> Option<Vec<u8>> {
+    let mut msg = cdr::deserialize_from::<_, TFMessage, _>(data, Infinite).ok()?;
+    for t in msg.transforms.iter_mut() {
+        t.header.frame_id = f(&t.header.frame_id);
+        t.child_frame_id = f(&t.child_frame_id);
+    }
+    if is_little_endian(data) {
+        cdr::serialize::<_, _, CdrLe>(&msg, Infinite).ok()
+    } else {
+        cdr::serialize::<_, _, CdrBe>(&msg, Infinite).ok()
+    }
+}
+
+// Rewrites the frames of a decoded message, and of the structures it contains
+fn rewrite_frames<F: Fn(&str) -> String>(value: &mut Value, f: &F) {
+    match value {
+        Value::Struct(fields) => {
+            for (name, v) in fields.iter_mut() {
+                match v {
+                    Value::String(frame) if FRAME_FIELDS.contains(&name.as_str()) => {
+                        *frame = f(frame)
+                    }
+                    v => rewrite_frames(v, f),
+                }
+            }
+        }
+        Value::Array(elements) => {
+            for e in elements.iter_mut() {
+                rewrite_frames(e, f);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Rewrites the `count` consecutive strings following the stamp of the Header starting the message.
+// The lengths of the strings must change by multiples of the max alignment, for the alignment of
+// the following data to be kept.
+fn rewrite_header<F: Fn(&str) -> String>(data: &[u8], count: usize, f: F) -> Option<Vec<u8>> {
+    let le = is_little_endian(data);
+    let read_u32 = |pos: usize| -> Option<usize> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(data.get(pos..pos + 4)?);
+        Some(if le {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        } as usize)
+    };
+    let mut out = data.get(..FRAME_ID_OFFSET)?.to_vec();
+    let mut pos = FRAME_ID_OFFSET;
+    for _ in 0..count {
+        // the length of a string is aligned on 4 bytes, relatively to the end of the encapsulation
+        let aligned = ENCAPSULATION_LEN + (pos - ENCAPSULATION_LEN + 3) / 4 * 4;
+        out.extend_from_slice(data.get(pos..aligned)?);
+        let len = read_u32(aligned)?;
+        // the length includes the terminating NUL
+        let bytes = data.get(aligned + 4..aligned + 4 + len.checked_sub(1)?)?;
+        let frame = f(std::str::from_utf8(bytes).ok()?);
+        if (frame.len() as isize - bytes.len() as isize) % MAX_ALIGNMENT as isize != 0 {
+            return None;
+        }
+        let new_len = frame.len() as u32 + 1;
+        out.extend_from_slice(&if le {
+            new_len.to_le_bytes()
+        } else {
+            new_len.to_be_bytes()
+        });
+        out.extend_from_slice(frame.as_bytes());
+        out.push(0);
+        pos = aligned + 4 + len;
+    }
+    out.extend_from_slice(data.get(pos..)?);
+    Some(out)
+}
+
+/// A coder prefixing the frames of the transforms and of the headers of the messages of a robot
+/// on their way from DDS to zenoh, and removing that prefix on their way back to DDS, for the
+/// frames of several robots not to collide in a same ROS 2 system.
+pub struct TfCoder {
+    writer: Box<dyn Writer + Send>,
+    prefix: FramePrefix,
+}
+
+impl TfCoder {
+    pub fn new(writer: Box<dyn Writer + Send>, prefix: FramePrefix) -> Self {
+        TfCoder { writer, prefix }
+    }
+
+    fn write<F: Fn(&str) -> String>(&self, data: Vec<u8>, meta: Option<&SampleMeta>, f: F) {
+        // the samples without data only carry the key of their instance
+        let data = match meta {
+            Some(m) if m.kind != SampleKind::Put => data,
+            _ => match self.prefix.rewrite(&data, f) {
+                Some(rewritten) => rewritten,
+                // forwarded as is, its frames would collide with the ones of the other robots
+                None => {
+                    warn!("[tf coder] Failed to rewrite the frames of a sample, dropping it");
+                    return;
+                }
+            },
+        };
+        match meta {
+            Some(meta) => self.writer.write_with(&data, meta),
+            None => self.writer.write(&data),
+        }
+    }
+}
+
+impl Coder for TfCoder {
+    fn encode(&self, data: Vec<u8>) {
+        self.write(data, None, |frame| self.prefix.add(frame));
+    }
+
+    fn decode(&self, data: Vec<u8>) {
+        self.write(data, None, |frame| self.prefix.strip(frame));
+    }
+
+    fn encode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        self.write(data, Some(meta), |frame| self.prefix.add(frame));
+    }
+
+    fn decode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        self.write(data, Some(meta), |frame| self.prefix.strip(frame));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(frame_id: &str, child_frame_id: &str) -> TransformStamped {
+        TransformStamped {
+            header: Header {
+                stamp: Time { sec: 1, nanosec: 2 },
+                frame_id: frame_id.to_string(),
+            },
+            child_frame_id: child_frame_id.to_string(),
+            transform: Transform {
+                translation: Vector3 {
+                    x: 1.0,
+                    y: 2.0,
+                    z: 3.0,
+                },
+                rotation: Quaternion {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                    w: 1.0,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_tf_message() {
+        let prefix = FramePrefix::new("/robot7", TF_MESSAGE_TYPE, &Arc::default()).unwrap();
+        let msg = TFMessage {
+            transforms: vec![transform("odom", "base_link"), transform("", "map")],
+        };
+        let data = cdr::serialize::<_, _, CdrLe>(&msg, Infinite).unwrap();
+        let out = prefix.rewrite(&data, |f| prefix.add(f)).unwrap();
+        let prefixed = cdr::deserialize_from::<_, TFMessage, _>(out.as_slice(), Infinite).unwrap();
+        assert_eq!(
+            prefixed.transforms[0],
+            transform("robot7/odom", "robot7/base_link")
+        );
+        assert_eq!(prefixed.transforms[1], transform("", "robot7/map"));
+        assert_eq!(prefix.rewrite(&out, |f| prefix.strip(f)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_header() {
+        let types = Arc::default();
+        assert!(FramePrefix::new("robot7", "nav_msgs::msg::dds_::Odometry_", &types).is_err());
+        assert!(FramePrefix::new("/", TF_MESSAGE_TYPE, &types).is_err());
+        let prefix = FramePrefix::new("robot07", "nav_msgs::msg::dds_::Odometry_", &types).unwrap();
+        // an Odometry: header {stamp, frame_id: "odom"}, child_frame_id: "base", then a f64
+        let mut data = vec![0x00, 0x01, 0x00, 0x00, 1, 0, 0, 0, 2, 0, 0, 0];
+        data.extend_from_slice(&[5, 0, 0, 0, b'o', b'd', b'o', b'm', 0, 0, 0, 0]);
+        data.extend_from_slice(&[5, 0, 0, 0, b'b', b'a', b's', b'e', 0, 0, 0, 0]);
+        data.extend_from_slice(&1.5f64.to_le_bytes());
+        let out = prefix.rewrite(&data, |f| prefix.add(f)).unwrap();
+        assert_eq!(out.len(), data.len() + 16);
+        assert_eq!(&out[12..16], &[13, 0, 0, 0]);
+        assert_eq!(&out[16..29], b"robot07/odom\0");
+        assert_eq!(&out[32..36], &[13, 0, 0, 0]);
+        assert_eq!(&out[36..49], b"robot07/base\0");
+        assert_eq!(&out[out.len() - 8..], &1.5f64.to_le_bytes());
+        assert_eq!(prefix.rewrite(&out, |f| prefix.strip(f)).unwrap(), data);
+        assert_eq!(prefix.rewrite(&data[..20], |f| prefix.add(f)), None);
+    }
+
+    #[test]
+    fn test_registered() {
+        let mut types = TypeRegistry::default();
+        types
+            .add_msg("builtin_interfaces", "Time", "int32 sec\nuint32 nanosec\n")
+            .unwrap();
+        types
+            .add_msg(
+                "std_msgs",
+                "Header",
+                "builtin_interfaces/Time stamp\nstring frame_id\n",
+            )
+            .unwrap();
+        types
+            .add_msg(
+                "demo",
+                "Poses",
+                "std_msgs/Header header\nstring child_frame_id\nfloat64 x\nstd_msgs/Header[] others\n",
+            )
+            .unwrap();
+        let types = Arc::new(types);
+        let header = |frame: &str| {
+            Value::Struct(vec![
+                (
+                    String::from("stamp"),
+                    Value::Struct(vec![
+                        (String::from("sec"), Value::I32(1)),
+                        (String::from("nanosec"), Value::U32(2)),
+                    ]),
+                ),
+                (String::from("frame_id"), Value::String(frame.to_string())),
+            ])
+        };
+        let poses = |frame: &str, child: &str, other: &str| {
+            Value::Struct(vec![
+                (String::from("header"), header(frame)),
+                (
+                    String::from("child_frame_id"),
+                    Value::String(child.to_string()),
+                ),
+                (String::from("x"), Value::F64(1.5)),
+                (String::from("others"), Value::Array(vec![header(other)])),
+            ])
+        };
+        let data = types
+            .encode("demo/msg/Poses", &poses("odom", "base_link", "map"))
+            .unwrap();
+
+        // the prefix isn't a multiple of 8 bytes long: the message is serialized again
+        let prefix = FramePrefix::new("robot7", "demo::msg::dds_::Poses_", &types).unwrap();
+        let out = prefix.rewrite(&data, |f| prefix.add(f)).unwrap();
+        assert_eq!(
+            types.decode("demo/msg/Poses", &out).unwrap(),
+            poses("robot7/odom", "robot7/base_link", "robot7/map")
+        );
+        assert_eq!(prefix.rewrite(&out, |f| prefix.strip(f)).unwrap(), data);
+        assert_eq!(prefix.rewrite(&data[..20], |f| prefix.add(f)), None);
+    }
+}
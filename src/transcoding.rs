@@ -0,0 +1,283 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::cbor::{from_cbor, to_cbor};
+use crate::coders::{Coder, SampleKind, SampleMeta, Writer};
+use crate::json::{from_json, to_json};
+use crate::protobuf::{from_protobuf, to_protobuf};
+use crate::type_registry::{TypeRegistry, Value};
+use log::warn;
+use std::sync::Arc;
+use zenoh::net::{encoding, ZInt};
+
+/// The format of the samples on zenoh into which a coder transcodes their CDR.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireFormat {
+    Json,
+    Cbor,
+    // as described by the schema generated with protobuf::proto_schema()
+    Protobuf,
+}
+
+impl WireFormat {
+    /// The format of a coder of the coders configuration, e.g. "json".
+    pub fn from_coder(coder: &str) -> Option<Self> {
+        match coder {
+            "json" => Some(WireFormat::Json),
+            "cbor" => Some(WireFormat::Cbor),
+            "protobuf" => Some(WireFormat::Protobuf),
+            _ => None,
+        }
+    }
+
+    // The zenoh encoding of the samples: the zenoh version used by the bridge has none for CBOR
+    // and Protobuf
+    fn encoding(self) -> ZInt {
+        match self {
+            WireFormat::Json => encoding::APP_JSON,
+            WireFormat::Cbor | WireFormat::Protobuf => encoding::APP_CUSTOM,
+        }
+    }
+
+    fn write(
+        self,
+        types: &TypeRegistry,
+        type_name: &str,
+        value: &Value,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => Ok(serde_json::to_vec(&to_json(value)).unwrap()),
+            WireFormat::Cbor => Ok(to_cbor(value)),
+            WireFormat::Protobuf => to_protobuf(types, type_name, value),
+        }
+    }
+
+    fn read(self, types: &TypeRegistry, type_name: &str, data: &[u8]) -> Result<Value, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(data)
+                .map_err(|e| e.to_string())
+                .and_then(|json| from_json(types, type_name, &json)),
+            WireFormat::Cbor => from_cbor(types, type_name, data),
+            WireFormat::Protobuf => from_protobuf(types, type_name, data),
+        }
+    }
+}
+
+/// A coder transcoding the CDR samples of a registered type to another format on their way to
+/// zenoh, published with the encoding of that format, and the samples received from zenoh back
+/// to CDR. The samples without data only carry the key of their instance, and are left as is.
+pub struct TranscodingCoder {
+    writer: Box<dyn Writer + Send>,
+    type_name: String,
+    types: Arc<TypeRegistry>,
+    format: WireFormat,
+}
+
+impl TranscodingCoder {
+    pub fn new(
+        writer: Box<dyn Writer + Send>,
+        type_name: &str,
+        types: Arc<TypeRegistry>,
+        format: WireFormat,
+    ) -> Self {
+        TranscodingCoder {
+            writer,
+            type_name: type_name.to_string(),
+            types,
+            format,
+        }
+    }
+}
+
+impl Coder for TranscodingCoder {
+    fn encode(&self, data: Vec<u8>) {
+        self.encode_with(data, &SampleMeta::default());
+    }
+
+    fn decode(&self, data: Vec<u8>) {
+        self.decode_with(data, &SampleMeta::default());
+    }
+
+    fn encode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        if meta.kind != SampleKind::Put {
+            return self.writer.write_with(&data, meta);
+        }
+        let transcoded = self
+            .types
+            .decode(&self.type_name, &data)
+            .and_then(|value| self.format.write(&self.types, &self.type_name, &value));
+        match transcoded {
+            Ok(transcoded) => {
+                let meta = SampleMeta {
+                    encoding: Some(self.format.encoding()),
+                    ..meta.clone()
+                };
+                self.writer.write_with(&transcoded, &meta);
+            }
+            Err(e) => warn!(
+                "[transcoding] Failed to transcode a {} to {:?}: {}",
+                self.type_name, self.format, e
+            ),
+        }
+    }
+
+    fn decode_with(&self, data: Vec<u8>, meta: &SampleMeta) {
+        if meta.kind != SampleKind::Put {
+            return self.writer.write_with(&data, meta);
+        }
+        let cdr = self
+            .format
+            .read(&self.types, &self.type_name, &data)
+            .and_then(|value| self.types.encode(&self.type_name, &value));
+        match cdr {
+            Ok(cdr) => self.writer.write_with(&cdr, meta),
+            Err(e) => warn!(
+                "[transcoding] Failed to transcode a {} from {:?}: {}",
+                self.type_name, self.format, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_registry::{BaseType, Multiplicity};
+    use std::sync::Mutex;
+
+    // the samples written, with their encoding
+    type Written = Arc<Mutex<Vec<(Vec<u8>, Option<ZInt>)>>>;
+
+    struct Samples(Written);
+
+    impl Writer for Samples {
+        fn write(&self, buf: &[u8]) {
+            self.write_with(buf, &SampleMeta::default());
+        }
+
+        fn write_with(&self, buf: &[u8], meta: &SampleMeta) {
+            self.0.lock().unwrap().push((buf.to_vec(), meta.encoding));
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let mut types = TypeRegistry::default();
+        types
+            .add_idl(
+                r#"
+module demo { module msg {
+  struct Reading {
+    boolean ok; int8 a; uint8 b; int16 c; uint16 d; long e; unsigned long f;
+    long long g; unsigned long long h; float i; double j; string k;
+    sequence<octet> data; int16 pair[2]; sequence<demo::msg::Reading, 1> next;
+  };
+}; };
+"#,
+            )
+            .unwrap();
+        let nested = types
+            .default_value(
+                &BaseType::Message(String::from("demo/msg/Reading")),
+                Multiplicity::Single,
+            )
+            .unwrap();
+        let value = Value::Struct(vec![
+            (String::from("ok"), Value::Bool(true)),
+            (String::from("a"), Value::I8(i8::MIN)),
+            (String::from("b"), Value::U8(u8::MAX)),
+            (String::from("c"), Value::I16(i16::MIN)),
+            (String::from("d"), Value::U16(u16::MAX)),
+            (String::from("e"), Value::I32(i32::MIN)),
+            (String::from("f"), Value::U32(u32::MAX)),
+            (String::from("g"), Value::I64(i64::MIN)),
+            (String::from("h"), Value::U64(u64::MAX)),
+            (String::from("i"), Value::F32(f32::from_bits(0x7fc0_0001))),
+            (String::from("j"), Value::F64(-0.0)),
+            (String::from("k"), Value::String(String::from("hé"))),
+            (
+                String::from("data"),
+                Value::Array(vec![Value::U8(0), Value::U8(255)]),
+            ),
+            (
+                String::from("pair"),
+                Value::Array(vec![Value::I16(-2), Value::I16(i16::MAX)]),
+            ),
+            (String::from("next"), Value::Array(vec![nested])),
+        ]);
+        let types = Arc::new(types);
+        let cdr = types.encode("demo/msg/Reading", &value).unwrap();
+
+        // the NaN payloads and -0.0 are kept, unlike in JSON
+        for (format, encoding) in [
+            (WireFormat::Cbor, encoding::APP_CUSTOM),
+            (WireFormat::Protobuf, encoding::APP_CUSTOM),
+        ]
+        .iter()
+        {
+            let samples = Arc::new(Mutex::new(vec![]));
+            let encoder = TranscodingCoder::new(
+                Box::new(Samples(samples.clone())),
+                "demo::msg::dds_::Reading_",
+                types.clone(),
+                *format,
+            );
+            encoder.encode(cdr.clone());
+            let (transcoded, e) = samples.lock().unwrap().remove(0);
+            assert_eq!(e, Some(*encoding));
+            encoder.decode(transcoded);
+            assert_eq!(samples.lock().unwrap().remove(0).0, cdr, "{:?}", format);
+
+            encoder.decode(vec![0xff]);
+            assert!(samples.lock().unwrap().is_empty());
+        }
+
+        // JSON, with values it can represent
+        let mut value = value;
+        *value.field_mut("i").unwrap() = Value::F32(0.1);
+        *value.field_mut("j").unwrap() = Value::F64(-2.5);
+        let cdr = types.encode("demo/msg/Reading", &value).unwrap();
+        let samples = Arc::new(Mutex::new(vec![]));
+        let encoder = TranscodingCoder::new(
+            Box::new(Samples(samples.clone())),
+            "demo::msg::dds_::Reading_",
+            types.clone(),
+            WireFormat::Json,
+        );
+        encoder.encode(cdr.clone());
+        let (json, e) = samples.lock().unwrap().remove(0);
+        assert_eq!(e, Some(encoding::APP_JSON));
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["i"], serde_json::json!(0.1));
+        assert_eq!(json["k"], serde_json::json!("hé"));
+        encoder.decode_with(serde_json::to_vec(&json).unwrap(), &SampleMeta::default());
+        assert_eq!(samples.lock().unwrap().remove(0).0, cdr);
+        encoder.decode(b"{\"unknown\": 1}".to_vec());
+        assert!(samples.lock().unwrap().is_empty());
+
+        // the disposals only carry the key of their instance, in CDR
+        let dispose = SampleMeta {
+            kind: SampleKind::Dispose,
+            ..Default::default()
+        };
+        encoder.encode_with(vec![0x00, 0x01, 0x00, 0x00, 7], &dispose);
+        encoder.decode_with(vec![0x00, 0x01, 0x00, 0x00, 8], &dispose);
+        assert_eq!(
+            *samples.lock().unwrap(),
+            vec![
+                (vec![0x00, 0x01, 0x00, 0x00, 7], None),
+                (vec![0x00, 0x01, 0x00, 0x00, 8], None)
+            ]
+        );
+    }
+}
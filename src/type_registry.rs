@@ -0,0 +1,1340 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::ros2_names::demangle_type;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use yaml_rust::Yaml;
+
+// The CDR encapsulation header, whose second byte tells the endianness of the data
+const ENCAPSULATION_LEN: usize = 4;
+// The encapsulation kinds of plain CDR, big and little-endian: the others (e.g. XCDR2) differ
+// in their alignment and headers
+const CDR_BE_KIND: u8 = 0x00;
+const CDR_LE_KIND: u8 = 0x01;
+// The encapsulation of the samples encoded by the registry: little-endian plain CDR
+const CDR_LE: [u8; 4] = [0x00, 0x01, 0x00, 0x00];
+// The max nesting of the structures, against the recursive definitions
+const MAX_DEPTH: usize = 64;
+
+/// The type of a field, or of the elements of an array or sequence field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaseType {
+    Bool,
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    String,
+    // a structure, by its ROS 2 name, e.g. "std_msgs/msg/Header"
+    Message(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Multiplicity {
+    Single,
+    Array(usize),
+    // a sequence, possibly bounded
+    Sequence(Option<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub base: BaseType,
+    pub multiplicity: Multiplicity,
+}
+
+/// A structure: a message, or the request or the response of a service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageType {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+/// A value of a registered type, the structures keeping their fields in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Array(Vec<Value>),
+    Struct(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The field of a structure by its path, e.g. "header.frame_id".
+    pub fn field(&self, path: &str) -> Option<&Value> {
+        path.split('.').try_fold(self, |v, name| match v {
+            Value::Struct(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    /// The field of a structure by its path, to be modified.
+    pub fn field_mut(&mut self, path: &str) -> Option<&mut Value> {
+        path.split('.').try_fold(self, |v, name| match v {
+            Value::Struct(fields) => fields.iter_mut().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+}
+
+// The ROS 2 primitive types of the .msg files
+fn msg_primitive(name: &str) -> Option<BaseType> {
+    match name {
+        "bool" => Some(BaseType::Bool),
+        "byte" | "char" | "uint8" => Some(BaseType::U8),
+        "int8" => Some(BaseType::I8),
+        "int16" => Some(BaseType::I16),
+        "uint16" => Some(BaseType::U16),
+        "int32" => Some(BaseType::I32),
+        "uint32" => Some(BaseType::U32),
+        "int64" => Some(BaseType::I64),
+        "uint64" => Some(BaseType::U64),
+        "float32" => Some(BaseType::F32),
+        "float64" => Some(BaseType::F64),
+        "string" => Some(BaseType::String),
+        _ => None,
+    }
+}
+
+// The single word primitive types of IDL
+fn idl_primitive(name: &str) -> Option<BaseType> {
+    match name {
+        "boolean" => Some(BaseType::Bool),
+        "octet" | "char" | "uint8" => Some(BaseType::U8),
+        "int8" => Some(BaseType::I8),
+        "short" | "int16" => Some(BaseType::I16),
+        "uint16" => Some(BaseType::U16),
+        "int32" => Some(BaseType::I32),
+        "uint32" => Some(BaseType::U32),
+        "int64" => Some(BaseType::I64),
+        "uint64" => Some(BaseType::U64),
+        "float" => Some(BaseType::F32),
+        "double" => Some(BaseType::F64),
+        _ => None,
+    }
+}
+
+fn parse_bound(s: &str, context: &str) -> Result<usize, String> {
+    s.trim()
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid bound '{}' in '{}'", s, context))
+}
+
+// Parses the type of a field of a .msg file of `package`, e.g. "int32[<=5]" or "geometry_msgs/Point"
+fn parse_msg_type(token: &str, package: &str) -> Result<(BaseType, Multiplicity), String> {
+    let (base, multiplicity) = match token.find('[') {
+        Some(i) => {
+            let dims = token[i + 1..]
+                .strip_suffix(']')
+                .ok_or_else(|| format!("Invalid type '{}'", token))?;
+            let multiplicity = if dims.is_empty() {
+                Multiplicity::Sequence(None)
+            } else if let Some(bound) = dims.strip_prefix("<=") {
+                Multiplicity::Sequence(Some(parse_bound(bound, token)?))
+            } else {
+                Multiplicity::Array(parse_bound(dims, token)?)
+            };
+            (&token[..i], multiplicity)
+        }
+        None => (token, Multiplicity::Single),
+    };
+    // the bounded strings are serialized as the other strings
+    let base = match base.strip_prefix("string<=") {
+        Some(bound) => {
+            parse_bound(bound, token)?;
+            "string"
+        }
+        None => base,
+    };
+    if base.starts_with("wstring") || base == "wchar" {
+        return Err(format!("Unsupported type '{}'", token));
+    }
+    if let Some(primitive) = msg_primitive(base) {
+        return Ok((primitive, multiplicity));
+    }
+    let name = match base.split('/').collect::<Vec<&str>>().as_slice() {
+        ["Header"] => String::from("std_msgs/msg/Header"),
+        [name] => format!("{}/msg/{}", package, name),
+        [pkg, name] => format!("{}/msg/{}", pkg, name),
+        [pkg, "msg", name] => format!("{}/msg/{}", pkg, name),
+        _ => return Err(format!("Invalid type '{}'", token)),
+    };
+    Ok((BaseType::Message(name), multiplicity))
+}
+
+// Parses the definition of the message `name` of a .msg file of `package`
+fn parse_msg(name: &str, package: &str, definition: &str) -> Result<MessageType, String> {
+    let mut fields = vec![];
+    for line in definition.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (type_token, rest) =
+            line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()));
+        let rest = rest.trim_start();
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let field_name = &rest[..name_end];
+        if field_name.is_empty() {
+            return Err(format!("Invalid field '{}' in {}", line, name));
+        }
+        // the constants are not serialized
+        if rest[name_end..].trim_start().starts_with('=') {
+            continue;
+        }
+        let (base, multiplicity) =
+            parse_msg_type(type_token, package).map_err(|e| format!("{} in {}", e, name))?;
+        fields.push(Field {
+            name: field_name.to_string(),
+            base,
+            multiplicity,
+        });
+    }
+    Ok(MessageType {
+        name: name.to_string(),
+        fields,
+    })
+}
+
+// Splits an IDL definition into its tokens, without its comments and preprocessor directives
+fn idl_tokens(idl: &str) -> Vec<String> {
+    let chars: Vec<char> = idl.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    let mut line_start = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line_start = true;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let next = chars.get(i + 1).copied();
+        if (c == '#' && line_start) || (c == '/' && next == Some('/')) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i += 1;
+            tokens.push(chars[start..i.min(chars.len())].iter().collect());
+        } else if c == ':' && next == Some(':') {
+            tokens.push(String::from("::"));
+            i += 2;
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            tokens.push(c.to_string());
+            i += 1;
+        }
+        line_start = false;
+    }
+    tokens
+}
+
+// A parser of the IDL subset generated by rosidl: modules, structures, typedefs of arrays,
+// sequences and bounded strings. The constants, enumerations and annotations are skipped.
+// The IDL type of a field of the type `base`
+fn idl_type(base: &BaseType) -> String {
+    match base {
+        BaseType::Bool => String::from("boolean"),
+        BaseType::I8 => String::from("int8"),
+        BaseType::U8 => String::from("uint8"),
+        BaseType::I16 => String::from("int16"),
+        BaseType::U16 => String::from("uint16"),
+        BaseType::I32 => String::from("int32"),
+        BaseType::U32 => String::from("uint32"),
+        BaseType::I64 => String::from("int64"),
+        BaseType::U64 => String::from("uint64"),
+        BaseType::F32 => String::from("float"),
+        BaseType::F64 => String::from("double"),
+        BaseType::String => String::from("string"),
+        BaseType::Message(name) => name.replace('/', "::"),
+    }
+}
+
+struct IdlParser {
+    tokens: Vec<String>,
+    pos: usize,
+    // the enclosing modules
+    scope: Vec<String>,
+    typedefs: HashMap<String, (BaseType, Multiplicity)>,
+    types: Vec<MessageType>,
+}
+
+impl IdlParser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| String::from("Unexpected end of IDL"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            t if t == expected => Ok(()),
+            t => Err(format!("Expected '{}' in IDL, found '{}'", expected, t)),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.next()? {
+            t if t.starts_with(|c: char| c.is_alphabetic() || c == '_') => Ok(t),
+            t => Err(format!("Expected an identifier in IDL, found '{}'", t)),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let token = self.next()?;
+        parse_bound(&token, &token)
+    }
+
+    // Skips the tokens up to the end of a declaration, including its nested blocks
+    fn skip_declaration(&mut self) -> Result<(), String> {
+        let mut depth = 0;
+        loop {
+            match self.next()?.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                ";" if depth == 0 => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+
+    fn skip_annotations(&mut self) -> Result<(), String> {
+        while self.peek() == Some("@") {
+            self.next()?;
+            self.identifier()?;
+            while self.peek() == Some("::") {
+                self.next()?;
+                self.identifier()?;
+            }
+            if self.peek() == Some("(") {
+                let mut depth = 0;
+                loop {
+                    match self.next()?.as_str() {
+                        "(" => depth += 1,
+                        ")" if depth == 1 => break,
+                        ")" => depth -= 1,
+                        _ => (),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn definitions(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_annotations()?;
+            match self.peek() {
+                None | Some("}") => return Ok(()),
+                Some(";") => {
+                    self.next()?;
+                }
+                Some("module") => {
+                    self.next()?;
+                    let name = self.identifier()?;
+                    self.expect("{")?;
+                    self.scope.push(name);
+                    self.definitions()?;
+                    self.scope.pop();
+                    self.expect("}")?;
+                }
+                Some("struct") => self.structure()?,
+                Some("typedef") => self.typedef()?,
+                Some(_) => self.skip_declaration()?,
+            }
+        }
+    }
+
+    fn scoped_name(&self, name: &str) -> String {
+        let mut scoped = self.scope.clone();
+        scoped.push(name.to_string());
+        scoped.join("/")
+    }
+
+    // Resolves a type name, as a typedef of the enclosing modules or as a structure
+    fn resolve(&self, parts: Vec<String>) -> (BaseType, Multiplicity) {
+        if parts.len() == 1 {
+            for depth in (0..=self.scope.len()).rev() {
+                let mut name = self.scope[..depth].to_vec();
+                name.push(parts[0].clone());
+                if let Some(t) = self.typedefs.get(&name.join("/")) {
+                    return t.clone();
+                }
+            }
+            return (
+                BaseType::Message(self.scoped_name(&parts[0])),
+                Multiplicity::Single,
+            );
+        }
+        let name = parts.join("/");
+        match self.typedefs.get(&name) {
+            Some(t) => t.clone(),
+            None => (BaseType::Message(name), Multiplicity::Single),
+        }
+    }
+
+    fn type_spec(&mut self) -> Result<(BaseType, Multiplicity), String> {
+        let token = self.next()?;
+        let base = match token.as_str() {
+            "sequence" => {
+                self.expect("<")?;
+                let (base, multiplicity) = self.type_spec()?;
+                if multiplicity != Multiplicity::Single {
+                    return Err(String::from("Unsupported nested collection in IDL"));
+                }
+                let bound = if self.peek() == Some(",") {
+                    self.next()?;
+                    Some(self.number()?)
+                } else {
+                    None
+                };
+                self.expect(">")?;
+                return Ok((base, Multiplicity::Sequence(bound)));
+            }
+            "string" => {
+                if self.peek() == Some("<") {
+                    self.next()?;
+                    self.number()?;
+                    self.expect(">")?;
+                }
+                BaseType::String
+            }
+            "unsigned" => match self.next()?.as_str() {
+                "short" => BaseType::U16,
+                "long" if self.peek() == Some("long") => {
+                    self.next()?;
+                    BaseType::U64
+                }
+                "long" => BaseType::U32,
+                t => return Err(format!("Invalid type 'unsigned {}' in IDL", t)),
+            },
+            "long" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    BaseType::I64
+                }
+                Some("double") => return Err(String::from("Unsupported type 'long double'")),
+                _ => BaseType::I32,
+            },
+            "wstring" | "wchar" => return Err(format!("Unsupported type '{}'", token)),
+            t => match idl_primitive(t) {
+                Some(primitive) => primitive,
+                None => {
+                    let mut parts = vec![];
+                    if t != "::" {
+                        parts.push(token.clone());
+                    }
+                    while self.peek() == Some("::") || parts.is_empty() {
+                        if self.peek() == Some("::") {
+                            self.next()?;
+                        }
+                        parts.push(self.identifier()?);
+                    }
+                    return Ok(self.resolve(parts));
+                }
+            },
+        };
+        Ok((base, Multiplicity::Single))
+    }
+
+    // A declarator of a member or typedef: its name and array dimension
+    fn declarator(
+        &mut self,
+        base: &BaseType,
+        multiplicity: Multiplicity,
+    ) -> Result<(String, BaseType, Multiplicity), String> {
+        let name = self.identifier()?;
+        if self.peek() != Some("[") {
+            return Ok((name, base.clone(), multiplicity));
+        }
+        self.next()?;
+        let size = self.number()?;
+        self.expect("]")?;
+        if multiplicity != Multiplicity::Single || self.peek() == Some("[") {
+            return Err(format!("Unsupported multi-dimensional member '{}'", name));
+        }
+        Ok((name, base.clone(), Multiplicity::Array(size)))
+    }
+
+    fn typedef(&mut self) -> Result<(), String> {
+        self.expect("typedef")?;
+        let (base, multiplicity) = self.type_spec()?;
+        let (name, base, multiplicity) = self.declarator(&base, multiplicity)?;
+        self.expect(";")?;
+        self.typedefs
+            .insert(self.scoped_name(&name), (base, multiplicity));
+        Ok(())
+    }
+
+    fn structure(&mut self) -> Result<(), String> {
+        self.expect("struct")?;
+        let name = self.identifier()?;
+        match self.next()?.as_str() {
+            // a forward declaration
+            ";" => return Ok(()),
+            "{" => (),
+            t => return Err(format!("Unsupported '{}' after struct {}", t, name)),
+        }
+        let mut fields = vec![];
+        loop {
+            self.skip_annotations()?;
+            if self.peek() == Some("}") {
+                break;
+            }
+            let (base, multiplicity) = self.type_spec()?;
+            loop {
+                let (field_name, base, multiplicity) = self.declarator(&base, multiplicity)?;
+                fields.push(Field {
+                    name: field_name,
+                    base,
+                    multiplicity,
+                });
+                if self.peek() == Some(",") {
+                    self.next()?;
+                } else {
+                    break;
+                }
+            }
+            self.expect(";")?;
+        }
+        self.expect("}")?;
+        self.expect(";")?;
+        self.types.push(MessageType {
+            name: self.scoped_name(&name),
+            fields,
+        });
+        Ok(())
+    }
+}
+
+fn parse_idl(idl: &str) -> Result<Vec<MessageType>, String> {
+    let mut parser = IdlParser {
+        tokens: idl_tokens(idl),
+        pos: 0,
+        scope: vec![],
+        typedefs: HashMap::new(),
+        types: vec![],
+    };
+    parser.definitions()?;
+    if let Some(t) = parser.peek() {
+        return Err(format!("Unexpected '{}' in IDL", t));
+    }
+    Ok(parser.types)
+}
+
+macro_rules! read_number {
+    ($reader:expr, $t:ty) => {{
+        const SIZE: usize = std::mem::size_of::<$t>();
+        $reader.align(SIZE);
+        let mut bytes = [0u8; SIZE];
+        bytes.copy_from_slice($reader.bytes(SIZE)?);
+        if $reader.little_endian {
+            <$t>::from_le_bytes(bytes)
+        } else {
+            <$t>::from_be_bytes(bytes)
+        }
+    }};
+}
+
+// The CDR data after its encapsulation header, the alignments being relative to its start
+struct CdrReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl<'a> CdrReader<'a> {
+    fn align(&mut self, n: usize) {
+        self.pos = (self.pos + n - 1) / n * n;
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| String::from("Truncated CDR data"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let len = read_number!(self, u32) as usize;
+        // each element takes at least a byte
+        if len > self.buf.len() - self.pos.min(self.buf.len()) {
+            return Err(String::from("Truncated CDR data"));
+        }
+        Ok(len)
+    }
+}
+
+// The CDR data of a sample being encoded, after its encapsulation header
+#[derive(Default)]
+struct CdrWriter {
+    buf: Vec<u8>,
+}
+
+impl CdrWriter {
+    fn align(&mut self, n: usize) {
+        while self.buf.len() % n != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn number(&mut self, bytes: &[u8]) {
+        self.align(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+/// The types loaded at runtime from ROS 2 .msg and .srv files and from OMG IDL files,
+/// by ROS 2 name, e.g. "sensor_msgs/msg/Image" or "example_interfaces/srv/AddTwoInts_Request".
+/// The samples of those types can be decoded from CDR and encoded back without their code.
+#[derive(Debug, Default)]
+pub struct TypeRegistry {
+    types: HashMap<String, MessageType>,
+}
+
+impl TypeRegistry {
+    /// Loads the definitions of the directories listed in `yaml`, or of the single one given.
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let mut registry = TypeRegistry::default();
+        let dirs = match yaml {
+            Yaml::Array(dirs) => dirs.as_slice(),
+            Yaml::String(_) => std::slice::from_ref(yaml),
+            _ => return Err(format!("Invalid types directories {:?}", yaml)),
+        };
+        for d in dirs {
+            match d.as_str() {
+                Some(dir) => registry.load_dir(Path::new(dir))?,
+                None => return Err(format!("Invalid types directory {:?}", d)),
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Loads the definitions found in `dir` and its sub-directories: the "<package>/msg/*.msg",
+    /// "<package>/srv/*.srv" and "*.idl" files. The definitions that can't be parsed are skipped.
+    pub fn load_dir(&mut self, dir: &Path) -> Result<(), String> {
+        let entries =
+            fs::read_dir(dir).map_err(|e| format!("Unable to read {}: {}", dir.display(), e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.load_dir(&path)?;
+            } else if let Err(e) = self.load_file(&path) {
+                warn!("Skipping the type definitions of {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if !["msg", "srv", "idl"].contains(&extension) {
+            return Ok(());
+        }
+        let definition = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        if extension == "idl" {
+            return self.add_idl(&definition);
+        }
+        let name = path.file_stem().and_then(|n| n.to_str());
+        let package = path
+            .parent()
+            .and_then(|p| p.parent())
+            .and_then(|p| p.file_name())
+            .and_then(|p| p.to_str());
+        match (package, name) {
+            (Some(package), Some(name)) if extension == "msg" => {
+                self.add_msg(package, name, &definition)
+            }
+            (Some(package), Some(name)) => self.add_srv(package, name, &definition),
+            _ => Err(String::from("Unable to tell the package of the definition")),
+        }
+    }
+
+    fn add(&mut self, t: MessageType) {
+        debug!("Registered type {}", t.name);
+        self.types.insert(t.name.clone(), t);
+    }
+
+    /// Adds the message `name` of `package` defined as in a .msg file.
+    pub fn add_msg(&mut self, package: &str, name: &str, definition: &str) -> Result<(), String> {
+        let t = parse_msg(&format!("{}/msg/{}", package, name), package, definition)?;
+        self.add(t);
+        Ok(())
+    }
+
+    /// Adds the request and the response of the service `name` of `package`, defined as in a
+    /// .srv file: "<package>/srv/<name>_Request" and "<package>/srv/<name>_Response".
+    pub fn add_srv(&mut self, package: &str, name: &str, definition: &str) -> Result<(), String> {
+        // the request and response are separated by a line "---", either of them may be empty
+        let lines: Vec<&str> = definition.lines().collect();
+        let separator = lines
+            .iter()
+            .position(|l| l.trim() == "---")
+            .ok_or_else(|| String::from("Missing '---' between the request and response"))?;
+        let request = lines[..separator].join("\n");
+        let response = lines[separator + 1..].join("\n");
+        let request = parse_msg(
+            &format!("{}/srv/{}_Request", package, name),
+            package,
+            &request,
+        )?;
+        let response = parse_msg(
+            &format!("{}/srv/{}_Response", package, name),
+            package,
+            &response,
+        )?;
+        self.add(request);
+        self.add(response);
+        Ok(())
+    }
+
+    /// Adds the structures of an IDL definition, named after their modules.
+    pub fn add_idl(&mut self, definition: &str) -> Result<(), String> {
+        for t in parse_idl(definition)? {
+            self.add(t);
+        }
+        Ok(())
+    }
+
+    /// The type `type_name`, given in its ROS 2 or DDS form, e.g. "sensor_msgs::msg::dds_::Image_".
+    pub fn get(&self, type_name: &str) -> Option<&MessageType> {
+        self.types
+            .get(type_name)
+            .or_else(|| self.types.get(&demangle_type(type_name)))
+    }
+
+    fn get_or_err(&self, type_name: &str) -> Result<&MessageType, String> {
+        self.get(type_name)
+            .ok_or_else(|| format!("Unknown type {}", type_name))
+    }
+
+    /// The IDL definition of the type `type_name` and of the structures it uses, the latter
+    /// first, as loaded back by `add_idl`.
+    pub fn to_idl(&self, type_name: &str) -> Result<String, String> {
+        let mut ordered = vec![];
+        self.used_types(self.get_or_err(type_name)?, &mut vec![], &mut ordered)?;
+        let mut idl = String::new();
+        for t in ordered {
+            let parts: Vec<&str> = t.name.split('/').collect();
+            let (modules, name) = parts.split_at(parts.len() - 1);
+            for m in modules {
+                idl.push_str(&format!("module {} {{ ", m));
+            }
+            idl.push_str(&format!("struct {} {{\n", name[0]));
+            for f in &t.fields {
+                let base = idl_type(&f.base);
+                idl.push_str(&match f.multiplicity {
+                    Multiplicity::Single => format!("  {} {};\n", base, f.name),
+                    Multiplicity::Array(n) => format!("  {} {}[{}];\n", base, f.name, n),
+                    Multiplicity::Sequence(None) => format!("  sequence<{}> {};\n", base, f.name),
+                    Multiplicity::Sequence(Some(n)) => {
+                        format!("  sequence<{}, {}> {};\n", base, n, f.name)
+                    }
+                });
+            }
+            idl.push_str("};");
+            idl.push_str(&" };".repeat(modules.len()));
+            idl.push('\n');
+        }
+        Ok(idl)
+    }
+
+    // Adds the structures used by `t` and then `t` to `ordered`, `visiting` being the structures
+    // using `t`, against the recursive definitions
+    fn used_types<'a>(
+        &'a self,
+        t: &'a MessageType,
+        visiting: &mut Vec<&'a str>,
+        ordered: &mut Vec<&'a MessageType>,
+    ) -> Result<(), String> {
+        if visiting.contains(&t.name.as_str()) || ordered.iter().any(|o| o.name == t.name) {
+            return Ok(());
+        }
+        visiting.push(&t.name);
+        for f in &t.fields {
+            if let BaseType::Message(name) = &f.base {
+                self.used_types(self.get_or_err(name)?, visiting, ordered)?;
+            }
+        }
+        visiting.pop();
+        ordered.push(t);
+        Ok(())
+    }
+
+    /// The default value of a field of the type `base`: zero, an empty string or sequence, or a
+    /// structure of default values. The arrays are filled with default values.
+    pub fn default_value(
+        &self,
+        base: &BaseType,
+        multiplicity: Multiplicity,
+    ) -> Result<Value, String> {
+        self.default_with_depth(base, multiplicity, 0)
+    }
+
+    fn default_with_depth(
+        &self,
+        base: &BaseType,
+        multiplicity: Multiplicity,
+        depth: usize,
+    ) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Too deeply nested type {:?}", base));
+        }
+        Ok(match (multiplicity, base) {
+            (Multiplicity::Array(n), base) => Value::Array(
+                (0..n)
+                    .map(|_| self.default_with_depth(base, Multiplicity::Single, depth))
+                    .collect::<Result<_, _>>()?,
+            ),
+            (Multiplicity::Sequence(_), _) => Value::Array(vec![]),
+            (_, BaseType::Bool) => Value::Bool(false),
+            (_, BaseType::I8) => Value::I8(0),
+            (_, BaseType::U8) => Value::U8(0),
+            (_, BaseType::I16) => Value::I16(0),
+            (_, BaseType::U16) => Value::U16(0),
+            (_, BaseType::I32) => Value::I32(0),
+            (_, BaseType::U32) => Value::U32(0),
+            (_, BaseType::I64) => Value::I64(0),
+            (_, BaseType::U64) => Value::U64(0),
+            (_, BaseType::F32) => Value::F32(0.0),
+            (_, BaseType::F64) => Value::F64(0.0),
+            (_, BaseType::String) => Value::String(String::new()),
+            (_, BaseType::Message(name)) => Value::Struct(
+                self.get_or_err(name)?
+                    .fields
+                    .iter()
+                    .map(|f| {
+                        self.default_with_depth(&f.base, f.multiplicity, depth + 1)
+                            .map(|v| (f.name.clone(), v))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    /// Decodes a CDR sample of the type `type_name`.
+    pub fn decode(&self, type_name: &str, data: &[u8]) -> Result<Value, String> {
+        if data.len() < ENCAPSULATION_LEN {
+            return Err(String::from("Truncated CDR data"));
+        }
+        if data[0] != 0 || (data[1] != CDR_BE_KIND && data[1] != CDR_LE_KIND) {
+            return Err(format!(
+                "Unsupported CDR encapsulation {:#06x}",
+                u16::from_be_bytes([data[0], data[1]])
+            ));
+        }
+        let mut reader = CdrReader {
+            buf: &data[ENCAPSULATION_LEN..],
+            pos: 0,
+            little_endian: data[1] == CDR_LE_KIND,
+        };
+        self.decode_struct(&mut reader, self.get_or_err(type_name)?, 0)
+    }
+
+    fn decode_struct(
+        &self,
+        r: &mut CdrReader,
+        t: &MessageType,
+        depth: usize,
+    ) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Too deeply nested type {}", t.name));
+        }
+        // an empty structure is serialized with a dummy byte
+        if t.fields.is_empty() {
+            r.bytes(1)?;
+        }
+        let mut fields = Vec::with_capacity(t.fields.len());
+        for f in &t.fields {
+            let value = match f.multiplicity {
+                Multiplicity::Single => self.decode_single(r, &f.base, depth)?,
+                Multiplicity::Array(n) => Value::Array(
+                    (0..n)
+                        .map(|_| self.decode_single(r, &f.base, depth))
+                        .collect::<Result<_, _>>()?,
+                ),
+                Multiplicity::Sequence(_) => {
+                    let n = r.length()?;
+                    Value::Array(
+                        (0..n)
+                            .map(|_| self.decode_single(r, &f.base, depth))
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+            };
+            fields.push((f.name.clone(), value));
+        }
+        Ok(Value::Struct(fields))
+    }
+
+    fn decode_single(
+        &self,
+        r: &mut CdrReader,
+        base: &BaseType,
+        depth: usize,
+    ) -> Result<Value, String> {
+        Ok(match base {
+            BaseType::Bool => Value::Bool(r.bytes(1)?[0] != 0),
+            BaseType::I8 => Value::I8(r.bytes(1)?[0] as i8),
+            BaseType::U8 => Value::U8(r.bytes(1)?[0]),
+            BaseType::I16 => Value::I16(read_number!(r, i16)),
+            BaseType::U16 => Value::U16(read_number!(r, u16)),
+            BaseType::I32 => Value::I32(read_number!(r, i32)),
+            BaseType::U32 => Value::U32(read_number!(r, u32)),
+            BaseType::I64 => Value::I64(read_number!(r, i64)),
+            BaseType::U64 => Value::U64(read_number!(r, u64)),
+            BaseType::F32 => Value::F32(read_number!(r, f32)),
+            BaseType::F64 => Value::F64(read_number!(r, f64)),
+            BaseType::String => {
+                // the length includes the terminating NUL
+                let len = r.length()?;
+                let bytes = r.bytes(len)?;
+                let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+                Value::String(
+                    String::from_utf8(bytes.to_vec())
+                        .map_err(|_| String::from("Invalid UTF-8 string in CDR data"))?,
+                )
+            }
+            BaseType::Message(name) => self.decode_struct(r, self.get_or_err(name)?, depth + 1)?,
+        })
+    }
+
+    /// Encodes a value of the type `type_name` in little-endian CDR.
+    pub fn encode(&self, type_name: &str, value: &Value) -> Result<Vec<u8>, String> {
+        let mut writer = CdrWriter::default();
+        self.encode_struct(&mut writer, self.get_or_err(type_name)?, value, 0)?;
+        let mut data = CDR_LE.to_vec();
+        data.extend_from_slice(&writer.buf);
+        Ok(data)
+    }
+
+    fn encode_struct(
+        &self,
+        w: &mut CdrWriter,
+        t: &MessageType,
+        value: &Value,
+        depth: usize,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Too deeply nested type {}", t.name));
+        }
+        let values = match value {
+            Value::Struct(values) => values,
+            v => return Err(format!("Invalid value {:?} for a {}", v, t.name)),
+        };
+        if t.fields.is_empty() {
+            w.buf.push(0);
+        }
+        for f in &t.fields {
+            let value = values
+                .iter()
+                .find(|(n, _)| n == &f.name)
+                .map(|(_, v)| v)
+                .ok_or_else(|| format!("Missing field {} of {}", f.name, t.name))?;
+            let elements = match (f.multiplicity, value) {
+                (Multiplicity::Single, v) => {
+                    self.encode_single(w, &f.base, v, depth)?;
+                    continue;
+                }
+                (Multiplicity::Array(n), Value::Array(elements)) if elements.len() == n => elements,
+                (Multiplicity::Sequence(bound), Value::Array(elements))
+                    if bound.map(|b| elements.len() <= b).unwrap_or(true) =>
+                {
+                    w.number(&(elements.len() as u32).to_le_bytes());
+                    elements
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid value for the field {} of {}: {:?} expected",
+                        f.name, t.name, f.multiplicity
+                    ))
+                }
+            };
+            for e in elements {
+                self.encode_single(w, &f.base, e, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_single(
+        &self,
+        w: &mut CdrWriter,
+        base: &BaseType,
+        value: &Value,
+        depth: usize,
+    ) -> Result<(), String> {
+        match (base, value) {
+            (BaseType::Bool, Value::Bool(v)) => w.buf.push(*v as u8),
+            (BaseType::I8, Value::I8(v)) => w.buf.push(*v as u8),
+            (BaseType::U8, Value::U8(v)) => w.buf.push(*v),
+            (BaseType::I16, Value::I16(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::U16, Value::U16(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::I32, Value::I32(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::U32, Value::U32(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::I64, Value::I64(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::U64, Value::U64(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::F32, Value::F32(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::F64, Value::F64(v)) => w.number(&v.to_le_bytes()),
+            (BaseType::String, Value::String(s)) => {
+                w.number(&(s.len() as u32 + 1).to_le_bytes());
+                w.buf.extend_from_slice(s.as_bytes());
+                w.buf.push(0);
+            }
+            (BaseType::Message(name), v) => {
+                self.encode_struct(w, self.get_or_err(name)?, v, depth + 1)?
+            }
+            (base, v) => return Err(format!("Invalid value {:?} for a {:?}", v, base)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TypeRegistry {
+        let mut registry = TypeRegistry::default();
+        registry
+            .add_msg("builtin_interfaces", "Time", "int32 sec\nuint32 nanosec\n")
+            .unwrap();
+        registry
+            .add_msg(
+                "std_msgs",
+                "Header",
+                "# Standard metadata\nbuiltin_interfaces/Time stamp\nstring frame_id\n",
+            )
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_msg() {
+        let mut registry = registry();
+        registry
+            .add_msg(
+                "demo",
+                "Scan",
+                r#"
+uint8 MODE_FAST=1  # a constant
+string NAME = "scan"
+Header header
+float32[] ranges
+int16[3] offsets # a comment
+string<=8 label "default"
+bool[<=2] flags
+Empty empty
+"#,
+            )
+            .unwrap();
+        registry.add_msg("demo", "Empty", "").unwrap();
+        let t = registry.get("demo::msg::dds_::Scan_").unwrap();
+        let fields: Vec<(&str, &BaseType, Multiplicity)> = t
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), &f.base, f.multiplicity))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    "header",
+                    &BaseType::Message(String::from("std_msgs/msg/Header")),
+                    Multiplicity::Single
+                ),
+                ("ranges", &BaseType::F32, Multiplicity::Sequence(None)),
+                ("offsets", &BaseType::I16, Multiplicity::Array(3)),
+                ("label", &BaseType::String, Multiplicity::Single),
+                ("flags", &BaseType::Bool, Multiplicity::Sequence(Some(2))),
+                (
+                    "empty",
+                    &BaseType::Message(String::from("demo/msg/Empty")),
+                    Multiplicity::Single
+                ),
+            ]
+        );
+
+        let value = Value::Struct(vec![
+            (
+                String::from("header"),
+                Value::Struct(vec![
+                    (
+                        String::from("stamp"),
+                        Value::Struct(vec![
+                            (String::from("sec"), Value::I32(7)),
+                            (String::from("nanosec"), Value::U32(9)),
+                        ]),
+                    ),
+                    (
+                        String::from("frame_id"),
+                        Value::String(String::from("laser")),
+                    ),
+                ]),
+            ),
+            (
+                String::from("ranges"),
+                Value::Array(vec![Value::F32(1.5), Value::F32(2.5)]),
+            ),
+            (
+                String::from("offsets"),
+                Value::Array(vec![Value::I16(-1), Value::I16(0), Value::I16(1)]),
+            ),
+            (String::from("label"), Value::String(String::new())),
+            (String::from("flags"), Value::Array(vec![Value::Bool(true)])),
+            (String::from("empty"), Value::Struct(vec![])),
+        ]);
+        let data = registry.encode("demo/msg/Scan", &value).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            data,
+            vec![
+                0x00, 0x01, 0x00, 0x00,
+                7, 0, 0, 0, 9, 0, 0, 0,
+                6, 0, 0, 0, b'l', b'a', b's', b'e', b'r', 0, 0, 0,
+                2, 0, 0, 0, 0, 0, 0xc0, 0x3f, 0, 0, 0x20, 0x40,
+                0xff, 0xff, 0, 0, 1, 0, 0, 0,
+                1, 0, 0, 0, 0,
+                0, 0, 0, 1, 0, 0, 0, 1,
+                0,
+            ]
+        );
+        let decoded = registry.decode("demo/msg/Scan", &data).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(
+            decoded.field("header.frame_id"),
+            Some(&Value::String(String::from("laser")))
+        );
+        assert_eq!(decoded.field("header.seq"), None);
+        assert!(registry.decode("demo/msg/Scan", &data[..20]).is_err());
+        // XCDR2, aligning the 8-byte values on 4 bytes
+        let mut xcdr2 = data.clone();
+        xcdr2[1] = 0x07;
+        assert!(registry.decode("demo/msg/Scan", &xcdr2).is_err());
+        assert!(registry.decode("demo/msg/Unknown", &data).is_err());
+
+        let default = registry
+            .default_value(
+                &BaseType::Message(String::from("demo/msg/Scan")),
+                Multiplicity::Single,
+            )
+            .unwrap();
+        assert_eq!(default.field("header.stamp.sec"), Some(&Value::I32(0)));
+        assert_eq!(
+            default.field("offsets"),
+            Some(&Value::Array(vec![Value::I16(0); 3]))
+        );
+        assert_eq!(default.field("ranges"), Some(&Value::Array(vec![])));
+
+        let mut value = value;
+        *value.field_mut("header.stamp.sec").unwrap() = Value::String(String::new());
+        assert!(registry.encode("demo/msg/Scan", &value).is_err());
+    }
+
+    #[test]
+    fn test_srv_and_idl() {
+        let mut registry = registry();
+        registry
+            .add_srv(
+                "example_interfaces",
+                "AddTwoInts",
+                "int64 a\nint64 b\n---\nint64 sum\n",
+            )
+            .unwrap();
+        assert_eq!(
+            registry
+                .get("example_interfaces::srv::dds_::AddTwoInts_Response_")
+                .unwrap()
+                .fields[0]
+                .name,
+            "sum"
+        );
+        assert_eq!(
+            registry
+                .get("example_interfaces/srv/AddTwoInts_Request")
+                .unwrap()
+                .fields
+                .len(),
+            2
+        );
+        assert!(registry.add_srv("demo", "Broken", "int64 a\n").is_err());
+
+        // the empty requests and responses, e.g. of std_srvs
+        registry
+            .add_srv(
+                "std_srvs",
+                "Trigger",
+                "---\nbool success # whether it succeeded\nstring message\n",
+            )
+            .unwrap();
+        registry.add_srv("std_srvs", "Empty", "---\n").unwrap();
+        assert!(registry
+            .get("std_srvs/srv/Trigger_Request")
+            .unwrap()
+            .fields
+            .is_empty());
+        assert_eq!(
+            registry
+                .get("std_srvs/srv/Trigger_Response")
+                .unwrap()
+                .fields
+                .len(),
+            2
+        );
+        assert!(registry.get("std_srvs/srv/Empty_Request").is_some());
+        assert!(registry
+            .get("std_srvs::srv::dds_::Empty_Response_")
+            .unwrap()
+            .fields
+            .is_empty());
+
+        registry
+            .add_idl(
+                r#"
+// generated from rosidl_adapter/resource/msg.idl.em
+#include "std_msgs/msg/Header.idl"
+
+module demo {
+  module msg {
+    typedef double double__9[9];
+    module Pose_Constants {
+      const uint8 FRAME = 1;
+    };
+    @verbatim (language="comment", text=
+      "A pose" "\n" "with covariance")
+    struct Pose {
+      std_msgs::msg::Header header;
+
+      @default (value=0.0)
+      double__9 covariance;
+      sequence<demo::msg::Point, 4> points;
+      string<16> name;
+      unsigned long long id, count;
+      octet data[2];
+    };
+    struct Point {
+      float x;
+      long y;
+    };
+  };
+};
+"#,
+            )
+            .unwrap();
+        let t = registry.get("demo::msg::dds_::Pose_").unwrap();
+        let fields: Vec<(&str, &BaseType, Multiplicity)> = t
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), &f.base, f.multiplicity))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    "header",
+                    &BaseType::Message(String::from("std_msgs/msg/Header")),
+                    Multiplicity::Single
+                ),
+                ("covariance", &BaseType::F64, Multiplicity::Array(9)),
+                (
+                    "points",
+                    &BaseType::Message(String::from("demo/msg/Point")),
+                    Multiplicity::Sequence(Some(4))
+                ),
+                ("name", &BaseType::String, Multiplicity::Single),
+                ("id", &BaseType::U64, Multiplicity::Single),
+                ("count", &BaseType::U64, Multiplicity::Single),
+                ("data", &BaseType::U8, Multiplicity::Array(2)),
+            ]
+        );
+        assert_eq!(
+            registry.get("demo/msg/Point").unwrap().fields[1].base,
+            BaseType::I32
+        );
+        assert!(registry
+            .add_idl("module demo { struct A { wstring w; }; };")
+            .is_err());
+
+        let idl = registry.to_idl("demo::msg::dds_::Pose_").unwrap();
+        assert!(idl.starts_with("module builtin_interfaces { module msg { struct Time {\n"));
+        let mut loaded = TypeRegistry::default();
+        loaded.add_idl(&idl).unwrap();
+        for name in &[
+            "demo/msg/Pose",
+            "demo/msg/Point",
+            "std_msgs/msg/Header",
+            "builtin_interfaces/msg/Time",
+        ] {
+            assert_eq!(loaded.get(name), registry.get(name));
+        }
+        assert!(registry.to_idl("demo/msg/Unknown").is_err());
+        assert!(registry
+            .add_idl("module demo { struct A { long a; }")
+            .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use crate::type_registry::TypeRegistry;
+use async_std::task;
+use cdr::{CdrLe, Infinite};
+use futures::prelude::*;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use zenoh::net::*;
+
+// The bridges publish the types of the endpoints they discover under
+// "<scope>/@dds_bridge/types/<type_name>", the type name possibly made of several chunks
+// in its ROS 2 form
+const TYPES_CHUNK: &str = "@dds_bridge/types";
+
+/// What the bridge knows about a DDS type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TypeInfo {
+    pub type_name: String,
+    pub keyless: bool,
+    /// The IDL definition of the type and of the structures it uses, if the type is known to
+    /// the bridge's `TypeRegistry`. Cyclone DDS, as provided by cyclors 0.1, doesn't implement
+    /// the XTypes type discovery, thus the discovered endpoints don't tell their definition.
+    pub definition: Option<String>,
+}
+
+impl TypeInfo {
+    pub fn new(type_name: &str, keyless: bool, types: &TypeRegistry) -> Self {
+        TypeInfo {
+            type_name: String::from(type_name),
+            keyless,
+            definition: types.to_idl(type_name).ok(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        cdr::serialize::<_, _, CdrLe>(self, Infinite).unwrap()
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match cdr::deserialize_from::<_, TypeInfo, _>(buf, Infinite) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!("Failed to decode a type information: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// The zenoh key of the information on type `type_name`.
+pub fn type_info_key(scope: &str, type_name: &str) -> String {
+    format!("{}/{}/{}", scope, TYPES_CHUNK, type_name)
+}
+
+/// The types of the discovered endpoints, by type name.
+#[derive(Debug, Default)]
+pub struct TypeCache {
+    types: Mutex<HashMap<String, TypeInfo>>,
+}
+
+impl TypeCache {
+    pub fn get(&self, type_name: &str) -> Option<TypeInfo> {
+        self.types.lock().unwrap().get(type_name).cloned()
+    }
+
+    /// Caches the type information, returning true if it is new or updated.
+    pub fn insert(&self, info: TypeInfo) -> bool {
+        let mut types = self.types.lock().unwrap();
+        match types.get(&info.type_name) {
+            // don't replace a type information with a less complete one
+            Some(known) if *known == info || info.definition.is_none() => false,
+            _ => {
+                types.insert(info.type_name.clone(), info);
+                true
+            }
+        }
+    }
+
+    fn all(&self) -> Vec<TypeInfo> {
+        self.types.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Caches the type information and publishes it on zenoh if it is new.
+pub async fn publish_type_info(z: &Session, scope: &str, cache: &TypeCache, info: TypeInfo) {
+    let key = type_info_key(scope, &info.type_name);
+    let payload = info.encode();
+    if cache.insert(info) {
+        debug!("Publish type information on {}", key);
+        if let Err(e) = z.write(&ResKey::RName(key), payload.into()).await {
+            warn!("Failed to publish a type information: {}", e);
+        }
+    }
+}
+
+/// Replies to the queries on the types with the cached type information.
+pub fn run_types_queryable(z: Arc<Session>, scope: String, cache: Arc<TypeCache>) {
+    task::spawn(async move {
+        let mut queryable = z
+            .declare_queryable(
+                &ResKey::RName(format!("{}/{}/**", scope, TYPES_CHUNK)),
+                queryable::EVAL,
+            )
+            .await
+            .unwrap();
+        while let Some(query) = queryable.stream().next().await {
+            for info in cache.all() {
+                let key = type_info_key(&scope, &info.type_name);
+                if !utils::resource_name::intersect(&query.res_name, &key) {
+                    continue;
+                }
+                query
+                    .reply(Sample {
+                        res_name: key,
+                        payload: info.encode().into(),
+                        data_info: None,
+                    })
+                    .await;
+            }
+        }
+    });
+}
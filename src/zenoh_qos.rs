@@ -0,0 +1,240 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+use cyclors::*;
+use regex::Regex;
+use yaml_rust::Yaml;
+use zenoh::net::{CongestionControl, Reliability};
+
+/// The zenoh QoS of a route, derived from the QoS of its DDS endpoints.
+/// Note that the zenoh version used by the bridge has neither per-message priorities
+/// nor express delivery, thus `transport_priority` can't be mapped, and a `latency_budget`
+/// can only be approximated with the congestion control.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZenohQos {
+    // the reliability of the zenoh subscriber of a zenoh => DDS route
+    pub reliability: Reliability,
+    // the congestion control of the writes of a DDS => zenoh route
+    pub congestion_control: CongestionControl,
+}
+
+impl Default for ZenohQos {
+    fn default() -> Self {
+        ZenohQos {
+            reliability: Reliability::Reliable,
+            congestion_control: CongestionControl::Block,
+        }
+    }
+}
+
+impl ZenohQos {
+    /// Best-effort DDS endpoints are mapped to best-effort and dropping zenoh routes,
+    /// reliable ones to reliable and blocking zenoh routes.
+    ///
+    /// # Safety
+    /// `qos` must point to a valid `dds_qos_t`.
+    pub unsafe fn from_dds(qos: *const dds_qos_t) -> Self {
+        let mut kind: dds_reliability_kind_t = dds_reliability_kind_DDS_RELIABILITY_RELIABLE;
+        let mut max_blocking_time: dds_duration_t = 0;
+        if dds_qget_reliability(qos, &mut kind, &mut max_blocking_time)
+            && kind == dds_reliability_kind_DDS_RELIABILITY_BEST_EFFORT
+        {
+            ZenohQos {
+                reliability: Reliability::BestEffort,
+                congestion_control: CongestionControl::Drop,
+            }
+        } else {
+            ZenohQos::default()
+        }
+    }
+}
+
+// The keys of an override
+const OVERRIDE_KEYS: [&str; 4] = [
+    "topic",
+    "reliability",
+    "congestion_control",
+    "latency_budget",
+];
+// The keys of the DDS QoS that the zenoh version used by the bridge can't map
+const UNMAPPED_KEYS: [&str; 3] = ["transport_priority", "priority", "express"];
+
+#[derive(Debug)]
+struct Override {
+    topic: Regex,
+    reliability: Option<Reliability>,
+    congestion_control: Option<CongestionControl>,
+}
+
+/// Per-topic overrides of the zenoh QoS derived from the DDS endpoints.
+#[derive(Debug, Default)]
+pub struct ZenohQosOverrides {
+    overrides: Vec<Override>,
+}
+
+impl ZenohQosOverrides {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+        let mut overrides = vec![];
+        for o in yaml.as_vec().map(|v| v.as_slice()).unwrap_or_default() {
+            // the keys that would have no effect are rejected rather than ignored
+            for (k, _) in o.as_hash().into_iter().flatten() {
+                match k.as_str() {
+                    Some(k) if OVERRIDE_KEYS.contains(&k) => (),
+                    Some(k) if UNMAPPED_KEYS.contains(&k) => {
+                        return Err(format!(
+                            "Unsupported '{}' in zenoh QoS override {:?}: the zenoh version used \
+                             by the bridge has no message priorities",
+                            k, o
+                        ))
+                    }
+                    _ => return Err(format!("Invalid key {:?} in zenoh QoS override {:?}", k, o)),
+                }
+            }
+            let topic = match o["topic"].as_str() {
+                Some(t) => Regex::new(t)
+                    .map_err(|e| format!("Invalid regular expression '{}': {}", t, e))?,
+                None => return Err(format!("Missing topic in zenoh QoS override {:?}", o)),
+            };
+            let reliability = match o["reliability"].as_str() {
+                Some("reliable") => Some(Reliability::Reliable),
+                Some("best_effort") => Some(Reliability::BestEffort),
+                Some(r) => {
+                    return Err(format!(
+                        "Invalid reliability '{}', expected 'reliable' or 'best_effort'",
+                        r
+                    ))
+                }
+                None => None,
+            };
+            let congestion_control = match o["congestion_control"].as_str() {
+                Some("block") => Some(CongestionControl::Block),
+                Some("drop") => Some(CongestionControl::Drop),
+                Some(c) => {
+                    return Err(format!(
+                        "Invalid congestion control '{}', expected 'block' or 'drop'",
+                        c
+                    ))
+                }
+                None => None,
+            };
+            let latency_budget = match &o["latency_budget"] {
+                Yaml::Real(_) => o["latency_budget"].as_f64(),
+                Yaml::Integer(l) => Some(*l as f64),
+                Yaml::BadValue => None,
+                l => return Err(format!("Invalid latency_budget {:?}", l)),
+            };
+            // Without express delivery, a null latency budget is approximated by dropping the
+            // samples rather than waiting behind the congested ones
+            let congestion_control = match (latency_budget, congestion_control) {
+                (None, c) => c,
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "Both latency_budget and congestion_control in zenoh QoS override {:?}",
+                        o
+                    ))
+                }
+                (Some(l), None) if l == 0.0 => Some(CongestionControl::Drop),
+                (Some(l), None) if l > 0.0 => Some(CongestionControl::Block),
+                (Some(l), None) => {
+                    return Err(format!(
+                        "Invalid latency_budget {}: a positive number of seconds or 0 is expected",
+                        l
+                    ))
+                }
+            };
+            overrides.push(Override {
+                topic,
+                reliability,
+                congestion_control,
+            });
+        }
+        Ok(ZenohQosOverrides { overrides })
+    }
+
+    /// Applies the first override matching `topic_name`, if any.
+    pub fn apply(&self, topic_name: &str, qos: ZenohQos) -> ZenohQos {
+        match self.overrides.iter().find(|o| o.topic.is_match(topic_name)) {
+            Some(o) => ZenohQos {
+                reliability: o.reliability.unwrap_or(qos.reliability),
+                congestion_control: o.congestion_control.unwrap_or(qos.congestion_control),
+            },
+            None => qos,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn overrides(yaml: &str) -> Result<ZenohQosOverrides, String> {
+        ZenohQosOverrides::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    #[test]
+    fn test_overrides() {
+        let o = overrides(
+            r#"
+- topic: "^rt/camera/"
+  congestion_control: drop
+- topic: "^rt/"
+  reliability: best_effort
+"#,
+        )
+        .unwrap();
+        let qos = o.apply("rt/camera/image_raw", ZenohQos::default());
+        assert_eq!(qos.reliability, Reliability::Reliable);
+        assert_eq!(qos.congestion_control, CongestionControl::Drop);
+        let qos = o.apply("rt/chatter", ZenohQos::default());
+        assert_eq!(qos.reliability, Reliability::BestEffort);
+        assert_eq!(qos.congestion_control, CongestionControl::Block);
+        assert_eq!(o.apply("rq/srv", ZenohQos::default()), ZenohQos::default());
+    }
+
+    #[test]
+    fn test_latency_budget() {
+        let o = overrides(
+            r#"
+- topic: "^rt/cmd_vel$"
+  latency_budget: 0
+- topic: "^rt/map$"
+  latency_budget: 0.5
+"#,
+        )
+        .unwrap();
+        let best_effort = ZenohQos {
+            reliability: Reliability::BestEffort,
+            congestion_control: CongestionControl::Drop,
+        };
+        let qos = o.apply("rt/cmd_vel", ZenohQos::default());
+        assert_eq!(qos.reliability, Reliability::Reliable);
+        assert_eq!(qos.congestion_control, CongestionControl::Drop);
+        let qos = o.apply("rt/map", best_effort);
+        assert_eq!(qos.reliability, Reliability::BestEffort);
+        assert_eq!(qos.congestion_control, CongestionControl::Block);
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        assert!(overrides("- topic: a\n  reliability: maybe\n").is_err());
+        assert!(overrides("- topic: a\n  congestion_control: wait\n").is_err());
+        assert!(overrides("- reliability: reliable\n").is_err());
+        assert!(overrides("- topic: a\n  transport_priority: 10\n").is_err());
+        assert!(overrides("- topic: a\n  latency_budget: -1\n").is_err());
+        assert!(
+            overrides("- topic: a\n  latency_budget: 0\n  congestion_control: block\n").is_err()
+        );
+        assert!(overrides("- topic: a\n  congestion: drop\n").is_err());
+    }
+}
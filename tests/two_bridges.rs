@@ -0,0 +1,156 @@
+//
+// Copyright (c) 2017, 2020 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+
+// Two bridges on the same DDS domain must neither route the endpoints nor the data of each other.
+use async_std::future::timeout;
+use cyclors::*;
+use futures::prelude::*;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zenoh::net::*;
+use zplugin_dds::bridges::*;
+use zplugin_dds::coders::{Coders, DDSWriter, Writer, ZenohWriter};
+use zplugin_dds::*;
+
+const TYPE_NAME: &str = "loop_test::Payload";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_qos() -> QosHolder {
+    QosHolder(unsafe { dds_create_qos() })
+}
+
+fn create_writer(dp: dds_entity_t, topic_name: &str) -> (dds_entity_t, DDSWriter) {
+    let wr = create_forwarding_dds_writer(
+        dp,
+        topic_name.to_string(),
+        TYPE_NAME.to_string(),
+        true,
+        default_qos(),
+    );
+    (wr, DDSWriter::new(dp, wr, topic_name, TYPE_NAME, true))
+}
+
+fn wait_matched(wr: dds_entity_t) {
+    let start = Instant::now();
+    loop {
+        let mut status: dds_publication_matched_status_t = unsafe { std::mem::zeroed() };
+        unsafe { dds_get_publication_matched_status(wr, &mut status) };
+        if status.current_count > 0 {
+            return;
+        }
+        assert!(start.elapsed() < TIMEOUT, "writer not matched");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_endpoints_of_other_bridges_are_not_discovered() {
+    let domain = 41;
+    let bridge_a = create_bridge_participant(domain);
+    let bridge_b = create_bridge_participant(domain);
+    let app = unsafe { dds_create_participant(domain, std::ptr::null(), std::ptr::null()) };
+
+    let (tx, rx): (Sender<MatchedEntity>, Receiver<MatchedEntity>) = channel();
+    run_discovery(bridge_b, create_participants_reader(bridge_b), tx);
+
+    let _a = create_writer(bridge_a, "loop_test/from_bridge");
+    let _app = create_writer(app, "loop_test/from_app");
+
+    // once the application's writer is discovered, give the other one some more time
+    let mut deadline = Instant::now() + TIMEOUT;
+    let mut app_discovered = false;
+    while let Ok(me) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        if let MatchedEntity::DiscoveredPublication { topic_name, .. } = me {
+            assert_ne!(topic_name, "loop_test/from_bridge");
+            if topic_name == "loop_test/from_app" && !app_discovered {
+                app_discovered = true;
+                deadline = Instant::now() + Duration::from_secs(1);
+            }
+        }
+    }
+    assert!(app_discovered);
+
+    unsafe {
+        dds_delete(app);
+        dds_delete(bridge_b);
+        dds_delete(bridge_a);
+    }
+}
+
+#[async_std::test]
+async fn test_data_of_other_bridges_is_not_routed() {
+    let domain = 42;
+    let topic_name = "loop_test/data";
+    let key = "/loop_test/data";
+    let bridge_a = create_bridge_participant(domain);
+    let bridge_b = create_bridge_participant(domain);
+    let app = unsafe { dds_create_participant(domain, std::ptr::null(), std::ptr::null()) };
+
+    let z = Arc::new(open(config::default()).await.unwrap());
+    let sub_info = SubInfo {
+        reliability: Reliability::Reliable,
+        mode: SubMode::Push,
+        period: None,
+    };
+    let mut sub = z
+        .declare_subscriber(&ResKey::RName(key.to_string()), &sub_info)
+        .await
+        .unwrap();
+
+    // bridge B routes the topic to zenoh, while bridge A and the application write on it
+    let _dr = create_forwarding_dds_reader(
+        bridge_b,
+        topic_name.to_string(),
+        TYPE_NAME.to_string(),
+        true,
+        default_qos(),
+        ResKey::RName(key.to_string()),
+        Box::new(ZenohWriter::new(z.clone(), ResKey::RName(key.to_string()))),
+        &Coders::new(),
+        create_participants_reader(bridge_b),
+    );
+    let (wr_a, writer_a) = create_writer(bridge_a, topic_name);
+    let (wr_app, writer_app) = create_writer(app, topic_name);
+    wait_matched(wr_a);
+    wait_matched(wr_app);
+
+    let from_bridge = [0x00, 0x01, 0x00, 0x00, b'B'];
+    let from_app = [0x00, 0x01, 0x00, 0x00, b'A'];
+    for _ in 0..10 {
+        writer_a.write(&from_bridge);
+    }
+    writer_app.write(&from_app);
+
+    // once the application's sample is routed, give the others some more time
+    let mut wait = TIMEOUT;
+    let mut app_routed = false;
+    while let Ok(sample) = timeout(wait, sub.stream().next()).await {
+        let payload = sample.unwrap().payload.to_vec();
+        assert_ne!(payload, from_bridge);
+        if payload == from_app {
+            app_routed = true;
+            wait = Duration::from_secs(1);
+        }
+    }
+    assert!(app_routed);
+
+    drop(writer_a);
+    drop(writer_app);
+    unsafe {
+        dds_delete(app);
+        dds_delete(bridge_b);
+        dds_delete(bridge_a);
+    }
+}